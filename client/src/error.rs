@@ -0,0 +1,92 @@
+use std::{fmt, time::Duration};
+
+use shared::api::TeamRejection;
+
+#[derive(Debug)]
+pub enum Error {
+    /// the request never got a response: connection failures, timeouts, tls errors
+    Transport(reqwest::Error),
+    /// 400: the request was malformed, or a shop action was not allowed
+    BadRequest(String),
+    /// 400: the submitted team breaks the rules of its turn
+    TeamRejected(TeamRejection),
+    /// 401: no session token was set, or it expired or is invalid
+    Unauthorized(String),
+    /// 403: the run belongs to a different player
+    Forbidden(String),
+    /// 404: the run, its battle for a turn, or the lobby does not exist
+    NotFound(String),
+    /// 409: the run is not in a state that allows the request, eg. it is on a different turn
+    Conflict(String),
+    /// 410: the lobby expired and can no longer be joined or looked at
+    Gone(String),
+    /// 503: the server is overloaded or a dependency failed. the same request can be retried
+    Unavailable(String),
+    /// any other non-success status
+    Status { status: u16, message: String },
+    /// the response body did not match the expected `shared::api` type
+    Decode(serde_json::Error),
+    /// the battle for `turn_number` was still pending when `PollConfig::timeout` ran out
+    BattleTimeout { run_id: String, turn_number: u32, waited: Duration },
+}
+
+impl Error {
+    /// whether sending the same request again can succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Transport(_) | Error::Unavailable(_) | Error::BattleTimeout { .. } => true,
+            Error::Status { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn from_status(status: u16, message: String) -> Self {
+        match status {
+            400 => Error::BadRequest(message),
+            401 => Error::Unauthorized(message),
+            403 => Error::Forbidden(message),
+            404 => Error::NotFound(message),
+            409 => Error::Conflict(message),
+            410 => Error::Gone(message),
+            503 => Error::Unavailable(message),
+            status => Error::Status { status, message },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::BadRequest(message) => write!(f, "bad request: {}", message),
+            Error::TeamRejected(rejection) => write!(f, "team rejected: {}", rejection),
+            Error::Unauthorized(message) => write!(f, "unauthorized: {}", message),
+            Error::Forbidden(message) => write!(f, "forbidden: {}", message),
+            Error::NotFound(message) => write!(f, "not found: {}", message),
+            Error::Conflict(message) => write!(f, "conflict: {}", message),
+            Error::Gone(message) => write!(f, "gone: {}", message),
+            Error::Unavailable(message) => write!(f, "service unavailable: {}", message),
+            Error::Status { status, message } => write!(f, "status {}: {}", status, message),
+            Error::Decode(e) => write!(f, "unexpected response body: {}", e),
+            Error::BattleTimeout { run_id, turn_number, waited } => {
+                write!(f, "battle of run '{}' on turn {} still pending after {:?}", run_id, turn_number, waited)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Transport(e)
+    }
+}
@@ -0,0 +1,241 @@
+//! async client for the game's http api. every request and response body is one of the
+//! `shared::api` types, and every failure is a typed `Error`.
+
+use std::time::{Duration, Instant};
+
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use shared::api::{
+    Battle, BattleResponse, CreateLobbyRequest, EndTurnRequest, EndTurnResponse, ErrorBody, LeaderboardEntry, LeaderboardPeriod,
+    LeaderboardResponse, LobbyResponse, RunResponse, SessionResponse, ShopActionsRequest, ShopView,
+};
+
+mod error;
+
+pub use error::Error;
+pub use shared::api;
+
+/// how `Client::wait_for_battle` polls a pending battle. the delay doubles after every poll,
+/// starting at `initial_delay` and capped at `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct PollConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// gives up with `Error::BattleTimeout` once the battle was pending for this long
+    pub timeout: Duration,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self { initial_delay: Duration::from_millis(200), max_delay: Duration::from_secs(2), timeout: Duration::from_secs(30) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// eg. the function url, or `http://127.0.0.1:3000` for `server --local`. no trailing slash
+    base_url: String,
+    poll: PollConfig,
+    /// sent as a bearer token with every request once set
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    /// uses an existing `reqwest::Client`, eg. one with custom timeouts
+    pub fn with_http_client(http: reqwest::Client, base_url: &str) -> Self {
+        Self { http, base_url: base_url.trim_end_matches('/').to_string(), poll: PollConfig::default(), token: None }
+    }
+
+    /// acts as the player the token was issued to. see `guest_session`
+    pub fn with_token(self, token: String) -> Self {
+        Self { token: Some(token), ..self }
+    }
+
+    pub fn with_poll_config(self, poll: PollConfig) -> Self {
+        Self { poll, ..self }
+    }
+
+    /// the status code is returned along with the body, as some endpoints answer with more than one
+    async fn send<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<&B>) -> Result<(StatusCode, T), Error> {
+        let mut request = self.http.request(method, format!("{}{}", self.base_url, path));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        if !status.is_success() {
+            // errors from the lambda itself (eg. a timeout) do not come with an `ErrorBody`
+            let body = serde_json::from_slice::<ErrorBody>(&bytes)
+                .unwrap_or_else(|_| ErrorBody { error: String::from_utf8_lossy(&bytes).into_owned(), rejection: None });
+            if let Some(rejection) = body.rejection {
+                return Err(Error::TeamRejected(rejection));
+            }
+            return Err(Error::from_status(status.as_u16(), body.error));
+        }
+        let value = serde_json::from_slice(&bytes).map_err(Error::Decode)?;
+        Ok((status, value))
+    }
+
+    /// signs in as a new guest player. pass the token to `with_token` to act as that player
+    pub async fn guest_session(&self) -> Result<SessionResponse, Error> {
+        Ok(self.send::<(), _>(Method::POST, "/sessions/guest", None).await?.1)
+    }
+
+    /// signs in as a new guest player and acts as it from now on
+    pub async fn sign_in_as_guest(self) -> Result<(Self, SessionResponse), Error> {
+        let session = self.guest_session().await?;
+        Ok((self.with_token(session.token.clone()), session))
+    }
+
+    pub async fn create_run(&self) -> Result<RunResponse, Error> {
+        Ok(self.send::<(), _>(Method::POST, "/runs", None).await?.1)
+    }
+
+    pub async fn get_run(&self, run_id: &str) -> Result<RunResponse, Error> {
+        Ok(self.send::<(), _>(Method::GET, &format!("/runs/{}", run_id), None).await?.1)
+    }
+
+    /// abandons the run
+    pub async fn end_run(&self, run_id: &str) -> Result<RunResponse, Error> {
+        Ok(self.send::<(), _>(Method::POST, &format!("/runs/{}/end", run_id), None).await?.1)
+    }
+
+    /// the shop of the run's current turn
+    pub async fn get_shop(&self, run_id: &str) -> Result<ShopView, Error> {
+        Ok(self.send::<(), _>(Method::GET, &format!("/runs/{}/shop", run_id), None).await?.1)
+    }
+
+    /// takes the actions in order. if any of them is not allowed none are taken and this fails with `Error::BadRequest`
+    pub async fn shop_actions(&self, run_id: &str, request: &ShopActionsRequest) -> Result<ShopView, Error> {
+        Ok(self.send(Method::POST, &format!("/runs/{}/shop", run_id), Some(request)).await?.1)
+    }
+
+    /// submits the team for the run's current turn, or the board built in the shop if it has none.
+    /// sending the same request again (same `idempotency_key`, or none both times) is safe and reports `replayed`.
+    pub async fn end_turn(&self, run_id: &str, request: &EndTurnRequest) -> Result<EndTurnResponse, Error> {
+        Ok(self.send(Method::POST, &format!("/runs/{}/end-turn", run_id), Some(request)).await?.1)
+    }
+
+    /// a single look at the battle of `turn_number`, which may still be pending
+    pub async fn get_battle(&self, run_id: &str, turn_number: u32) -> Result<BattleResponse, Error> {
+        Ok(self.send::<(), _>(Method::GET, &format!("/runs/{}/battle/{}", run_id, turn_number), None).await?.1)
+    }
+
+    /// the best runs of the current `period`, best first. the server picks how many if `limit` is None.
+    /// leaderboards are public, so this works without a token
+    pub async fn leaderboard(&self, period: LeaderboardPeriod, limit: Option<u32>) -> Result<LeaderboardResponse, Error> {
+        let mut path = format!("/leaderboards/{}", period.as_str());
+        if let Some(limit) = limit {
+            path.push_str(&format!("?limit={}", limit));
+        }
+        Ok(self.send::<(), _>(Method::GET, &path, None).await?.1)
+    }
+
+    /// the rank of a finished run on the `period` leaderboard it finished in. `Error::NotFound` if it is not ranked
+    pub async fn rank_of(&self, period: LeaderboardPeriod, run_id: &str) -> Result<LeaderboardEntry, Error> {
+        Ok(self.send::<(), _>(Method::GET, &format!("/leaderboards/{}/runs/{}", period.as_str(), run_id), None).await?.1)
+    }
+
+    /// opens a lobby. share its `code` with friends so they can `join_lobby`. the lobby lives for
+    /// `ttl_secs`, or the server's lobby ttl if that is shorter or None
+    pub async fn create_lobby(&self, ttl_secs: Option<u64>) -> Result<LobbyResponse, Error> {
+        Ok(self.send(Method::POST, "/lobbies", Some(&CreateLobbyRequest { ttl_secs })).await?.1)
+    }
+
+    /// starts a run in the lobby, or returns the run the player already joined it with
+    pub async fn join_lobby(&self, code: &str) -> Result<RunResponse, Error> {
+        Ok(self.send::<(), _>(Method::POST, &format!("/lobbies/{}/join", code), None).await?.1)
+    }
+
+    /// the lobby with the standings of its runs. `Error::Gone` once it expired
+    pub async fn get_lobby(&self, code: &str) -> Result<LobbyResponse, Error> {
+        Ok(self.send::<(), _>(Method::GET, &format!("/lobbies/{}", code), None).await?.1)
+    }
+
+    /// polls the battle of `turn_number` until matchmaking has fought it, backing off according to the `PollConfig`
+    pub async fn wait_for_battle(&self, run_id: &str, turn_number: u32) -> Result<Battle, Error> {
+        let start = Instant::now();
+        let mut delay = self.poll.initial_delay;
+        loop {
+            if let BattleResponse::Complete(battle) = self.get_battle(run_id, turn_number).await? {
+                return Ok(*battle);
+            }
+            let waited = start.elapsed();
+            if waited + delay > self.poll.timeout {
+                return Err(Error::BattleTimeout { run_id: run_id.to_string(), turn_number, waited });
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.poll.max_delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared::api::{Opponent, RunStatus, TeamRejection, TeamSnapshot, UnitSnapshot};
+
+    /// a `server --local` on a random loopback port, signed in as a guest
+    async fn local_client() -> Client {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("failed to bind");
+        let addr = listener.local_addr().expect("no local addr");
+        tokio::spawn(server::local::serve(listener));
+        let poll = PollConfig { initial_delay: Duration::from_millis(5), ..PollConfig::default() };
+        let client = Client::new(&format!("http://{}/", addr)).with_poll_config(poll);
+        client.sign_in_as_guest().await.expect("failed to sign in").0
+    }
+
+    #[tokio::test]
+    async fn plays_a_turn_against_the_local_server() {
+        let client = local_client().await;
+        let run = client.create_run().await.expect("failed to create run");
+        assert_eq!((run.turn_number, run.status), (1, RunStatus::Active));
+
+        let request = EndTurnRequest { turn_number: 1, team: Some(TeamSnapshot::default()), idempotency_key: None };
+        let ended = client.end_turn(&run.run_id, &request).await.expect("failed to end turn");
+        assert!(!ended.replayed);
+        assert!(client.end_turn(&run.run_id, &request).await.expect("failed to replay").replayed);
+
+        // nobody else is playing, so the battle is against an empty team
+        let battle = client.wait_for_battle(&run.run_id, 1).await.expect("battle never finished");
+        assert_eq!(battle.opponent, Opponent::Empty);
+        let run = client.get_run(&run.run_id).await.expect("failed to get run");
+        assert_eq!(run.turn_number, 2);
+    }
+
+    #[tokio::test]
+    async fn errors_are_typed() {
+        let client = local_client().await;
+        assert!(matches!(client.get_run("missing").await, Err(Error::NotFound(_))));
+        let run = client.create_run().await.expect("failed to create run");
+        let (other, _) = client.clone().sign_in_as_guest().await.expect("failed to sign in");
+        assert!(matches!(other.get_run(&run.run_id).await, Err(Error::Forbidden(_))));
+        let anonymous = Client { token: None, ..client.clone() };
+        assert!(matches!(anonymous.create_run().await, Err(Error::Unauthorized(_))));
+        let request = EndTurnRequest { turn_number: 3, team: Some(TeamSnapshot::default()), idempotency_key: None };
+        assert!(matches!(client.end_turn(&run.run_id, &request).await, Err(Error::Conflict(_))));
+        let cheat = UnitSnapshot { id: None, kind: "squire".to_string(), attack: 50, health: 50, ability: None };
+        let request = EndTurnRequest { turn_number: 1, team: Some(TeamSnapshot { units: vec![cheat], ..TeamSnapshot::default() }), ..request };
+        let res = client.end_turn(&run.run_id, &request).await;
+        assert!(matches!(res, Err(Error::TeamRejected(TeamRejection::StatsOutOfRange { .. }))), "{:?}", res);
+        // the turn was never ended, so there is nothing to wait for
+        assert!(matches!(client.wait_for_battle(&run.run_id, 1).await, Err(Error::NotFound(_))));
+
+        assert!(anonymous.leaderboard(LeaderboardPeriod::Daily, Some(5)).await.expect("failed to list").entries.is_empty());
+        assert!(matches!(anonymous.rank_of(LeaderboardPeriod::AllTime, &run.run_id).await, Err(Error::NotFound(_))));
+
+        let unreachable = Client::new("http://127.0.0.1:1");
+        let res = unreachable.create_run().await;
+        assert!(matches!(res, Err(Error::Transport(_))), "{:?}", res);
+        assert!(res.unwrap_err().is_transient());
+    }
+}
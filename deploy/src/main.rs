@@ -15,8 +15,28 @@ fn create_inline_policy(table_arn: &str) -> String {
     INLINE_POLICY.replace("resource_arn_here", table_arn).replace("\n", "")
 }
 
+/// the session secret is taken from the environment deploy runs in, so it is never checked in.
+/// changing it signs out every player.
+fn session_secret() -> String {
+    let secret = std::env::var(shared::SESSION_SECRET_ENV)
+        .unwrap_or_else(|_| panic!("{} must be set to the secret session tokens are signed with", shared::SESSION_SECRET_ENV));
+    // it ends up inside a json string below
+    assert!(secret.chars().all(|x| x.is_ascii_alphanumeric()), "{} must be alphanumeric", shared::SESSION_SECRET_ENV);
+    secret
+}
+
+/// the input the schedule invokes the function with
+fn reap_event() -> String {
+    format!(r#"{{"{}": {{}}}}"#, shared::REAP_EVENT_KEY)
+}
+
 fn get_environment_vars() -> String {
-    r#"{"hello": "world"}"#.to_string()
+    format!(
+        r#"{{"{}": "{}", "{}": "{}", "{}": "{}"}}"#,
+        shared::TABLE_NAME_ENV, shared::DEFAULT_TABLE_NAME,
+        shared::MATCHMAKING_SHARDS_ENV, shared::DEFAULT_MATCHMAKING_SHARDS,
+        shared::SESSION_SECRET_ENV, session_secret(),
+    )
 }
 
 ensko!(
@@ -29,14 +49,17 @@ ensko!(
             lambdafn = ::ensko_aws::lambda::function::LambdaFunction
             lambdaurl = ::ensko_aws::lambda::url::LambdaFunctionUrl
             iamrole = ::ensko_aws::iam::role::IamRole
+            schedulerule = ::ensko_aws::eventbridge::rule::EventBridgeRule
+            lambdaperm = ::ensko_aws::lambda::permission::LambdaPermission
         }
     };
 
     const mytable = dynamotable {
-        table_name = "mygametable2025"
+        table_name = { shared::DEFAULT_TABLE_NAME }
         pkey_name = { shared::PKEY }
         region = "us-east-1"
         skey_name = { Some(shared::SKEY.to_string()) }
+        ttl_attribute_name = { Some(shared::EXPIRES_AT_ATTR.to_string()) }
     };
 
     const lambdarole = iamrole {
@@ -58,6 +81,21 @@ ensko!(
     const serverurl = lambdaurl {
         function_name = server.function_name
     } on [server];
+
+    // runs the reaper, which resolves matchmaking entries and battles whose task never finished
+    const reapschedule = schedulerule {
+        rule_name = "mygamething-reap"
+        region = "us-east-1"
+        schedule_expression = "rate(1 minute)"
+        target_arn = server.function_arn
+        input = { Some(crate::reap_event()) }
+    } on [server];
+
+    const reappermission = lambdaperm {
+        function_name = server.function_name
+        principal = "events.amazonaws.com"
+        source_arn = reapschedule.rule_arn
+    } on [reapschedule];
 );
 
 fn main() {
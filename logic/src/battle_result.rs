@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{battle::{BattleEvent, Side}, replay::Replay, PKEY, SKEY};
+
+use crate::{get_number_attr, get_string_attr, get_u32_attr, BattleOutcome, Error, Item, MatchmakingStore};
+
+pub const OPPONENT_KIND_ATTR: &str = "opponent_kind";
+pub const OPPONENT_RUN_ID_ATTR: &str = "opponent_run_id";
+pub const OPPONENT_TURN_ATTR: &str = "opponent_turn";
+pub const OPPONENT_TEAM_ATTR: &str = "opponent_team";
+pub const SEED_ATTR: &str = "seed";
+pub const SIDE_ATTR: &str = "side";
+pub const OUTCOME_ATTR: &str = "outcome";
+pub const EVENTS_ATTR: &str = "events";
+pub const REPLAY_ATTR: &str = "replay";
+
+/// who a recorded battle was fought against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpponentRef {
+    Run(String),
+    /// a ghost recorded by `run_id` at the end of `turn_number`
+    Ghost { run_id: String, turn_number: u32 },
+    Empty,
+}
+
+/// everything needed to show (and re-simulate) a battle a run fought
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BattleRecord {
+    pub run_id: String,
+    pub turn_number: u32,
+    pub opponent: OpponentRef,
+    pub team: String,
+    pub opponent_team: String,
+    pub seed: u64,
+    /// which side this run's team was on in the simulation
+    pub side: Side,
+    pub outcome: BattleOutcome,
+    pub events: Vec<BattleEvent>,
+    /// None for battles recorded before replays were stored
+    pub replay: Option<Replay>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BattleStatus {
+    /// the turn was ended, but matchmaking has not produced a battle yet
+    Pending,
+    Complete(Box<BattleRecord>),
+}
+
+pub(crate) fn side_str(side: Side) -> &'static str {
+    match side {
+        Side::Player1 => "player1",
+        Side::Player2 => "player2",
+    }
+}
+
+pub(crate) fn parse_side(s: &str) -> Result<Side, Error> {
+    match s {
+        "player1" => Ok(Side::Player1),
+        "player2" => Ok(Side::Player2),
+        _ => Err(Error::invalid_attribute(SIDE_ATTR, format!("unknown side '{}'", s))),
+    }
+}
+
+impl BattleRecord {
+    pub fn to_item(&self) -> Result<Item, Error> {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(&self.run_id)));
+        item.insert(SKEY.to_string(), AttributeValue::S(shared::battle_skey(self.turn_number)));
+        item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(self.run_id.clone()));
+        item.insert(crate::run::TURN_ATTR.to_string(), AttributeValue::N(self.turn_number.to_string()));
+        let kind = match &self.opponent {
+            OpponentRef::Run(run_id) => {
+                item.insert(OPPONENT_RUN_ID_ATTR.to_string(), AttributeValue::S(run_id.clone()));
+                "run"
+            }
+            OpponentRef::Ghost { run_id, turn_number } => {
+                item.insert(OPPONENT_RUN_ID_ATTR.to_string(), AttributeValue::S(run_id.clone()));
+                item.insert(OPPONENT_TURN_ATTR.to_string(), AttributeValue::N(turn_number.to_string()));
+                "ghost"
+            }
+            OpponentRef::Empty => "empty",
+        };
+        item.insert(OPPONENT_KIND_ATTR.to_string(), AttributeValue::S(kind.to_string()));
+        item.insert(crate::ghost::TEAM_ATTR.to_string(), AttributeValue::S(self.team.clone()));
+        item.insert(OPPONENT_TEAM_ATTR.to_string(), AttributeValue::S(self.opponent_team.clone()));
+        item.insert(SEED_ATTR.to_string(), AttributeValue::N(self.seed.to_string()));
+        item.insert(SIDE_ATTR.to_string(), AttributeValue::S(side_str(self.side).to_string()));
+        item.insert(OUTCOME_ATTR.to_string(), AttributeValue::S(self.outcome.as_str().to_string()));
+        let events = serde_json::to_string(&self.events).map_err(|e| Error::invalid_attribute(EVENTS_ATTR, e))?;
+        item.insert(EVENTS_ATTR.to_string(), AttributeValue::S(events));
+        if let Some(replay) = &self.replay {
+            let replay = serde_json::to_string(replay).map_err(|e| Error::invalid_attribute(REPLAY_ATTR, e))?;
+            item.insert(REPLAY_ATTR.to_string(), AttributeValue::S(replay));
+        }
+        Ok(item)
+    }
+
+    pub fn from_item(item: &Item) -> Result<Self, Error> {
+        let turn_number = get_u32_attr(item, crate::run::TURN_ATTR)?;
+        let opponent = match get_string_attr(item, OPPONENT_KIND_ATTR)?.as_str() {
+            "run" => OpponentRef::Run(get_string_attr(item, OPPONENT_RUN_ID_ATTR)?),
+            "ghost" => OpponentRef::Ghost {
+                run_id: get_string_attr(item, OPPONENT_RUN_ID_ATTR)?,
+                turn_number: get_u32_attr(item, OPPONENT_TURN_ATTR)?,
+            },
+            "empty" => OpponentRef::Empty,
+            x => return Err(Error::invalid_attribute(OPPONENT_KIND_ATTR, format!("unknown opponent kind '{}'", x))),
+        };
+        let events = get_string_attr(item, EVENTS_ATTR)?;
+        let replay = match item.get(REPLAY_ATTR) {
+            Some(_) => Some(serde_json::from_str(&get_string_attr(item, REPLAY_ATTR)?).map_err(|e| Error::invalid_attribute(REPLAY_ATTR, e))?),
+            None => None,
+        };
+        Ok(Self {
+            run_id: get_string_attr(item, crate::RUN_ID_ATTR)?,
+            turn_number,
+            opponent,
+            team: get_string_attr(item, crate::ghost::TEAM_ATTR)?,
+            opponent_team: get_string_attr(item, OPPONENT_TEAM_ATTR)?,
+            seed: get_number_attr(item, SEED_ATTR)?,
+            side: parse_side(&get_string_attr(item, SIDE_ATTR)?)?,
+            outcome: BattleOutcome::from_str(&get_string_attr(item, OUTCOME_ATTR)?)?,
+            events: serde_json::from_str(&events).map_err(|e| Error::invalid_attribute(EVENTS_ATTR, e))?,
+            replay,
+        })
+    }
+}
+
+/// writes (or overwrites) the battle record of `record.run_id` for `record.turn_number`
+pub async fn save_battle_record<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    record: &BattleRecord,
+) -> Result<(), Error> {
+    store.put(table_name, record.to_item()?).await
+}
+
+/// looks up the battle `run_id` fought at the end of `turn_number`.
+/// returns None if the run never ended that turn.
+pub async fn get_battle_result<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+) -> Result<Option<BattleStatus>, Error> {
+    let pkey = shared::run_pkey(run_id);
+    if let Some(item) = store.get(table_name, &pkey, &shared::battle_skey(turn_number)).await? {
+        let record = BattleRecord::from_item(&item)?;
+        return Ok(Some(BattleStatus::Complete(Box::new(record))));
+    }
+    // no battle yet. if the turn was ended then matchmaking is still in progress
+    let snapshot = store.get(table_name, &pkey, &shared::snapshot_skey(turn_number)).await?;
+    Ok(snapshot.map(|_| BattleStatus::Pending))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TC_TABLE;
+
+    tc!(battle_records_round_trip; |c| {
+        let record = BattleRecord {
+            run_id: "a".to_string(),
+            turn_number: 2,
+            opponent: OpponentRef::Ghost { run_id: "b".to_string(), turn_number: 1 },
+            team: "{}".to_string(),
+            opponent_team: "{}".to_string(),
+            seed: u64::MAX,
+            side: Side::Player2,
+            outcome: BattleOutcome::Draw,
+            events: vec![BattleEvent::Faint { unit: shared::battle::UnitRef { side: Side::Player1, slot: 3 } }],
+            replay: None,
+        };
+        assert!(get_battle_result(c, TC_TABLE, "a", 2).await.expect("lookup failed").is_none());
+        crate::run::save_snapshot(c, TC_TABLE, "a", 2, "{}").await.expect("failed to save snapshot");
+        let res = get_battle_result(c, TC_TABLE, "a", 2).await.expect("lookup failed");
+        assert_eq!(res, Some(BattleStatus::Pending));
+        save_battle_record(c, TC_TABLE, &record).await.expect("failed to save record");
+        let res = get_battle_result(c, TC_TABLE, "a", 2).await.expect("lookup failed");
+        assert_eq!(res, Some(BattleStatus::Complete(Box::new(record))));
+    });
+}
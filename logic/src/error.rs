@@ -0,0 +1,174 @@
+use std::{fmt, time::Duration};
+
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+
+use shared::api::TeamRejection;
+
+use crate::{shop::ShopError, RunStatus};
+
+/// the underlying error an `Error` was created from
+pub type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// dynamodb rejected the request because we are over capacity or request limits
+    Throttled(Source),
+    /// the table does not exist
+    TableNotFound(Source),
+    /// a conditional write found the item in a different state than expected
+    ConditionFailed(Source),
+    /// a transaction collided with another request touching the same items
+    TransactionConflict(Source),
+    /// the request never got a response: connection failures, timeouts, unreadable responses
+    Transport(Source),
+    /// the call and its retries did not finish within the `RetryPolicy` deadline
+    DeadlineExceeded(Duration),
+    /// any other error returned by the service
+    Service(Source),
+    /// a matchmaking sort key that is not in the `{random}_{shard}_{content_version}_{run_id}` format
+    MalformedSortKey(String),
+    /// an item is missing a required attribute
+    MissingAttribute(String),
+    /// an attribute exists but has the wrong type or an unparseable value
+    InvalidAttribute { name: String, reason: String },
+    RunNotFound(String),
+    RunNotActive { run_id: String, status: RunStatus },
+    /// the run belongs to a different player
+    NotRunOwner { run_id: String, player_id: String },
+    /// the caller acted on a turn that is not the run's current turn
+    TurnMismatch { run_id: String, current: u32, requested: u32 },
+    SnapshotNotFound { run_id: String, turn_number: u32 },
+    /// the run has no recorded battle for the turn
+    BattleNotFound { run_id: String, turn_number: u32 },
+    /// the turn was already ended by a submission with a different idempotency key
+    TurnAlreadyEnded { run_id: String, turn_number: u32 },
+    /// the run is played with a content version this build does not have
+    UnsupportedContentVersion { run_id: String, content_version: u32 },
+    /// the content embedded in this build does not parse or breaks one of its rules
+    InvalidContent(String),
+    /// a team snapshot that could not be decoded
+    InvalidTeam(Source),
+    /// the shop action at `index` was not allowed. none of the actions sent with it were taken
+    InvalidShopAction { index: usize, reason: ShopError },
+    /// a team that breaks the rules of its turn
+    TeamRejected(TeamRejection),
+    LobbyNotFound(String),
+    /// the lobby can no longer be joined or viewed
+    LobbyExpired(String),
+}
+
+impl Error {
+    /// whether retrying the same request later can succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Throttled(_) | Error::TransactionConflict(_) | Error::Transport(_) | Error::DeadlineExceeded(_))
+    }
+
+    /// whether a write that failed with this error may have been applied anyway,
+    /// eg. a transaction that committed after we stopped waiting for it
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, Error::Transport(_) | Error::DeadlineExceeded(_))
+    }
+
+    pub(crate) fn invalid_attribute(name: &str, reason: impl fmt::Display) -> Self {
+        Error::InvalidAttribute { name: name.to_string(), reason: reason.to_string() }
+    }
+
+    /// classifies a service error by its dynamodb error code
+    pub(crate) fn from_code(code: Option<&str>, source: Source) -> Self {
+        match code {
+            Some("ProvisionedThroughputExceededException" | "ThrottlingException" | "RequestLimitExceeded"
+                | "ThrottlingError" | "ProvisionedThroughputExceeded") => Error::Throttled(source),
+            Some("ResourceNotFoundException") => Error::TableNotFound(source),
+            Some("ConditionalCheckFailedException" | "ConditionalCheckFailed") => Error::ConditionFailed(source),
+            Some("TransactionConflictException" | "TransactionConflict" | "TransactionInProgressException") => {
+                Error::TransactionConflict(source)
+            }
+            _ => Error::Service(source),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Throttled(e) => write!(f, "request throttled: {}", e),
+            Error::TableNotFound(e) => write!(f, "table not found: {}", e),
+            Error::ConditionFailed(e) => write!(f, "condition failed: {}", e),
+            Error::TransactionConflict(e) => write!(f, "transaction conflict: {}", e),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::DeadlineExceeded(deadline) => write!(f, "deadline of {:?} exceeded", deadline),
+            Error::Service(e) => write!(f, "service error: {}", e),
+            Error::MalformedSortKey(s) => write!(f, "failed to detect MatchmakingSkey from '{}'", s),
+            Error::MissingAttribute(name) => write!(f, "failed to find '{}' attribute", name),
+            Error::InvalidAttribute { name, reason } => write!(f, "invalid value for {}: {}", name, reason),
+            Error::RunNotFound(run_id) => write!(f, "run '{}' does not exist", run_id),
+            Error::RunNotActive { run_id, status } => write!(f, "run '{}' is already {}", run_id, status.as_str()),
+            Error::NotRunOwner { run_id, player_id } => write!(f, "run '{}' does not belong to player '{}'", run_id, player_id),
+            Error::TurnMismatch { run_id, current, requested } => {
+                write!(f, "run '{}' is on turn {}, not turn {}", run_id, current, requested)
+            }
+            Error::SnapshotNotFound { run_id, turn_number } => write!(f, "run '{}' has no team for turn {}", run_id, turn_number),
+            Error::BattleNotFound { run_id, turn_number } => write!(f, "run '{}' has no battle for turn {}", run_id, turn_number),
+            Error::TurnAlreadyEnded { run_id, turn_number } => {
+                write!(f, "run '{}' already ended turn {} with a different submission", run_id, turn_number)
+            }
+            Error::UnsupportedContentVersion { run_id, content_version } => {
+                write!(f, "run '{}' is played with content version {}, which is not available", run_id, content_version)
+            }
+            Error::InvalidContent(errors) => write!(f, "embedded content is invalid: {}", errors),
+            Error::InvalidTeam(e) => write!(f, "invalid team snapshot: {}", e),
+            Error::InvalidShopAction { index, reason } => write!(f, "shop action {} is not allowed: {}", index, reason),
+            Error::TeamRejected(rejection) => write!(f, "team rejected: {}", rejection),
+            Error::LobbyNotFound(code) => write!(f, "lobby '{}' does not exist", code),
+            Error::LobbyExpired(code) => write!(f, "lobby '{}' has expired", code),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Throttled(e)
+            | Error::TableNotFound(e)
+            | Error::ConditionFailed(e)
+            | Error::TransactionConflict(e)
+            | Error::Transport(e)
+            | Error::Service(e)
+            | Error::InvalidTeam(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for Error
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: fmt::Debug + Send + Sync + 'static,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        match &e {
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => Error::Transport(Box::new(e)),
+            SdkError::ServiceError(_) => {
+                let code = e.code().map(|x| x.to_string());
+                Error::from_code(code.as_deref(), Box::new(e))
+            }
+            _ => Error::Service(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn service_errors_are_classified_by_code() {
+        let classify = |code: &str| Error::from_code(Some(code), "x".into());
+        assert!(matches!(classify("ProvisionedThroughputExceededException"), Error::Throttled(_)));
+        assert!(matches!(classify("ResourceNotFoundException"), Error::TableNotFound(_)));
+        assert!(matches!(classify("ConditionalCheckFailed"), Error::ConditionFailed(_)));
+        assert!(matches!(classify("ValidationException"), Error::Service(_)));
+        assert!(classify("TransactionConflict").is_transient());
+        assert!(!classify("ConditionalCheckFailedException").is_transient());
+    }
+}
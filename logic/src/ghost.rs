@@ -0,0 +1,153 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{PKEY, SKEY};
+
+use crate::{get_string_attr, get_u32_attr, Error, Item, MatchmakingStore, Pool};
+
+/// how many ghosts are kept per turn and pool. writes land on a random slot and replace
+/// whatever ghost was there, so the pool never grows past this size.
+pub const GHOST_POOL_SIZE: u32 = 64;
+
+pub const TEAM_ATTR: &str = "team";
+
+/// a team snapshot recorded at the end of a turn, used as an opponent
+/// when no live player could be matched.
+#[derive(Debug, Clone)]
+pub struct Ghost {
+    /// ghosts only fight runs of the pool they were recorded in
+    pub pool: Pool,
+    pub turn_number: u32,
+    pub run_id: String,
+    pub rating: u32,
+    pub team: String,
+}
+
+fn ghost_skey(slot: u32) -> String {
+    format!("slot_{:04}", slot)
+}
+
+/// archive a team snapshot into the ghost pool of its turn and pool
+pub async fn archive_ghost<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    ghost: &Ghost,
+) -> Result<(), Error> {
+    let slot = fastrand::u32(0..GHOST_POOL_SIZE);
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(ghost.pool.ghost_pkey(ghost.turn_number)));
+    item.insert(SKEY.to_string(), AttributeValue::S(ghost_skey(slot)));
+    item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(ghost.run_id.clone()));
+    item.insert(crate::RATING_ATTR.to_string(), AttributeValue::N(ghost.rating.to_string()));
+    item.insert(TEAM_ATTR.to_string(), AttributeValue::S(ghost.team.clone()));
+    store.put(table_name, item).await
+}
+
+pub async fn list_ghosts<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pool: &Pool,
+    turn_number: u32,
+) -> Result<Vec<Ghost>, Error> {
+    let items = store.query_partition(table_name, &pool.ghost_pkey(turn_number)).await?;
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        out.push(Ghost {
+            pool: pool.clone(),
+            turn_number,
+            run_id: get_string_attr(&item, crate::RUN_ID_ATTR)?,
+            rating: get_u32_attr(&item, crate::RATING_ATTR)?,
+            team: get_string_attr(&item, TEAM_ATTR)?,
+        });
+    }
+    Ok(out)
+}
+
+/// the order turns are searched in when sampling a ghost:
+/// the exact turn first, then alternating one turn earlier and one turn later, widening up to `max_turn_distance`
+pub fn ghost_search_order(turn_number: u32, max_turn_distance: u32) -> Vec<u32> {
+    let mut out = vec![turn_number];
+    for distance in 1..=max_turn_distance {
+        if let Some(earlier) = turn_number.checked_sub(distance) {
+            out.push(earlier);
+        }
+        if let Some(later) = turn_number.checked_add(distance) {
+            out.push(later);
+        }
+    }
+    out
+}
+
+/// pick a random ghost to fight when matchmaking returned `FakeSimulate`.
+/// only ghosts of `pool` are picked: runs of an old content version still finish against ghosts of
+/// their version, and lobby runs only fight ghosts of other lobby members.
+/// ghosts recorded by `exclude_run_id` are never picked so a run does not fight itself.
+/// returns None if no ghost exists within `max_turn_distance` turns.
+pub async fn sample_ghost<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pool: &Pool,
+    turn_number: u32,
+    exclude_run_id: &str,
+    max_turn_distance: u32,
+) -> Result<Option<Ghost>, Error> {
+    for turn in ghost_search_order(turn_number, max_turn_distance) {
+        let mut ghosts = list_ghosts(store, table_name, pool, turn).await?;
+        ghosts.retain(|x| x.run_id != exclude_run_id);
+        if !ghosts.is_empty() {
+            let index = fastrand::usize(0..ghosts.len());
+            return Ok(Some(ghosts.swap_remove(index)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TC_TABLE;
+
+    fn ghost(turn_number: u32, run_id: &str) -> Ghost {
+        Ghost { pool: Pool::public(1), turn_number, run_id: run_id.to_string(), rating: 100, team: format!("team_{}", run_id) }
+    }
+
+    #[test]
+    fn search_order_alternates_and_stops_at_zero() {
+        assert_eq!(ghost_search_order(2, 3), vec![2, 1, 3, 0, 4, 5]);
+    }
+
+    tc!(pool_is_bounded; |c| {
+        for i in 0..(GHOST_POOL_SIZE * 4) {
+            archive_ghost(c, TC_TABLE, &ghost(1, &i.to_string())).await.expect("failed to archive ghost");
+        }
+        let ghosts = list_ghosts(c, TC_TABLE, &Pool::public(1), 1).await.expect("failed to list ghosts");
+        assert!(!ghosts.is_empty());
+        assert!(ghosts.len() <= GHOST_POOL_SIZE as usize);
+    });
+
+    tc!(sample_skips_own_ghost; |c| {
+        archive_ghost(c, TC_TABLE, &ghost(3, "a")).await.expect("failed to archive ghost");
+        let res = sample_ghost(c, TC_TABLE, &Pool::public(1), 3, "a", 0).await.expect("failed to sample");
+        assert!(res.is_none());
+    });
+
+    tc!(sample_falls_back_to_nearby_turns; |c| {
+        archive_ghost(c, TC_TABLE, &ghost(6, "b")).await.expect("failed to archive ghost");
+        let res = sample_ghost(c, TC_TABLE, &Pool::public(1), 5, "a", 0).await.expect("failed to sample");
+        assert!(res.is_none());
+        let res = sample_ghost(c, TC_TABLE, &Pool::public(1), 5, "a", 1).await.expect("failed to sample").expect("should find a ghost");
+        assert_eq!(res.turn_number, 6);
+        assert_eq!(res.run_id, "b");
+        assert_eq!(res.team, "team_b");
+    });
+
+    tc!(sample_stays_within_pool; |c| {
+        let (public, new, lobby) = (Pool::public(1), Pool::public(2), Pool { content_version: 1, lobby: Some("ABC".to_string()) });
+        archive_ghost(c, TC_TABLE, &Ghost { pool: new.clone(), ..ghost(4, "new") }).await.expect("failed to archive ghost");
+        archive_ghost(c, TC_TABLE, &Ghost { pool: lobby.clone(), ..ghost(4, "friend") }).await.expect("failed to archive ghost");
+        assert!(sample_ghost(c, TC_TABLE, &public, 4, "a", 1).await.expect("failed to sample").is_none());
+        archive_ghost(c, TC_TABLE, &ghost(4, "old")).await.expect("failed to archive ghost");
+        for (pool, run_id) in [(public, "old"), (new, "new"), (lobby, "friend")] {
+            let res = sample_ghost(c, TC_TABLE, &pool, 4, "a", 1).await.expect("failed to sample").expect("should find a ghost");
+            assert_eq!((res.pool, res.run_id.as_str()), (pool, run_id));
+        }
+    });
+}
@@ -0,0 +1,184 @@
+//! leaderboards of finished runs. every finished run gets an entry in the daily, weekly and all-time
+//! partition its completion falls into. the sort key starts with the zero-padded inverted score, so
+//! reading a partition in sort key order lists the best runs first and the rank of an entry is the
+//! number of entries before it.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{
+    api::{LeaderboardEntry, LeaderboardPeriod},
+    PKEY, SKEY,
+};
+
+use crate::{get_number_attr, get_string_attr, get_u32_attr, run, Error, Item, MatchmakingStore, Run, RunStatus};
+
+/// `top_n` returns at most this many entries
+pub const MAX_TOP_N: u32 = 100;
+
+pub const LIVES_LOST_ATTR: &str = "lives_lost";
+pub const COMPLETED_AT_ATTR: &str = "completed_at";
+/// on the item in the run's partition: the sort key of the run's entries
+pub const ENTRY_SKEY_ATTR: &str = "entry_skey";
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// `days` since the unix epoch as `yyyy-mm-dd`
+fn date(days: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// the partition of `period` that a run completed at `at` (unix seconds) is ranked in
+pub fn leaderboard_pkey(period: LeaderboardPeriod, at: u64) -> String {
+    let day = at / SECS_PER_DAY;
+    let board = match period {
+        LeaderboardPeriod::Daily => format!("daily_{}", date(day)),
+        // the epoch was a thursday
+        LeaderboardPeriod::Weekly => format!("weekly_{}", date(day.saturating_sub((day + 3) % 7))),
+        LeaderboardPeriod::AllTime => "all_time".to_string(),
+    };
+    shared::leaderboard_pkey(&board)
+}
+
+/// more wins first, then fewer lives lost, then whoever finished first
+fn entry_skey(wins: u32, lives_lost: u32, completed_at: u64, run_id: &str) -> String {
+    format!("{:010}_{:05}_{:010}_{}", u32::MAX - wins, lives_lost, completed_at, run_id)
+}
+
+fn entry_from_item(item: &Item, rank: u64) -> Result<LeaderboardEntry, Error> {
+    Ok(LeaderboardEntry {
+        rank,
+        run_id: get_string_attr(item, crate::RUN_ID_ATTR)?,
+        player_id: get_string_attr(item, run::OWNER_ATTR)?,
+        wins: get_u32_attr(item, run::WINS_ATTR)?,
+        lives_lost: get_u32_attr(item, LIVES_LOST_ATTR)?,
+        completed_at: get_number_attr(item, COMPLETED_AT_ATTR)?,
+    })
+}
+
+/// adds a run that finished at `completed_at` to every leaderboard. abandoned runs are not ranked,
+/// and lobby runs are only ranked in their lobby's standings. recording the same run again does nothing.
+pub async fn record_run<S: MatchmakingStore>(store: &S, table_name: &str, run: &Run, completed_at: u64) -> Result<(), Error> {
+    if !matches!(run.status, RunStatus::Won | RunStatus::Lost) || run.lobby.is_some() {
+        return Ok(());
+    }
+    let lives_lost = run::STARTING_LIVES.saturating_sub(run.lives);
+    let skey = entry_skey(run.wins, lives_lost, completed_at, &run.run_id);
+    let mut items = Vec::with_capacity(LeaderboardPeriod::ALL.len() + 1);
+    for period in LeaderboardPeriod::ALL {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(leaderboard_pkey(period, completed_at)));
+        item.insert(SKEY.to_string(), AttributeValue::S(skey.clone()));
+        item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(run.run_id.clone()));
+        item.insert(run::OWNER_ATTR.to_string(), AttributeValue::S(run.owner.clone()));
+        item.insert(run::WINS_ATTR.to_string(), AttributeValue::N(run.wins.to_string()));
+        item.insert(LIVES_LOST_ATTR.to_string(), AttributeValue::N(lives_lost.to_string()));
+        item.insert(COMPLETED_AT_ATTR.to_string(), AttributeValue::N(completed_at.to_string()));
+        items.push(item);
+    }
+    let mut pointer = Item::new();
+    pointer.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(&run.run_id)));
+    pointer.insert(SKEY.to_string(), AttributeValue::S(shared::LEADERBOARD_SKEY.to_string()));
+    pointer.insert(ENTRY_SKEY_ATTR.to_string(), AttributeValue::S(skey));
+    pointer.insert(COMPLETED_AT_ATTR.to_string(), AttributeValue::N(completed_at.to_string()));
+    items.push(pointer);
+    match store.put_all_if_absent(table_name, items).await {
+        // the pointer only exists if the entries were written along with it
+        Ok(()) | Err(Error::ConditionFailed(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// the best `n` runs (at most `MAX_TOP_N`) of the `period` that contains `at` (unix seconds)
+pub async fn top_n<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    period: LeaderboardPeriod,
+    at: u64,
+    n: u32,
+) -> Result<Vec<LeaderboardEntry>, Error> {
+    // dynamodb rejects a limit of 0
+    if n == 0 {
+        return Ok(vec![]);
+    }
+    let page = store.query_page(table_name, &leaderboard_pkey(period, at), None, n.min(MAX_TOP_N)).await?;
+    page.items.iter().enumerate().map(|(i, item)| entry_from_item(item, i as u64 + 1)).collect()
+}
+
+/// where `run_id` is ranked on the `period` leaderboard it finished in. None if it is not ranked
+pub async fn rank_of<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    period: LeaderboardPeriod,
+    run_id: &str,
+) -> Result<Option<LeaderboardEntry>, Error> {
+    let Some(pointer) = store.get(table_name, &shared::run_pkey(run_id), shared::LEADERBOARD_SKEY).await? else {
+        return Ok(None);
+    };
+    let skey = get_string_attr(&pointer, ENTRY_SKEY_ATTR)?;
+    let pkey = leaderboard_pkey(period, get_number_attr(&pointer, COMPLETED_AT_ATTR)?);
+    let Some(item) = store.get(table_name, &pkey, &skey).await? else {
+        return Ok(None);
+    };
+    let before = store.count_before(table_name, &pkey, &skey).await?;
+    Ok(Some(entry_from_item(&item, before + 1)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TC_TABLE;
+
+    fn finished(run_id: &str, wins: u32, lives: u32) -> Run {
+        let mut run = Run::new(run_id.to_string(), format!("p_{}", run_id));
+        (run.wins, run.lives) = (wins, lives);
+        run.status = if lives == 0 { RunStatus::Lost } else { RunStatus::Won };
+        run
+    }
+
+    #[test]
+    fn partitions_follow_the_calendar() {
+        // 2024-03-01 was a friday
+        let at = 1_709_294_400;
+        assert_eq!(leaderboard_pkey(LeaderboardPeriod::Daily, at), "leaderboard_daily_2024-03-01");
+        assert_eq!(leaderboard_pkey(LeaderboardPeriod::Weekly, at), "leaderboard_weekly_2024-02-26");
+        assert_eq!(leaderboard_pkey(LeaderboardPeriod::AllTime, at), "leaderboard_all_time");
+        assert_eq!(date(0), "1970-01-01");
+    }
+
+    tc!(runs_are_ranked_by_wins_then_lives_lost_then_completion; |c| {
+        let day = 1_709_294_400;
+        record_run(c, TC_TABLE, &finished("slow", 10, 3), day + 20).await.expect("failed to record");
+        record_run(c, TC_TABLE, &finished("fast", 10, 3), day + 10).await.expect("failed to record");
+        record_run(c, TC_TABLE, &finished("clean", 10, 5), day + 30).await.expect("failed to record");
+        record_run(c, TC_TABLE, &finished("lost", 4, 0), day + 5).await.expect("failed to record");
+        // a day later, so only on the weekly and all-time leaderboards
+        record_run(c, TC_TABLE, &finished("next", 2, 0), day + SECS_PER_DAY).await.expect("failed to record");
+        // abandoned runs are not ranked, and recording twice changes nothing
+        let mut abandoned = finished("gone", 10, 5);
+        abandoned.status = RunStatus::Abandoned;
+        record_run(c, TC_TABLE, &abandoned, day).await.expect("failed to record");
+        record_run(c, TC_TABLE, &finished("fast", 10, 3), day + 40).await.expect("failed to record");
+
+        let top = top_n(c, TC_TABLE, LeaderboardPeriod::Daily, day, 10).await.expect("failed to list");
+        let ids: Vec<_> = top.iter().map(|x| (x.rank, x.run_id.as_str())).collect();
+        assert_eq!(ids, vec![(1, "clean"), (2, "fast"), (3, "slow"), (4, "lost")]);
+        assert_eq!((top[1].player_id.as_str(), top[1].lives_lost, top[1].completed_at), ("p_fast", 2, day + 10));
+        assert_eq!(top_n(c, TC_TABLE, LeaderboardPeriod::Weekly, day, 10).await.expect("failed to list").len(), 5);
+        assert_eq!(top_n(c, TC_TABLE, LeaderboardPeriod::AllTime, day, 2).await.expect("failed to list").len(), 2);
+
+        let rank = |period, run_id| rank_of(c, TC_TABLE, period, run_id);
+        assert_eq!(rank(LeaderboardPeriod::Daily, "slow").await.expect("failed to rank").map(|x| x.rank), Some(3));
+        assert_eq!(rank(LeaderboardPeriod::Daily, "next").await.expect("failed to rank").map(|x| x.rank), Some(1));
+        assert_eq!(rank(LeaderboardPeriod::AllTime, "next").await.expect("failed to rank").map(|x| x.rank), Some(5));
+        assert_eq!(rank(LeaderboardPeriod::AllTime, "gone").await.expect("failed to rank"), None);
+    });
+}
@@ -1,12 +1,72 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::{SystemTime, UNIX_EPOCH}};
 
-use aws_sdk_dynamodb::{types::{AttributeValue, Delete, TransactWriteItem}, Client};
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use shared::{PKEY, SKEY};
 
+/// defines an async test case that runs against a fresh `MemoryStore`.
+/// `TC_TABLE` must be in scope at the call site.
+#[cfg(test)]
+macro_rules! tc {
+    ($name:ident; |$c:ident| { $($x:tt)*}) => {
+        #[test]
+        fn $name() {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("aa");
+            rt.block_on(async {
+                let store = $crate::MemoryStore::new(&[TC_TABLE]);
+                let $c = &store;
+                $($x)*
+            });
+        }
+    };
+}
+
+pub mod battle_result;
+pub mod error;
+pub mod ghost;
+pub mod leaderboard;
+pub mod lobby;
+pub mod pending;
+pub mod reaper;
+pub mod replay;
+pub mod retry;
+pub mod run;
+pub mod shop;
+pub mod store;
+pub mod validate;
+pub mod worker;
+
+pub use shared::battle;
+pub use battle_result::{get_battle_result, BattleRecord, BattleStatus, OpponentRef};
+pub use error::Error;
+pub use ghost::{sample_ghost, Ghost};
+pub use leaderboard::{rank_of, top_n};
+pub use lobby::{create_lobby, get_lobby, join_lobby, lobby_standings, Joined, Lobby};
+pub use pending::{get_pending, list_pending, PendingBattle};
+pub use reaper::{reap_stale_entries, ReapReport, ReaperConfig};
+pub use replay::{verify_battle, verify_replay, Divergence};
+pub use retry::{Retried, RetryPolicy};
+pub use run::{create_run, end_run, get_run, record_battle_result, BattleOutcome, Run, RunStatus};
+pub use shop::{apply_shop_actions, get_shop, shop_team, Shop, ShopBoard, ShopError};
+pub use store::{Item, MatchmakingStore, MemoryStore, Page, TransactError, Write};
+pub use validate::{check_team, list_rejections, validate_team, RejectionRecord};
+
+/// attribute holding the run an item belongs to
+pub const RUN_ID_ATTR: &str = "run_id";
+/// attribute on matchmaking items holding the player's skill/trophy rating
+pub const RATING_ATTR: &str = "rating";
+/// attribute on matchmaking items holding the unix time (seconds) the entry was written
+pub const CREATED_AT_ATTR: &str = "created_at";
+/// matchmaking items are deleted by dynamodb ttl this long after they were written, in case nothing else
+/// removed them. the reaper resolves stale entries long before that.
+pub const MATCHMAKING_TTL_SECS: u64 = 24 * 60 * 60;
+/// attribute on end turn markers holding the idempotency key of the submission that ended the turn
+pub const IDEMPOTENCY_KEY_ATTR: &str = "idempotency_key";
+/// attribute on end turn markers holding the sort key of the matchmaking entry that was written
+pub const MATCHMAKING_SKEY_ATTR: &str = "matchmaking_skey";
 
 #[derive(Debug)]
 pub enum MatchResult {
-    UnrecoverableError(String),
+    UnrecoverableError(Error),
     P1ConditionError,
     P2ConditionError,
     Matched(MatchmakingSkey, MatchmakingSkey),
@@ -14,47 +74,236 @@ pub enum MatchResult {
 
 #[derive(Debug)]
 pub enum MatchmakingResult {
-    Matched(MatchmakingSkey),
-    /// if Some(string) => there was an unknown error causing us to fake simulate
+    /// the opponent we matched with, and the rating distance that was accepted
+    Matched(MatchmakingSkey, u32),
+    /// if Some(error) => there was an unknown error causing us to fake simulate
     /// if None => there were no other players to match against, so we fake simulate
-    FakeSimulate(Option<String>),
+    FakeSimulate(Option<Error>),
     /// this happens if our player was already matched by another invocation. we can
     /// drop this request as they were already matched
     CanDrop,
 }
 
+#[derive(Debug, Clone)]
 pub struct AsyncMatchmakingRequest {
     pub turn_number: u32,
     pub skey: MatchmakingSkey,
+    pub rating: u32,
+}
+
+/// a matchmaking item as read back from the table
+#[derive(Debug, Clone)]
+pub struct MatchmakingEntry {
+    pub skey: MatchmakingSkey,
+    pub rating: u32,
+    pub created_at: u64,
+}
+
+impl MatchmakingEntry {
+    pub fn from_item(item: &Item) -> Result<Self, Error> {
+        Ok(Self {
+            skey: MatchmakingSkey::from_str(&get_string_attr(item, SKEY)?)?,
+            rating: get_u32_attr(item, RATING_ATTR)?,
+            created_at: get_number_attr(item, CREATED_AT_ATTR)?,
+        })
+    }
+}
+
+/// how the matchmaking partition is read when it is read in slices.
+/// each slice is at most `limit` entries long and starts at a random sort key.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceConfig {
+    /// a limit of 0 reads slices of a single entry
+    pub limit: u32,
+    /// how many following slices are read when no candidate in a slice could be matched,
+    /// e.g. because every one of them was already taken or the slice was empty
+    pub max_continuations: u32,
+}
+
+impl Default for SliceConfig {
+    fn default() -> Self {
+        Self { limit: 25, max_continuations: 2 }
+    }
+}
+
+/// where the next slice of a matchmaking partition starts. reading begins at a random
+/// sort key, wraps around to the start of the partition once and ends where it began.
+#[derive(Debug, Clone)]
+pub struct SliceCursor {
+    origin: String,
+    after: Option<String>,
+    wrapped: bool,
+}
+
+impl SliceCursor {
+    pub fn random() -> Self {
+        // same alphabet and length as the random component of the sort keys
+        let origin = get_random_string(16);
+        Self { after: Some(origin.clone()), origin, wrapped: false }
+    }
+}
+
+/// how far apart two ratings are allowed to be for a match.
+/// starts at `base` and grows by `growth_per_sec` for every second the
+/// longest waiting of the two entries has been in the table, capped at `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct RatingWindow {
+    pub base: u32,
+    pub growth_per_sec: u32,
+    pub max: u32,
+}
+
+impl Default for RatingWindow {
+    fn default() -> Self {
+        Self { base: 50, growth_per_sec: 10, max: u32::MAX }
+    }
+}
+
+impl RatingWindow {
+    pub fn allowed_distance(&self, age_secs: u64) -> u32 {
+        let growth = age_secs.saturating_mul(self.growth_per_sec as u64);
+        let allowed = (self.base as u64).saturating_add(growth);
+        allowed.min(self.max as u64) as u32
+    }
+}
+
+/// the runs that can meet, in matchmaking or as ghosts: those of the same content version
+/// that play in the same lobby, or outside of any lobby
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pool {
+    pub content_version: u32,
+    /// join code of the lobby. None for everybody else
+    pub lobby: Option<String>,
+}
+
+impl Pool {
+    pub fn public(content_version: u32) -> Self {
+        Self { content_version, lobby: None }
+    }
+
+    pub fn of(run: &Run) -> Self {
+        Self { content_version: run.content_version, lobby: run.lobby.clone() }
+    }
+
+    pub fn matchmaking_pkey(&self, turn_number: u32, shard: u32) -> String {
+        shared::matchmaking_pkey(self.content_version, self.lobby.as_deref(), turn_number, shard)
+    }
+
+    pub fn pending_battles_pkey(&self, turn_number: u32, shard: u32) -> String {
+        shared::pending_battles_pkey(self.content_version, self.lobby.as_deref(), turn_number, shard)
+    }
+
+    pub fn ghost_pkey(&self, turn_number: u32) -> String {
+        shared::ghost_pkey(self.content_version, self.lobby.as_deref(), turn_number)
+    }
+
+    /// lobbies are small enough to never need more than one shard
+    pub fn shard_count(&self, shard_count: u32) -> u32 {
+        if self.lobby.is_some() { 1 } else { shard_count }
+    }
 }
 
+/// the sort key of a matchmaking entry: `{random}_{shard}_{content_version}_{run_id}`, with the
+/// lobby before the run id for lobby runs: `{random}_{shard}_{content_version}_{lobby}_{run_id}`.
+/// the shard and pool are part of the key so the entry can always be found in its partition,
+/// even by an invocation using a different shard count.
 #[derive(Debug, Clone)]
 pub struct MatchmakingSkey {
     pub random_component: String,
+    pub shard: u32,
+    pub pool: Pool,
     pub run_id: String,
 }
 
 impl MatchmakingSkey {
-    pub fn new(run_id: String) -> Self {
-        Self { random_component: get_random_string(16), run_id }
+    pub fn new(run_id: String, shard: u32, pool: Pool) -> Self {
+        Self { random_component: get_random_string(16), shard, pool, run_id }
     }
     pub fn format(&self) -> String {
-        format!("{}_{}", self.random_component, self.run_id)
+        let lobby = self.pool.lobby.as_ref().map(|x| format!("{}_", x)).unwrap_or_default();
+        format!("{}_{}_{}_{}{}", self.random_component, self.shard, self.pool.content_version, lobby, self.run_id)
+    }
+    /// the partition this entry lives in
+    pub fn pkey(&self, turn_number: u32) -> String {
+        self.pool.matchmaking_pkey(turn_number, self.shard)
+    }
+
+    /// the partition the battle this entry was claimed for waits in until it is fought
+    pub fn pending_pkey(&self, turn_number: u32) -> String {
+        self.pool.pending_battles_pkey(turn_number, self.shard)
     }
 }
 
 impl FromStr for MatchmakingSkey {
-    type Err = String;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (random_component, run_id) = match s.split_once("_") {
-            Some((l, r)) => (l.to_string(), r.to_string()),
-            _ => return Err(format!("failed to detect MatchmakingSkey from '{}'", s)),
+        let malformed = || Error::MalformedSortKey(s.to_string());
+        let (random_component, rest) = s.split_once("_").ok_or_else(malformed)?;
+        let (shard, rest) = rest.split_once("_").ok_or_else(malformed)?;
+        let shard = shard.parse().map_err(|_| malformed())?;
+        let (content_version, rest) = rest.split_once("_").ok_or_else(malformed)?;
+        let content_version = content_version.parse().map_err(|_| malformed())?;
+        let (lobby, run_id) = match rest.split_once("_") {
+            Some((lobby, run_id)) => (Some(lobby.to_string()), run_id),
+            None => (None, rest),
         };
-        Ok(Self { random_component, run_id })
+        let pool = Pool { content_version, lobby };
+        Ok(Self { random_component: random_component.to_string(), shard, pool, run_id: run_id.to_string() })
     }
 }
 
+/// the order shards are searched in when matchmaking: our own shard first,
+/// then alternating one shard up and one shard down, wrapping around, until every shard was visited
+pub fn shard_search_order(own_shard: u32, shard_count: u32) -> Vec<u32> {
+    let shard_count = shard_count.max(1);
+    // our shard can be out of range if the shard count was lowered after we ended our turn
+    let base = own_shard % shard_count;
+    let mut out = vec![own_shard];
+    let mut candidates = vec![base];
+    for distance in 1..=shard_count / 2 {
+        candidates.push((base + distance) % shard_count);
+        candidates.push((base + shard_count - distance) % shard_count);
+    }
+    for shard in candidates {
+        if !out.contains(&shard) {
+            out.push(shard);
+        }
+    }
+    out
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default()
+}
+
+pub(crate) fn get_string_attr(item: &Item, name: &str) -> Result<String, Error> {
+    let attr = item.get(name).ok_or_else(|| Error::MissingAttribute(name.to_string()))?;
+    let value = attr.as_s().map_err(|e| Error::invalid_attribute(name, format!("incorrect attr type: {:?}", e)))?;
+    Ok(value.clone())
+}
+
+pub(crate) fn get_number_attr(item: &Item, name: &str) -> Result<u64, Error> {
+    let attr = item.get(name).ok_or_else(|| Error::MissingAttribute(name.to_string()))?;
+    let value = attr.as_n().map_err(|e| Error::invalid_attribute(name, format!("incorrect attr type: {:?}", e)))?;
+    value.parse().map_err(|e| Error::invalid_attribute(name, e))
+}
+
+/// like `get_number_attr`, for attributes that must fit in a u32
+pub(crate) fn get_u32_attr(item: &Item, name: &str) -> Result<u32, Error> {
+    u32::try_from(get_number_attr(item, name)?).map_err(|e| Error::invalid_attribute(name, e))
+}
+
+/// builds the item written to the matchmaking partition for `turn_number`
+pub fn matchmaking_item(turn_number: u32, skey: &MatchmakingSkey, rating: u32, created_at: u64) -> Item {
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(skey.pkey(turn_number)));
+    item.insert(SKEY.to_string(), AttributeValue::S(skey.format()));
+    item.insert(RATING_ATTR.to_string(), AttributeValue::N(rating.to_string()));
+    item.insert(CREATED_AT_ATTR.to_string(), AttributeValue::N(created_at.to_string()));
+    item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N((created_at + MATCHMAKING_TTL_SECS).to_string()));
+    item
+}
 
 #[allow(deprecated)]
 pub async fn get_client() -> Client {
@@ -65,161 +314,433 @@ pub async fn get_client() -> Client {
 /// only fetches one page instead of paginating. reason is
 /// we only care about matchmaking 1:1, therefore no reason to get every single possible opponent.
 /// also, the sort keys have a random prefix, which should make the sorting random.
-pub async fn list_matchmaking_entries(
-    ddb_client: &Client,
+pub async fn list_matchmaking_entries<S: MatchmakingStore>(
+    store: &S,
     table_name: &str,
-    turn_number: u32
-) -> Result<Vec<MatchmakingSkey>, String> {
-    let out = ddb_client.query()
-        .table_name(table_name)
-        .key_condition_expression(format!("{} = :pkey", PKEY))
-        .expression_attribute_values(":pkey", AttributeValue::S(shared::matchmaking_pkey(turn_number)))
-        .send().await.map_err(|e| e.to_string())?;
-    let items = out.items().to_vec(); 
-    let mut out_items = Vec::with_capacity(items.len());
-    for mut item in items {
-        let skey = item.remove(SKEY).ok_or(&format!("failed to find '{}' sort key", SKEY))?;
-        let skey_value = skey.as_s().map_err(|e| format!("incorrect attr type for {}: {:?}", SKEY, e))?;
-        let matchmakingskey = MatchmakingSkey::from_str(&skey_value)?;
-        out_items.push(matchmakingskey);
-    }
-    Ok(out_items)
+    pool: Pool,
+    turn_number: u32,
+    shard: u32,
+) -> Result<Vec<MatchmakingEntry>, Error> {
+    let items = store.query_partition(table_name, &pool.matchmaking_pkey(turn_number, shard)).await?;
+    items.iter().map(MatchmakingEntry::from_item).collect()
+}
+
+/// reads up to `limit` (at least 1) entries of a matchmaking partition starting at `cursor`.
+/// returns the cursor for the following slice, or None once the whole partition was read.
+pub async fn list_matchmaking_slice<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pool: &Pool,
+    turn_number: u32,
+    shard: u32,
+    mut cursor: SliceCursor,
+    limit: u32,
+) -> Result<(Vec<MatchmakingEntry>, Option<SliceCursor>), Error> {
+    let pkey = pool.matchmaking_pkey(turn_number, shard);
+    // an empty slice would never move the cursor
+    let limit = limit.max(1);
+    let mut out = Vec::new();
+    while out.len() < limit as usize {
+        let remaining = limit - out.len() as u32;
+        let page = store.query_page(table_name, &pkey, cursor.after.as_deref(), remaining).await?;
+        for item in &page.items {
+            let entry = MatchmakingEntry::from_item(item)?;
+            let skey = entry.skey.format();
+            if cursor.wrapped && skey > cursor.origin {
+                // back where we started
+                return Ok((out, None));
+            }
+            cursor.after = Some(skey);
+            out.push(entry);
+        }
+        if !page.more {
+            if cursor.wrapped {
+                return Ok((out, None));
+            }
+            cursor.wrapped = true;
+            cursor.after = None;
+        }
+    }
+    Ok((out, Some(cursor)))
 }
 
 pub fn get_random_string(num: usize) -> String {
     let mut out = String::with_capacity(num);
     for _ in 0..num {
-        let c = fastrand::char('a'..'z');
+        let c = fastrand::char('a'..='z');
         out.push(c);
     }
     out
 }
 
-pub async fn end_turn(
-    ddb_client: &Client,
+/// the matchmaking entry a turn ended with
+#[derive(Debug, Clone)]
+pub struct EndedTurn {
+    pub skey: MatchmakingSkey,
+    /// the rating the run entered matchmaking with
+    pub rating: u32,
+    /// the turn had already been ended by an earlier submission with the same idempotency key
+    pub replayed: bool,
+}
+
+/// idempotency key used when the client does not send one: any second submission for the turn is a replay
+pub fn default_idempotency_key(run_id: &str, turn_number: u32) -> String {
+    format!("{}:{}", run_id, turn_number)
+}
+
+/// submits `team` for the current turn of a run owned by `player_id` and puts the run into matchmaking
+#[allow(clippy::too_many_arguments)]
+pub async fn end_turn<S: MatchmakingStore>(
+    store: &S,
     table_name: &str,
     turn_number: u32,
     run_id: String,
-) -> Result<MatchmakingSkey, String> {
-    let skey = MatchmakingSkey::new(run_id);
-    ddb_client.put_item()
-        .table_name(table_name)
-        .item(PKEY, AttributeValue::S(shared::matchmaking_pkey(turn_number)))
-        .item(SKEY, AttributeValue::S(skey.format()))
-        // this is unlikely to happen as we have a random component, but just in case:
-        .condition_expression(format!("attribute_not_exists({PKEY})"))
-        .send().await.map_err(|e| format!("Failed to end turn: {:?}", e))?;
-    Ok(skey)
-}
-
-pub async fn delete_item(
-    ddb_client: &Client,
+    player_id: &str,
+    team: String,
+    shard_count: u32,
+) -> Result<MatchmakingSkey, Error> {
+    let idempotency_key = default_idempotency_key(&run_id, turn_number);
+    let ended = write_end_turn(store, table_name, turn_number, &run_id, player_id, &team, None, shard_count, &idempotency_key).await?;
+    Ok(ended.skey)
+}
+
+/// `end_turn`, retrying transient errors according to `policy`.
+/// submissions with the same `idempotency_key` end the turn once and all get the same matchmaking entry back.
+/// with a `shop_version`, the turn only ends if the shop's action log is still at that version, so the
+/// team is the board the shop had when it was read. otherwise it fails with `Error::ConditionFailed`
+#[allow(clippy::too_many_arguments)]
+pub async fn end_turn_with_retry<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    turn_number: u32,
+    run_id: String,
+    player_id: &str,
+    team: String,
+    shop_version: Option<u64>,
+    shard_count: u32,
+    idempotency_key: Option<String>,
+    policy: &RetryPolicy,
+) -> Retried<Result<EndedTurn, Error>> {
+    let idempotency_key = idempotency_key.unwrap_or_else(|| default_idempotency_key(&run_id, turn_number));
+    let (run_id, team, idempotency_key) = (&run_id, &team, &idempotency_key);
+    // an attempt whose response got lost shows up as a replay on the next one
+    let res = retry::retry(policy, move |_| {
+        write_end_turn(store, table_name, turn_number, run_id, player_id, team, shop_version, shard_count, idempotency_key)
+    }).await;
+    let value = res.value.map(|x| EndedTurn { replayed: x.replayed && res.retries == 0, ..x });
+    Retried { value, retries: res.retries }
+}
+
+/// spreads the players ending a turn evenly over the shards of its matchmaking pool
+fn random_shard(shard_count: u32) -> u32 {
+    fastrand::u32(0..shard_count.max(1))
+}
+
+fn end_turn_marker(run_id: &str, turn_number: u32, skey: &MatchmakingSkey, rating: u32, idempotency_key: &str) -> Item {
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(run_id)));
+    item.insert(SKEY.to_string(), AttributeValue::S(shared::end_turn_skey(turn_number)));
+    item.insert(IDEMPOTENCY_KEY_ATTR.to_string(), AttributeValue::S(idempotency_key.to_string()));
+    item.insert(MATCHMAKING_SKEY_ATTR.to_string(), AttributeValue::S(skey.format()));
+    item.insert(RATING_ATTR.to_string(), AttributeValue::N(rating.to_string()));
+    item
+}
+
+/// the entry an earlier submission ended the turn with, if there was one
+async fn find_ended_turn<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    turn_number: u32,
+    run_id: &str,
+    idempotency_key: &str,
+) -> Result<Option<EndedTurn>, Error> {
+    let Some(marker) = store.get(table_name, &shared::run_pkey(run_id), &shared::end_turn_skey(turn_number)).await? else {
+        return Ok(None);
+    };
+    if get_string_attr(&marker, IDEMPOTENCY_KEY_ATTR)? != idempotency_key {
+        return Err(Error::TurnAlreadyEnded { run_id: run_id.to_string(), turn_number });
+    }
+    let skey = get_string_attr(&marker, MATCHMAKING_SKEY_ATTR)?.parse()?;
+    // turns ended before ratings were derived on the server did not keep one
+    let rating = get_u32_attr(&marker, RATING_ATTR).unwrap_or(run::BASE_RATING);
+    Ok(Some(EndedTurn { skey, rating, replayed: true }))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write_end_turn<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    turn_number: u32,
+    run_id: &str,
+    player_id: &str,
+    team: &str,
+    shop_version: Option<u64>,
+    shard_count: u32,
+    idempotency_key: &str,
+) -> Result<EndedTurn, Error> {
+    let run = run::load_run(store, table_name, run_id).await?;
+    run.ensure_owner(player_id)?;
+    // replays are checked before the turn, as the run may have moved on since the first submission
+    if let Some(ended) = find_ended_turn(store, table_name, turn_number, run_id, idempotency_key).await? {
+        return Ok(ended);
+    }
+    // the run is the source of truth for which turn is being played, not the caller
+    run.ensure_turn(turn_number)?;
+    let rating = run.rating();
+    // runs only ever meet runs and ghosts of their own pool
+    let pool = Pool::of(&run);
+    let skey = MatchmakingSkey::new(run_id.to_string(), random_shard(pool.shard_count(shard_count)), pool.clone());
+    let indexed = reaper::IndexedPool { pool: pool.clone(), turn_number, shard: skey.shard };
+    reaper::index_pool(store, table_name, &indexed, now_secs()).await?;
+    // the marker, the team and the matchmaking entry are written together, so a turn is ended
+    // exactly once and a concurrent submission cannot replace the team that gets matched.
+    let mut writes = vec![
+        Write::PutIfAbsent(end_turn_marker(run_id, turn_number, &skey, rating, idempotency_key)),
+        Write::PutIfAbsent(run::snapshot_item(run_id, turn_number, team)),
+        Write::PutIfAbsent(matchmaking_item(turn_number, &skey, rating, now_secs())),
+    ];
+    // and the shop is closed at the version the team was read from
+    if let Some(version) = shop_version {
+        writes.push(shop::close_shop(store, table_name, run_id, turn_number, version).await?);
+    }
+    match store.transact(table_name, writes).await {
+        Ok(()) => {}
+        // lost a race against another submission for the same turn
+        Err(TransactError::ConditionFailed(i)) if i < 3 => {
+            return find_ended_turn(store, table_name, turn_number, run_id, idempotency_key).await?
+                .ok_or_else(|| TransactError::ConditionFailed(i).into());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    // the ghost pool is overwritten in place. the turn has ended at this point, so failing here
+    // would only make the retry a replay that never queues matchmaking. we only miss a ghost
+    let ghost = Ghost { pool, turn_number, run_id: run_id.to_string(), rating, team: team.to_string() };
+    if let Err(e) = ghost::archive_ghost(store, table_name, &ghost).await {
+        eprintln!("failed to archive the ghost of run '{}' on turn {}: {}", run_id, turn_number, e);
+    }
+    Ok(EndedTurn { skey, rating, replayed: false })
+}
+
+pub async fn delete_item<S: MatchmakingStore>(
+    store: &S,
     table_name: &str,
     pkey: &str,
     skey: &str,
-) -> Result<(), String> {
-    ddb_client.delete_item()
-        .table_name(table_name)
-        .key(PKEY, AttributeValue::S(pkey.to_string()))
-        .key(SKEY, AttributeValue::S(skey.to_string()))
-        .send().await.map_err(|e| e.to_string())?;
-    Ok(())
+) -> Result<(), Error> {
+    store.delete(table_name, pkey, skey).await
 }
 
-pub async fn attempt_match(
-    ddb_client: &Client,
+pub async fn attempt_match<S: MatchmakingStore>(
+    store: &S,
     table_name: &str,
     turn_number: u32,
     player1: MatchmakingSkey,
     player2: MatchmakingSkey,
 ) -> MatchResult {
-    let delete1 = Delete::builder()
-        .table_name(table_name)
-        .key(PKEY, AttributeValue::S(shared::matchmaking_pkey(turn_number)))
-        .key(SKEY, AttributeValue::S(player1.format()))
-        .condition_expression(format!("attribute_exists({PKEY})"))
-        .return_values_on_condition_check_failure(aws_sdk_dynamodb::types::ReturnValuesOnConditionCheckFailure::AllOld)
-        .build().expect("transaction builder failure!");
-
-    let delete2 = Delete::builder()
-        .table_name(table_name)
-        .key(PKEY, AttributeValue::S(shared::matchmaking_pkey(turn_number)))
-        .key(SKEY, AttributeValue::S(player2.format()))
-        .condition_expression(format!("attribute_exists({PKEY})"))
-        .return_values_on_condition_check_failure(aws_sdk_dynamodb::types::ReturnValuesOnConditionCheckFailure::AllOld)
-        .build().expect("transaction builder failure!");
-
-    let resp = ddb_client
-        .transact_write_items()
-        .transact_items(TransactWriteItem::builder().delete(delete1).build())
-        .transact_items(TransactWriteItem::builder().delete(delete2).build())        
-        .send()
-        .await;
-    match resp {
-        Ok(_) => MatchResult::Matched(player1, player2),
-        Err(e) => {
-            if let Some(transact_err) = e.as_service_error() {
-                match transact_err {
-                    aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError::TransactionCanceledException(transaction_canceled_exception) => {
-                        let reasons = transaction_canceled_exception.cancellation_reasons.clone().unwrap_or_default();
-                        let reason1 = reasons.get(0).and_then(|x| Some(x.message.is_some()));
-                        let reason2 = reasons.get(1).and_then(|x| Some(x.message.is_some()));
-                        if reason1 == Some(true) {
-                            // condition error on p1
-                            // this should trump condition error on p2
-                            MatchResult::P1ConditionError
-                        } else if reason2 == Some(true) {
-                            MatchResult::P2ConditionError
-                        } else {
-                            // not sure what can cause this, but we treat it as unrecoverable just in case
-                            MatchResult::UnrecoverableError(transaction_canceled_exception.to_string())
-                        }
-                    }
-                    e => MatchResult::UnrecoverableError(e.to_string()),
-                }
-            } else {
-                MatchResult::UnrecoverableError(e.to_string())
-            }
-        }
+    // the players can be in different shards, which a transaction handles just fine.
+    // the pairing is written along with the deletes, so whoever finishes the battle fights this one
+    let [pending1, pending2] = PendingBattle::matched(turn_number, &player1, &player2, fastrand::u64(..), now_secs());
+    let writes = vec![
+        Write::DeleteExisting(player1.pkey(turn_number), player1.format()),
+        Write::DeleteExisting(player2.pkey(turn_number), player2.format()),
+        Write::PutIfAbsent(pending1.to_item()),
+        Write::PutIfAbsent(pending2.to_item()),
+    ];
+    match store.transact(table_name, writes).await {
+        Ok(()) => MatchResult::Matched(player1, player2),
+        // p1 failing trumps p2 failing
+        Err(TransactError::ConditionFailed(0)) => MatchResult::P1ConditionError,
+        Err(TransactError::ConditionFailed(1)) => MatchResult::P2ConditionError,
+        Err(e) => MatchResult::UnrecoverableError(e.into()),
     }
 }
 
-pub async fn attempt_matchmaking<'a, Fut>(
-    ddb_client: &'a Client,
+/// `attempt_match`, retrying according to `policy`. only throttling and transaction conflicts are
+/// retried: those cancel the whole transaction, whereas after a transport error we cannot know if
+/// the items were deleted, and a retry would then wrongly report them as already matched.
+pub async fn attempt_match_with_retry<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    turn_number: u32,
+    player1: MatchmakingSkey,
+    player2: MatchmakingSkey,
+    policy: &RetryPolicy,
+) -> Retried<MatchResult> {
+    let cancelled = |e: &Error| matches!(e, Error::Throttled(_) | Error::TransactionConflict(_));
+    let (player1, player2) = (&player1, &player2);
+    let res = retry::retry_if(policy, cancelled, move |_| async move {
+        match attempt_match(store, table_name, turn_number, player1.clone(), player2.clone()).await {
+            MatchResult::UnrecoverableError(e) => Err(e),
+            res => Ok(res),
+        }
+    }).await;
+    Retried { value: res.value.unwrap_or_else(MatchResult::UnrecoverableError), retries: res.retries }
+}
+
+/// attempts to match `player1` against the closest rated opponent in its turn partition,
+/// which only holds runs of its pool.
+/// candidates are tried from the smallest rating distance to the largest, and only
+/// if the distance fits in `window` for the age of the longest waiting of the two entries.
+/// our own shard is searched first, then the other shards in `shard_search_order`.
+/// listing and matching are retried according to `policy`, and the retries of all calls are summed up.
+pub async fn attempt_matchmaking<'a, S: MatchmakingStore, Fut>(
+    store: &'a S,
     table_name: &'a str,
     player1: AsyncMatchmakingRequest,
-    list_matchmaking_fn: fn(&'a Client, &'a str, u32) -> Fut,
-) -> Result<MatchmakingResult, String>
-    where Fut: Future<Output = Result<Vec<MatchmakingSkey>, String>>,
+    window: RatingWindow,
+    policy: &RetryPolicy,
+    shard_count: u32,
+    list_matchmaking_fn: fn(&'a S, &'a str, Pool, u32, u32) -> Fut,
+) -> Result<Retried<MatchmakingResult>, Error>
+    where Fut: Future<Output = Result<Vec<MatchmakingEntry>, Error>>,
 {
-    let mut available_opponents = list_matchmaking_fn(ddb_client, table_name, player1.turn_number).await?;
-    // prevent matching against self!
-    available_opponents.retain(|x| x.run_id != player1.skey.run_id || x.random_component != player1.skey.random_component);
-
-    let AsyncMatchmakingRequest { turn_number, skey } = player1;
-    for op in available_opponents {
-        match attempt_match(ddb_client, table_name, turn_number, skey.clone(), op).await {
-            MatchResult::P2ConditionError => {},
-            MatchResult::P1ConditionError => return Ok(MatchmakingResult::CanDrop),
-            MatchResult::UnrecoverableError(e) => return Ok(MatchmakingResult::FakeSimulate(Some(e))),
-            MatchResult::Matched(_, matchmaking_skey) => {
-                return Ok(MatchmakingResult::Matched(matchmaking_skey))
-            }
+    let mut retries = 0;
+    let mut p1_created_at = None;
+    for shard in shard_search_order(player1.skey.shard, player1.skey.pool.shard_count(shard_count)) {
+        let listed = retry::retry(policy, |_| {
+            list_matchmaking_fn(store, table_name, player1.skey.pool.clone(), player1.turn_number, shard)
+        }).await;
+        retries += listed.retries;
+        let available_opponents = listed.value?;
+        // our own entry is usually part of the listing of our shard. if it is, it tells us how long we have been waiting.
+        let p1_created_at = *p1_created_at.get_or_insert_with(|| {
+            available_opponents.iter()
+                .find(|x| is_same_entry(&x.skey, &player1.skey))
+                .map(|x| x.created_at)
+                .unwrap_or_else(now_secs)
+        });
+        let res = match_candidates(store, table_name, &player1, p1_created_at, available_opponents, window, policy, &mut retries).await;
+        if let Some(value) = res {
+            return Ok(Retried { value, retries });
         }
     }
 
-    // if we get here it means we ran out of opponents to match against (or there were none)
+    // if we get here it means we ran out of opponents to match against (or there were none within our rating window)
     // so we should simulate a fake opponent for the matchmaking
-    Ok(MatchmakingResult::FakeSimulate(None))
+    Ok(Retried { value: MatchmakingResult::FakeSimulate(None), retries })
+}
+
+/// like `attempt_matchmaking`, but reads each shard in bounded slices starting at a random
+/// sort key, so the work per invocation stays the same no matter how many players are waiting.
+/// if no candidate in a slice can be matched, up to `slices.max_continuations` further slices are tried,
+/// moving on to the next shard once one was read completely. slices without anybody else in them are free.
+pub async fn attempt_matchmaking_sliced<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player1: AsyncMatchmakingRequest,
+    window: RatingWindow,
+    policy: &RetryPolicy,
+    shard_count: u32,
+    slices: SliceConfig,
+) -> Result<Retried<MatchmakingResult>, Error> {
+    // our own entry is unlikely to be in a random slice, so read it directly. if it is gone,
+    // another invocation already matched us.
+    let pkey = player1.skey.pkey(player1.turn_number);
+    let p1_skey = player1.skey.format();
+    let own = retry::retry(policy, |_| store.get(table_name, &pkey, &p1_skey)).await;
+    let mut retries = own.retries;
+    let p1_created_at = match own.value? {
+        Some(item) => get_number_attr(&item, CREATED_AT_ATTR)?,
+        None => return Ok(Retried { value: MatchmakingResult::CanDrop, retries }),
+    };
+
+    let mut budget = slices.max_continuations + 1;
+    'shards: for shard in shard_search_order(player1.skey.shard, player1.skey.pool.shard_count(shard_count)) {
+        let mut cursor = Some(SliceCursor::random());
+        while let Some(current) = cursor.take() {
+            if budget == 0 {
+                break 'shards;
+            }
+            let listed = retry::retry(policy, |_| {
+                list_matchmaking_slice(store, table_name, &player1.skey.pool, player1.turn_number, shard, current.clone(), slices.limit)
+            }).await;
+            retries += listed.retries;
+            let (entries, next) = listed.value?;
+            cursor = next;
+            // every slice counts, even one with nobody but us in it
+            budget -= 1;
+            if entries.iter().all(|x| is_same_entry(&x.skey, &player1.skey)) {
+                continue;
+            }
+            if let Some(value) = match_candidates(store, table_name, &player1, p1_created_at, entries, window, policy, &mut retries).await {
+                return Ok(Retried { value, retries });
+            }
+        }
+    }
+    Ok(Retried { value: MatchmakingResult::FakeSimulate(None), retries })
+}
+
+/// the match transaction failed in a way that does not tell whether it committed, eg. it timed out.
+/// if it did, our entry is gone and our pending battle is there, and we must fight that battle
+/// rather than a ghost. if we cannot tell, the ghost path still only claims an entry that exists
+async fn after_ambiguous_match<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    turn_number: u32,
+    player1: &MatchmakingSkey,
+    e: Error,
+) -> MatchmakingResult {
+    let entry = store.get(table_name, &player1.pkey(turn_number), &player1.format()).await;
+    let pending = get_pending(store, table_name, player1, turn_number).await;
+    match (entry, pending) {
+        (_, Ok(Some(_))) | (Ok(None), _) => MatchmakingResult::CanDrop,
+        _ => MatchmakingResult::FakeSimulate(Some(e)),
+    }
+}
+
+fn is_same_entry(a: &MatchmakingSkey, b: &MatchmakingSkey) -> bool {
+    a.run_id == b.run_id && a.random_component == b.random_component
+}
+
+/// tries `available_opponents` in order of rating distance. returns None if none of them could be matched
+#[allow(clippy::too_many_arguments)]
+async fn match_candidates<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player1: &AsyncMatchmakingRequest,
+    p1_created_at: u64,
+    mut available_opponents: Vec<MatchmakingEntry>,
+    window: RatingWindow,
+    policy: &RetryPolicy,
+    retries: &mut u32,
+) -> Option<MatchmakingResult> {
+    let now = now_secs();
+    // prevent matching against self!
+    available_opponents.retain(|x| !is_same_entry(&x.skey, &player1.skey));
+
+    let AsyncMatchmakingRequest { turn_number, skey, rating } = player1;
+    let mut candidates: Vec<(u32, MatchmakingSkey)> = available_opponents.into_iter().filter_map(|op| {
+        let distance = op.rating.abs_diff(*rating);
+        let age = now.saturating_sub(p1_created_at.min(op.created_at));
+        (distance <= window.allowed_distance(age)).then_some((distance, op.skey))
+    }).collect();
+    // stable sort: opponents with an equal distance keep their (random) listing order
+    candidates.sort_by_key(|(distance, _)| *distance);
+
+    for (distance, op) in candidates {
+        let res = attempt_match_with_retry(store, table_name, *turn_number, skey.clone(), op, policy).await;
+        *retries += res.retries;
+        let value = match res.value {
+            MatchResult::P2ConditionError => continue,
+            MatchResult::P1ConditionError => MatchmakingResult::CanDrop,
+            // retries are exhausted by now
+            MatchResult::UnrecoverableError(e) if e.is_ambiguous() => after_ambiguous_match(store, table_name, *turn_number, skey, e).await,
+            MatchResult::UnrecoverableError(e) => MatchmakingResult::FakeSimulate(Some(e)),
+            MatchResult::Matched(_, matchmaking_skey) => MatchmakingResult::Matched(matchmaking_skey, distance),
+        };
+        return Some(value);
+    }
+    None
 }
 
-// end turn => submit matchmaking item: PKEY:turn_X, SKEY:{some_id}, idempotency: {random}
+// end turn => submit matchmaking item: PKEY:turn_X, SKEY:{some_id}, together with PKEY:run_X, SKEY:end_turn_X, idempotency: {key}
 // async matchmaking => query all turn_X
 // pick best partner
 // delete matchmaking items for both players:
 // transaction pt1: Delete PKEY:turn_x, SKEY:{some_id}, condition: PKEY exists
 // transaction pt2: Delete PKEY:turn_x, SKEY:{...}, condition: PKEY exists (prevent deletion returning success if this item already doesnt exist)
-// if successful: queue simulation task for {some_id} and {...} which will update those items respectively, but without transaction
+// transaction pt3: Put PKEY:pending_turn_x, SKEY:{some_id} and SKEY:{...}, the pairing and seed of the battle
+// if successful: simulate the pending battle and update both runs, but without transaction. retries simulate it again
 // if error due to condition failure on pt2: try again with a different user
 // if error due to condition failure on pt1: it was already assigned by a different invocation, therefore mission accomplished, no need to enqueue anything
 // if error otherwise: log the error, enqueue a simulation against a fake opponent, log a metric that the user played against a fake player. we want to track this and minimize it
@@ -228,45 +749,37 @@ pub async fn attempt_matchmaking<'a, Fut>(
 mod test {
     use super::*;
 
-    const TC_TABLE: &'static str = "mygametable2025";
+    pub(crate) const TC_TABLE: &str = "mygametable2025";
+
+    /// writes an active run that is currently on `turn_number`. the run is owned by a player with the same id
+    pub async fn ensure_run(store: &MemoryStore, run_id: &str, turn_number: u32) {
+        let mut run = Run::new(run_id.to_string(), run_id.to_string());
+        run.turn_number = turn_number;
+        store.put(TC_TABLE, run.to_item()).await.expect("failed to write run");
+    }
 
     /// a test version of `end_turn`.
     /// the test version uses a deterministic value for the random_component
     pub async fn end_turn_test(
-        ddb_client: &Client,
+        store: &MemoryStore,
         table_name: &str,
         turn_number: u32,
         run_id: String,
-    ) -> Result<MatchmakingSkey, String> {
-        let mut skey = MatchmakingSkey::new(run_id.clone());
+        rating: u32,
+        created_at: u64,
+    ) -> Result<MatchmakingSkey, Error> {
+        let mut skey = MatchmakingSkey::new(run_id.clone(), 0, Pool::public(1));
         skey.random_component = run_id;
-        ddb_client.put_item()
-            .table_name(table_name)
-            .item(PKEY, AttributeValue::S(shared::matchmaking_pkey(turn_number)))
-            .item(SKEY, AttributeValue::S(skey.format()))
-            // this is unlikely to happen as we have a random component, but just in case:
-            .condition_expression(format!("attribute_not_exists({PKEY})"))
-            .send().await.map_err(|e| format!("Failed to end turn: {:?}", e))?;
+        let item = matchmaking_item(turn_number, &skey, rating, created_at);
+        store.put_if_absent(table_name, item).await?;
         Ok(skey)
     }
 
-    macro_rules! tc {
-        ($name:ident; |$c:ident| { $($x:tt)*}) => {
-            #[test]
-            fn $name() {
-                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("aa");
-                rt.block_on(async {
-                    let client = get_client().await;
-                    let $c = &client;
-                    $($x)*
-                });
-            }
-        };
-    }
-
     tc!(match_happy_path_works; |c| {
-        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string()).await.expect("failed to end turn");
-        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string()).await.expect("failed to end turn");
+        ensure_run(c, "a", 1).await;
+        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+        ensure_run(c, "b", 1).await;
+        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
         let res = attempt_match(c, TC_TABLE, 1, player1, player2).await;
         match res {
             MatchResult::Matched(p1, p2) => {
@@ -278,9 +791,10 @@ mod test {
     });
 
     tc!(match_can_report_if_p2_already_matched; |c| {
-        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string()).await.expect("failed to end turn");
+        ensure_run(c, "a", 1).await;
+        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
         // player2 doesnt exist in the table. we should get a player2 condition error if we try to matchmake:
-        let player2 = MatchmakingSkey::new("b".to_string());
+        let player2 = MatchmakingSkey::new("b".to_string(), 0, Pool::public(1));
         let res = attempt_match(c, TC_TABLE, 1, player1, player2).await;
         match res {
             MatchResult::P2ConditionError => {}
@@ -290,8 +804,9 @@ mod test {
 
     tc!(match_can_report_if_p1_already_matched; |c| {
         // player1 doesnt exist in the table. we should get a player1 condition error if we try to matchmake:
-        let player1 = MatchmakingSkey::new("a".to_string());
-        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string()).await.expect("failed to end turn");
+        let player1 = MatchmakingSkey::new("a".to_string(), 0, Pool::public(1));
+        ensure_run(c, "b", 1).await;
+        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
         let res = attempt_match(c, TC_TABLE, 1, player1, player2).await;
         match res {
             MatchResult::P1ConditionError => {}
@@ -301,53 +816,58 @@ mod test {
 
     tc!(match_can_report_unknown_errors; |c| {
         // the table doesnt exist, so we should get an unexpected error
-        let player1 = MatchmakingSkey::new("a".to_string());
-        let player2 = MatchmakingSkey::new("a".to_string());
+        let player1 = MatchmakingSkey::new("a".to_string(), 0, Pool::public(1));
+        let player2 = MatchmakingSkey::new("a".to_string(), 0, Pool::public(1));
         let res = attempt_match(c, "eeeeeeeeefaketable", 1, player1, player2).await;
         match res {
-            MatchResult::UnrecoverableError(e) => {
-                assert!(e.contains("ResourceNotFoundException"), "{:?}", e);
-            }
+            MatchResult::UnrecoverableError(Error::TableNotFound(_)) => {}
             e => panic!("Unexpected result: {:?}", e),
         }
     });
 
     tc!(matchmaking_happy_path; |c| {
-        let player1 = end_turn(c, TC_TABLE, 3, "a".to_string()).await.expect("failed to end turn");
-        let player2 = end_turn(c, TC_TABLE, 3, "b".to_string()).await.expect("failed to end turn");
-        let player1 = AsyncMatchmakingRequest { turn_number: 3, skey: player1 };
-        let res = attempt_matchmaking(c, TC_TABLE, player1, list_matchmaking_entries).await.expect("should succeed");
+        ensure_run(c, "a", 3).await;
+        let player1 = end_turn(c, TC_TABLE, 3, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+        ensure_run(c, "b", 3).await;
+        let player2 = end_turn(c, TC_TABLE, 3, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 3, skey: player1, rating: 100 };
+        let res = attempt_matchmaking(c, TC_TABLE, player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_entries).await.expect("should succeed").value;
         match res {
-            MatchmakingResult::Matched(opponent) => {
+            MatchmakingResult::Matched(opponent, distance) => {
                 assert_eq!(opponent.random_component, player2.random_component);
                 assert_eq!(opponent.run_id, player2.run_id);
+                assert_eq!(distance, 0);
             }
             e => panic!("unexpected matchmakingresult: {:?}", e),
         }
     });
 
     tc!(matchmaking_can_be_dropped_if_p1_already_matched; |c| {
-        let player1 = end_turn(c, TC_TABLE, 4, "a".to_string()).await.expect("failed to end turn");
-        let _ = end_turn(c, TC_TABLE, 4, "b".to_string()).await.expect("failed to end turn");
-        let player1 = AsyncMatchmakingRequest { turn_number: 4, skey: player1 };
+        ensure_run(c, "a", 4).await;
+        let player1 = end_turn(c, TC_TABLE, 4, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+        ensure_run(c, "b", 4).await;
+        let _ = end_turn(c, TC_TABLE, 4, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 4, skey: player1, rating: 100 };
         static mut P1_SKEY: String = String::new();
         unsafe {
             P1_SKEY = player1.skey.format();
         }
-        pub async fn list_matchmaking_cb<'a>(
-            ddb_client: &'a Client,
+        pub async fn list_matchmaking_cb(
+            store: &MemoryStore,
             table_name: &str,
-            turn_number: u32
-        ) -> Result<Vec<MatchmakingSkey>, String> {
-            let out = list_matchmaking_entries(ddb_client, table_name, turn_number).await;
+            pool: Pool,
+            turn_number: u32,
+            shard: u32,
+        ) -> Result<Vec<MatchmakingEntry>, Error> {
+            let out = list_matchmaking_entries(store, table_name, pool, turn_number, shard).await;
             // we will return the full list of opponents, but first we remove
             // the player1's item to imply that player1 has already been matched with someone
             #[allow(static_mut_refs)]
             let p1_skey = unsafe { P1_SKEY.clone() };
-            delete_item(ddb_client, table_name, &shared::matchmaking_pkey(4), p1_skey.as_str()).await.expect("failed to delete item for test case");
+            delete_item(store, table_name, &Pool::public(1).matchmaking_pkey(4, 0), p1_skey.as_str()).await.expect("failed to delete item for test case");
             out
         }
-        let res = attempt_matchmaking(c, TC_TABLE, player1, list_matchmaking_cb).await.expect("should succeed");
+        let res = attempt_matchmaking(c, TC_TABLE, player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_cb).await.expect("should succeed").value;
         match res {
             MatchmakingResult::CanDrop => {}
             e => panic!("unexpected matchmakingresult: {:?}", e),
@@ -357,14 +877,15 @@ mod test {
     tc!(matchmaking_can_fake_simulation_if_no_opponents; |c| {
         // destroy past items first, we want this test to simulate a state where
         // there are no other items except for player1
-        let items = list_matchmaking_entries(c, TC_TABLE, 999).await.expect("failed to list entries for deletion");
+        let items = list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 999, 0).await.expect("failed to list entries for deletion");
         for item in items {
-            delete_item(c, TC_TABLE, &shared::matchmaking_pkey(999), &item.format()).await.expect("failed to delete item");
+            delete_item(c, TC_TABLE, &Pool::public(1).matchmaking_pkey(999, 0), &item.skey.format()).await.expect("failed to delete item");
         }
 
-        let player1 = end_turn(c, TC_TABLE, 999, "a".to_string()).await.expect("failed to end turn");
-        let player1 = AsyncMatchmakingRequest { turn_number: 999, skey: player1 };
-        let res = attempt_matchmaking(c, TC_TABLE, player1, list_matchmaking_entries).await.expect("should succeed");
+        ensure_run(c, "a", 999).await;
+        let player1 = end_turn(c, TC_TABLE, 999, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 999, skey: player1, rating: 100 };
+        let res = attempt_matchmaking(c, TC_TABLE, player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_entries).await.expect("should succeed").value;
         match res {
             MatchmakingResult::FakeSimulate(x) => {
                 // there should be no error since we are here due
@@ -375,19 +896,64 @@ mod test {
         }
     });
 
+    /// commits every transaction, then reports that it timed out
+    struct TimesOutAfterCommit<'a>(&'a MemoryStore);
+
+    impl MatchmakingStore for TimesOutAfterCommit<'_> {
+        async fn put_if_absent(&self, table_name: &str, item: Item) -> Result<(), Error> { self.0.put_if_absent(table_name, item).await }
+        async fn put_all_if_absent(&self, table_name: &str, items: Vec<Item>) -> Result<(), Error> { self.0.put_all_if_absent(table_name, items).await }
+        async fn transact(&self, table_name: &str, writes: Vec<Write>) -> Result<(), TransactError> {
+            self.0.transact(table_name, writes).await?;
+            Err(TransactError::Other(Error::DeadlineExceeded(std::time::Duration::from_secs(1))))
+        }
+        async fn put(&self, table_name: &str, item: Item) -> Result<(), Error> { self.0.put(table_name, item).await }
+        async fn put_if_attr_equals(&self, table_name: &str, item: Item, attr: &str, expected: AttributeValue) -> Result<(), Error> {
+            self.0.put_if_attr_equals(table_name, item, attr, expected).await
+        }
+        async fn get(&self, table_name: &str, pkey: &str, skey: &str) -> Result<Option<Item>, Error> { self.0.get(table_name, pkey, skey).await }
+        async fn get_many(&self, table_name: &str, keys: &[(String, String)]) -> Result<Vec<Item>, Error> { self.0.get_many(table_name, keys).await }
+        async fn query_partition(&self, table_name: &str, pkey: &str) -> Result<Vec<Item>, Error> { self.0.query_partition(table_name, pkey).await }
+        async fn query_page(&self, table_name: &str, pkey: &str, after: Option<&str>, limit: u32) -> Result<Page, Error> {
+            self.0.query_page(table_name, pkey, after, limit).await
+        }
+        async fn count_before(&self, table_name: &str, pkey: &str, skey: &str) -> Result<u64, Error> { self.0.count_before(table_name, pkey, skey).await }
+        async fn delete(&self, table_name: &str, pkey: &str, skey: &str) -> Result<(), Error> { self.0.delete(table_name, pkey, skey).await }
+    }
+
+    tc!(a_match_that_timed_out_after_committing_is_not_fought_against_a_ghost; |c| {
+        ensure_run(c, "b", 17).await;
+        let _ = end_turn(c, TC_TABLE, 17, "b".to_string(), "b", r#"{"units":[]}"#.to_string(), 1).await.expect("failed to end turn");
+        ensure_run(c, "a", 17).await;
+        let player1 = end_turn(c, TC_TABLE, 17, "a".to_string(), "a", r#"{"units":[]}"#.to_string(), 1).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 17, skey: player1, rating: 100 };
+        let store = TimesOutAfterCommit(c);
+        let res = attempt_matchmaking(&store, TC_TABLE, request.clone(), RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_entries)
+            .await.expect("should succeed").value;
+        // the match went through, so the battle it paired us for is still ours to fight
+        assert!(matches!(res, MatchmakingResult::CanDrop), "{:?}", res);
+        let pending = get_pending(c, TC_TABLE, &request.skey, 17).await.expect("lookup failed").expect("pairing missing");
+        assert_eq!(pending.opponent.map(|x| x.run_id).as_deref(), Some("b"));
+        let config = worker::WorkerConfig { shard_count: 1, ..worker::WorkerConfig::default() };
+        let res = worker::run_matchmaking_task(c, TC_TABLE, request, config).await.expect("task failed").value;
+        assert!(matches!(res, worker::MatchmakingTaskResult::Resumed { fight: worker::Fight { opponent: worker::Opponent::Run(ref x), .. } } if x.run_id == "b"), "{:?}", res);
+    });
+
     tc!(matchmaking_can_fake_simulation_in_case_of_error; |c| {
-        pub async fn list_matchmaking_cb<'a>(
-            ddb_client: &'a Client,
+        pub async fn list_matchmaking_cb(
+            store: &MemoryStore,
             _table_name: &str,
-            turn_number: u32
-        ) -> Result<Vec<MatchmakingSkey>, String> {
-            let out = list_matchmaking_entries(ddb_client, TC_TABLE, turn_number).await;
-            out
+            pool: Pool,
+            turn_number: u32,
+            shard: u32,
+        ) -> Result<Vec<MatchmakingEntry>, Error> {
+            list_matchmaking_entries(store, TC_TABLE, pool, turn_number, shard).await
         }
-        let _ = end_turn(c, TC_TABLE, 6, "b".to_string()).await.expect("failed to end turn");
-        let player1 = end_turn(c, TC_TABLE, 6, "a".to_string()).await.expect("failed to end turn");
-        let player1 = AsyncMatchmakingRequest { turn_number: 6, skey: player1 };
-        let res = attempt_matchmaking(c, "fake-table-that-doesnt-exist", player1, list_matchmaking_cb).await.expect("should succeed");
+        ensure_run(c, "b", 6).await;
+        let _ = end_turn(c, TC_TABLE, 6, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
+        ensure_run(c, "a", 6).await;
+        let player1 = end_turn(c, TC_TABLE, 6, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 6, skey: player1, rating: 100 };
+        let res = attempt_matchmaking(c, "fake-table-that-doesnt-exist", player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_cb).await.expect("should succeed").value;
         match res {
             MatchmakingResult::FakeSimulate(x) => {
                 // there should be an error since we had an unexpected error when
@@ -401,41 +967,43 @@ mod test {
     tc!(matchmaking_attempts_opponents_in_order; |c| {
         // destroy past items first, we want this test to simulate a state where
         // there are no other items except for player1
-        let items = list_matchmaking_entries(c, TC_TABLE, 7).await.expect("failed to list entries for deletion");
+        let items = list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 7, 0).await.expect("failed to list entries for deletion");
         for item in items {
-            delete_item(c, TC_TABLE, &shared::matchmaking_pkey(7), &item.format()).await.expect("failed to delete item");
+            delete_item(c, TC_TABLE, &Pool::public(1).matchmaking_pkey(7, 0), &item.skey.format()).await.expect("failed to delete item");
         }
 
         static mut P2_SKEY: String = String::new();
         static mut P3_SKEY: String = String::new();
         static mut P4_SKEY: String = String::new();
-        pub async fn list_matchmaking_cb<'a>(
-            ddb_client: &'a Client,
+        pub async fn list_matchmaking_cb(
+            store: &MemoryStore,
             table_name: &str,
-            turn_number: u32
-        ) -> Result<Vec<MatchmakingSkey>, String> {
-            let out = list_matchmaking_entries(ddb_client, table_name, turn_number).await;
+            pool: Pool,
+            turn_number: u32,
+            shard: u32,
+        ) -> Result<Vec<MatchmakingEntry>, Error> {
+            let out = list_matchmaking_entries(store, table_name, pool, turn_number, shard).await;
             #[allow(static_mut_refs)]
             unsafe {
                 // ensure the results are in order. v[0] should be p1
                 if let Ok(v) = &out {
-                    assert_eq!(v[1].format(), P2_SKEY);
-                    assert_eq!(v[2].format(), P3_SKEY);
-                    assert_eq!(v[3].format(), P4_SKEY);
+                    assert_eq!(v[1].skey.format(), P2_SKEY);
+                    assert_eq!(v[2].skey.format(), P3_SKEY);
+                    assert_eq!(v[3].skey.format(), P4_SKEY);
 
-                    for i in 1..=2 {
+                    for entry in &v[1..=2] {
                         // delete entry for P2, P3, such that we match only with P4
-                        let _ = delete_item(ddb_client, table_name, &shared::matchmaking_pkey(7), &v[i].format()).await;
+                        let _ = delete_item(store, table_name, &Pool::public(1).matchmaking_pkey(7, 0), &entry.skey.format()).await;
                     }
                 }
             }
             out
         }
-        let player4 = end_turn_test(c, TC_TABLE, 7, "d".to_string()).await.expect("failed to end turn");
-        let player3 = end_turn_test(c, TC_TABLE, 7, "c".to_string()).await.expect("failed to end turn");
-        let player2 = end_turn_test(c, TC_TABLE, 7, "b".to_string()).await.expect("failed to end turn");
-        let player1 = end_turn_test(c, TC_TABLE, 7, "a".to_string()).await.expect("failed to end turn");
-        let player1 = AsyncMatchmakingRequest { turn_number: 7, skey: player1 };
+        let player4 = end_turn_test(c, TC_TABLE, 7, "d".to_string(), 100, now_secs()).await.expect("failed to end turn");
+        let player3 = end_turn_test(c, TC_TABLE, 7, "c".to_string(), 100, now_secs()).await.expect("failed to end turn");
+        let player2 = end_turn_test(c, TC_TABLE, 7, "b".to_string(), 100, now_secs()).await.expect("failed to end turn");
+        let player1 = end_turn_test(c, TC_TABLE, 7, "a".to_string(), 100, now_secs()).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 7, skey: player1, rating: 100 };
 
         unsafe {
             P2_SKEY = player2.format();
@@ -443,9 +1011,9 @@ mod test {
             P4_SKEY = player4.format();
         }
 
-        let res = attempt_matchmaking(c, TC_TABLE, player1, list_matchmaking_cb).await.expect("should succeed");
+        let res = attempt_matchmaking(c, TC_TABLE, player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_cb).await.expect("should succeed").value;
         match res {
-            MatchmakingResult::Matched(x) => {
+            MatchmakingResult::Matched(x, _) => {
                 // we should match with player 4 (d)
                 // because player2 and player3 were matched between the time we made the query
                 // and the time we attempted to match them
@@ -454,4 +1022,192 @@ mod test {
             e => panic!("unexpected matchmakingresult: {:?}", e),
         }
     });
+
+    tc!(matchmaking_prefers_closest_rating; |c| {
+        let now = now_secs();
+        let _ = end_turn_test(c, TC_TABLE, 8, "b".to_string(), 1040, now).await.expect("failed to end turn");
+        let _ = end_turn_test(c, TC_TABLE, 8, "c".to_string(), 1005, now).await.expect("failed to end turn");
+        let _ = end_turn_test(c, TC_TABLE, 8, "d".to_string(), 980, now).await.expect("failed to end turn");
+        let player1 = end_turn_test(c, TC_TABLE, 8, "a".to_string(), 1000, now).await.expect("failed to end turn");
+        let player1 = AsyncMatchmakingRequest { turn_number: 8, skey: player1, rating: 1000 };
+        let res = attempt_matchmaking(c, TC_TABLE, player1, RatingWindow::default(), &RetryPolicy::default(), 1, list_matchmaking_entries).await.expect("should succeed").value;
+        match res {
+            MatchmakingResult::Matched(x, distance) => {
+                assert_eq!(x.run_id, "c");
+                assert_eq!(distance, 5);
+            }
+            e => panic!("unexpected matchmakingresult: {:?}", e),
+        }
+    });
+
+    tc!(matchmaking_window_widens_with_age; |c| {
+        let window = RatingWindow { base: 50, growth_per_sec: 10, max: 500 };
+        let now = now_secs();
+        let far = end_turn_test(c, TC_TABLE, 9, "b".to_string(), 1400, now).await.expect("failed to end turn");
+        let player1 = end_turn_test(c, TC_TABLE, 9, "a".to_string(), 1000, now).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 9, skey: player1.clone(), rating: 1000 };
+        let res = attempt_matchmaking(c, TC_TABLE, request, window, &RetryPolicy::default(), 1, list_matchmaking_entries).await.expect("should succeed").value;
+        match res {
+            // both entries are fresh, so a distance of 400 is outside the window
+            MatchmakingResult::FakeSimulate(None) => {}
+            e => panic!("unexpected matchmakingresult: {:?}", e),
+        }
+
+        // the opponent has now been waiting for 60 seconds, widening the window to 650, capped at 500
+        delete_item(c, TC_TABLE, &Pool::public(1).matchmaking_pkey(9, 0), &far.format()).await.expect("failed to delete item");
+        let _ = end_turn_test(c, TC_TABLE, 9, "b".to_string(), 1400, now - 60).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 9, skey: player1, rating: 1000 };
+        let res = attempt_matchmaking(c, TC_TABLE, request, window, &RetryPolicy::default(), 1, list_matchmaking_entries).await.expect("should succeed").value;
+        match res {
+            MatchmakingResult::Matched(x, distance) => {
+                assert_eq!(x.run_id, "b");
+                assert_eq!(distance, 400);
+            }
+            e => panic!("unexpected matchmakingresult: {:?}", e),
+        }
+    });
+
+    tc!(end_turn_archives_ghost; |c| {
+        ensure_run(c, "a", 10).await;
+        let _ = end_turn(c, TC_TABLE, 10, "a".to_string(), "a", "team_a".to_string(), 1).await.expect("failed to end turn");
+        let ghost = sample_ghost(c, TC_TABLE, &Pool::public(1), 10, "b", 0).await.expect("failed to sample").expect("should find a ghost");
+        assert_eq!(ghost.run_id, "a");
+        assert_eq!(ghost.team, "team_a");
+    });
+
+    tc!(end_turn_validates_turn_number; |c| {
+        let res = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", String::new(), 1).await;
+        assert!(matches!(res, Err(Error::RunNotFound(_))), "run does not exist: {:?}", res);
+        ensure_run(c, "a", 2).await;
+        let res = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", String::new(), 1).await;
+        assert!(matches!(res, Err(Error::TurnMismatch { current: 2, requested: 1, .. })), "run is on turn 2: {:?}", res);
+        let res = end_turn(c, TC_TABLE, 2, "a".to_string(), "b", String::new(), 1).await;
+        assert!(matches!(res, Err(Error::NotRunOwner { .. })), "run belongs to a: {:?}", res);
+        let _ = end_turn(c, TC_TABLE, 2, "a".to_string(), "a", String::new(), 1).await.expect("failed to end turn");
+    });
+
+    tc!(end_turn_replays_return_the_original_entry; |c| {
+        ensure_run(c, "a", 1).await;
+        let submit = async |team: &str, key: Option<&str>| {
+            end_turn_with_retry(c, TC_TABLE, 1, "a".to_string(), "a", team.to_string(), None, 4, key.map(|x| x.to_string()), &RetryPolicy::none()).await.value
+        };
+        let first = submit("{}", Some("k1")).await.expect("failed to end turn");
+        assert!(!first.replayed);
+        let replay = submit("{}", Some("k1")).await.expect("failed to replay");
+        assert!(replay.replayed);
+        assert_eq!(replay.skey.format(), first.skey.format());
+        let res = submit(r#"{"units":[]}"#, Some("k2")).await;
+        assert!(matches!(res, Err(Error::TurnAlreadyEnded { turn_number: 1, .. })), "{:?}", res);
+        let res = submit(r#"{"units":[]}"#, None).await;
+        assert!(matches!(res, Err(Error::TurnAlreadyEnded { .. })), "{:?}", res);
+        // only the first submission made it into the pool, with its team
+        let mut entries = Vec::new();
+        for shard in 0..4 {
+            entries.extend(list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 1, shard).await.expect("failed to list"));
+        }
+        assert_eq!(entries.len(), 1);
+        assert_eq!(run::load_snapshot(c, TC_TABLE, "a", 1).await.expect("missing snapshot"), "{}");
+
+        // without a key, any second submission is a replay
+        ensure_run(c, "b", 1).await;
+        let first = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", String::new(), 1).await.expect("failed to end turn");
+        let replay = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", String::new(), 1).await.expect("failed to replay");
+        assert_eq!(replay.format(), first.format());
+    });
+
+    tc!(slices_cover_the_partition_once; |c| {
+        let now = now_secs();
+        for run_id in ["a", "b", "c", "d", "e"] {
+            let _ = end_turn_test(c, TC_TABLE, 11, run_id.to_string(), 100, now).await.expect("failed to end turn");
+        }
+        // start in the middle so the listing has to wrap around
+        let mut cursor = Some(SliceCursor { origin: "c_0_c".to_string(), after: Some("c_0_c".to_string()), wrapped: false });
+        let mut seen = Vec::new();
+        while let Some(current) = cursor {
+            let (entries, next) = list_matchmaking_slice(c, TC_TABLE, &Pool::public(1), 11, 0, current, 2).await.expect("failed to list");
+            assert!(entries.len() <= 2);
+            seen.extend(entries.into_iter().map(|x| x.skey.run_id));
+            cursor = next;
+        }
+        assert_eq!(seen, vec!["d", "e", "a", "b", "c"]);
+    });
+
+    tc!(sliced_matchmaking_continues_past_taken_opponents; |c| {
+        let now = now_secs();
+        for run_id in ["b", "c", "d", "e"] {
+            let _ = end_turn_test(c, TC_TABLE, 12, run_id.to_string(), 100, now).await.expect("failed to end turn");
+        }
+        let player1 = end_turn_test(c, TC_TABLE, 12, "a".to_string(), 100, now).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 12, skey: player1.clone(), rating: 100 };
+        // one entry per slice, and enough continuations to reach every opponent
+        let slices = SliceConfig { limit: 1, max_continuations: 5 };
+        let res = attempt_matchmaking_sliced(c, TC_TABLE, request.clone(), RatingWindow::default(), &RetryPolicy::default(), 1, slices)
+            .await.expect("should succeed").value;
+        assert!(matches!(res, MatchmakingResult::Matched(_, 0)), "{:?}", res);
+
+        // already matched, so the request can be dropped without listing anything
+        let res = attempt_matchmaking_sliced(c, TC_TABLE, request, RatingWindow::default(), &RetryPolicy::default(), 1, slices)
+            .await.expect("should succeed").value;
+        assert!(matches!(res, MatchmakingResult::CanDrop), "{:?}", res);
+
+        // alone in the pool, slices of nothing but ourselves still run out, even with a limit of 0
+        let lone = end_turn_test(c, TC_TABLE, 15, "f".to_string(), 100, now).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 15, skey: lone, rating: 100 };
+        let slices = SliceConfig { limit: 0, max_continuations: 2 };
+        let res = attempt_matchmaking_sliced(c, TC_TABLE, request, RatingWindow::default(), &RetryPolicy::default(), 4, slices)
+            .await.expect("should succeed").value;
+        assert!(matches!(res, MatchmakingResult::FakeSimulate(None)), "{:?}", res);
+    });
+
+    #[test]
+    fn shard_search_order_visits_every_shard_once() {
+        assert_eq!(shard_search_order(0, 1), vec![0]);
+        assert_eq!(shard_search_order(1, 4), vec![1, 2, 0, 3]);
+        assert_eq!(shard_search_order(0, 5), vec![0, 1, 4, 2, 3]);
+        // the shard count was lowered since the entry was written
+        assert_eq!(shard_search_order(5, 2), vec![5, 1, 0]);
+    }
+
+    #[test]
+    fn matchmaking_skey_round_trips() {
+        let skey = MatchmakingSkey::new("abc".to_string(), 3, Pool::public(2));
+        let parsed = MatchmakingSkey::from_str(&skey.format()).expect("failed to parse");
+        assert_eq!((parsed.random_component, parsed.shard, parsed.pool, parsed.run_id), (skey.random_component, 3, Pool::public(2), "abc".to_string()));
+        let lobby = Pool { content_version: 1, lobby: Some("K7QX2M".to_string()) };
+        let parsed = MatchmakingSkey::from_str(&MatchmakingSkey::new("abc".to_string(), 0, lobby.clone()).format()).expect("failed to parse");
+        assert_eq!((parsed.pool, parsed.run_id.as_str()), (lobby, "abc"));
+        // keys without a content version are rejected
+        assert!(matches!(MatchmakingSkey::from_str("xyz_1_abc"), Err(Error::MalformedSortKey(_))));
+        assert!(matches!(MatchmakingSkey::from_str("xyz_1_v_abc"), Err(Error::MalformedSortKey(_))));
+        assert!(matches!(MatchmakingSkey::from_str("xyz_abc"), Err(Error::MalformedSortKey(_))));
+        assert!(matches!(MatchmakingSkey::from_str("xyz_x_abc"), Err(Error::MalformedSortKey(_))));
+    }
+
+    tc!(matchmaking_finds_opponents_in_other_shards; |c| {
+        let now = now_secs();
+        let write = async |run_id: &str, shard: u32| {
+            let skey = MatchmakingSkey::new(run_id.to_string(), shard, Pool::public(1));
+            c.put_if_absent(TC_TABLE, matchmaking_item(13, &skey, 100, now)).await.expect("failed to write entry");
+            skey
+        };
+        let player1 = write("a", 0).await;
+        let _ = write("b", 2).await;
+        let _ = write("c", 1).await;
+        let request = AsyncMatchmakingRequest { turn_number: 13, skey: player1.clone(), rating: 100 };
+        let res = attempt_matchmaking(c, TC_TABLE, request, RatingWindow::default(), &RetryPolicy::default(), 3, list_matchmaking_entries)
+            .await.expect("should succeed").value;
+        match res {
+            // shard 1 is searched before shard 2
+            MatchmakingResult::Matched(x, _) => assert_eq!((x.run_id.as_str(), x.shard), ("c", 1)),
+            e => panic!("unexpected matchmakingresult: {:?}", e),
+        }
+        assert!(list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 13, 0).await.expect("failed to list").is_empty());
+
+        let player1 = write("d", 0).await;
+        let request = AsyncMatchmakingRequest { turn_number: 13, skey: player1, rating: 100 };
+        let res = attempt_matchmaking_sliced(c, TC_TABLE, request, RatingWindow::default(), &RetryPolicy::default(), 3, SliceConfig::default())
+            .await.expect("should succeed").value;
+        assert!(matches!(res, MatchmakingResult::Matched(ref x, _) if x.run_id == "b"), "{:?}", res);
+        assert!(list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 13, 2).await.expect("failed to list").is_empty());
+    });
 }
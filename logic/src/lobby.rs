@@ -0,0 +1,219 @@
+//! private lobbies. friends join with a short code, and the runs they play in the lobby only meet
+//! each other, in matchmaking and as ghosts. a lobby can be joined until it expires, after which
+//! dynamodb ttl deletes it along with its memberships.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{api::LobbyStanding, PKEY, SKEY};
+
+use crate::{get_number_attr, get_random_string, get_string_attr, run, Error, Item, MatchmakingStore, Run};
+
+pub const CODE_LEN: usize = 6;
+/// no 0, O, 1 or I, so codes can be read out to friends
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// how many codes are tried when creating a lobby, in case a code is already taken
+const CODE_ATTEMPTS: u32 = 3;
+
+pub const CODE_ATTR: &str = "code";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lobby {
+    pub code: String,
+    /// the player that created the lobby
+    pub owner: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+/// the run a player plays in a lobby
+#[derive(Debug, Clone)]
+pub struct Joined {
+    pub run: Run,
+    /// the player had already joined, `run` is the run they joined with back then
+    pub rejoined: bool,
+}
+
+impl Lobby {
+    fn to_item(&self) -> Item {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(shared::lobby_pkey(&self.code)));
+        item.insert(SKEY.to_string(), AttributeValue::S(shared::LOBBY_SKEY.to_string()));
+        item.insert(CODE_ATTR.to_string(), AttributeValue::S(self.code.clone()));
+        item.insert(run::OWNER_ATTR.to_string(), AttributeValue::S(self.owner.clone()));
+        item.insert(crate::CREATED_AT_ATTR.to_string(), AttributeValue::N(self.created_at.to_string()));
+        item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N(self.expires_at.to_string()));
+        item
+    }
+
+    fn from_item(item: &Item) -> Result<Self, Error> {
+        Ok(Self {
+            code: get_string_attr(item, CODE_ATTR)?,
+            owner: get_string_attr(item, run::OWNER_ATTR)?,
+            created_at: get_number_attr(item, crate::CREATED_AT_ATTR)?,
+            expires_at: get_number_attr(item, shared::EXPIRES_AT_ATTR)?,
+        })
+    }
+
+    fn member_item(&self, player_id: &str, run_id: &str) -> Item {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(shared::lobby_pkey(&self.code)));
+        item.insert(SKEY.to_string(), AttributeValue::S(shared::lobby_member_skey(player_id)));
+        item.insert(run::OWNER_ATTR.to_string(), AttributeValue::S(player_id.to_string()));
+        item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(run_id.to_string()));
+        item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N(self.expires_at.to_string()));
+        item
+    }
+}
+
+/// codes are not case sensitive
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_ascii_uppercase()
+}
+
+fn random_code() -> String {
+    (0..CODE_LEN).map(|_| CODE_ALPHABET[fastrand::usize(..CODE_ALPHABET.len())] as char).collect()
+}
+
+/// creates a lobby owned by `owner` that can be joined for `ttl_secs` after `now`
+pub async fn create_lobby<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    owner: &str,
+    ttl_secs: u64,
+    now: u64,
+) -> Result<Lobby, Error> {
+    let mut attempt = 1;
+    loop {
+        let lobby = Lobby { code: random_code(), owner: owner.to_string(), created_at: now, expires_at: now + ttl_secs };
+        match store.put_if_absent(table_name, lobby.to_item()).await {
+            Ok(()) => return Ok(lobby),
+            // the code is taken
+            Err(Error::ConditionFailed(_)) if attempt < CODE_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// the lobby with join code `code`, unless it expired before `now`
+pub async fn get_lobby<S: MatchmakingStore>(store: &S, table_name: &str, code: &str, now: u64) -> Result<Lobby, Error> {
+    let code = normalize_code(code);
+    let item = store.get(table_name, &shared::lobby_pkey(&code), shared::LOBBY_SKEY).await?
+        .ok_or_else(|| Error::LobbyNotFound(code.clone()))?;
+    let lobby = Lobby::from_item(&item)?;
+    // ttl deletes expired items some time after they expired, not right away
+    if lobby.expires_at <= now {
+        return Err(Error::LobbyExpired(code));
+    }
+    Ok(lobby)
+}
+
+async fn find_member_run<S: MatchmakingStore>(store: &S, table_name: &str, lobby: &Lobby, player_id: &str) -> Result<Option<Joined>, Error> {
+    let Some(member) = store.get(table_name, &shared::lobby_pkey(&lobby.code), &shared::lobby_member_skey(player_id)).await? else {
+        return Ok(None);
+    };
+    let run = run::load_run(store, table_name, &get_string_attr(&member, crate::RUN_ID_ATTR)?).await?;
+    Ok(Some(Joined { run, rejoined: true }))
+}
+
+/// joins `player_id` to the lobby with a new run that only plays within it.
+/// every player gets one run per lobby, joining again returns the run they already have.
+pub async fn join_lobby<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    code: &str,
+    player_id: &str,
+    now: u64,
+) -> Result<Joined, Error> {
+    let lobby = get_lobby(store, table_name, code, now).await?;
+    if let Some(joined) = find_member_run(store, table_name, &lobby, player_id).await? {
+        return Ok(joined);
+    }
+    let run = Run { lobby: Some(lobby.code.clone()), ..Run::new(get_random_string(16), player_id.to_string()) };
+    // the membership and the run are written together, so a player cannot end up with two runs
+    match store.put_all_if_absent(table_name, vec![run.to_item(), lobby.member_item(player_id, &run.run_id)]).await {
+        Ok(()) => Ok(Joined { run, rejoined: false }),
+        // lost a race against another join of the same player
+        Err(Error::ConditionFailed(e)) => find_member_run(store, table_name, &lobby, player_id).await?.ok_or(Error::ConditionFailed(e)),
+        Err(e) => Err(e),
+    }
+}
+
+/// the runs of every member: most wins first, then most lives left
+pub async fn lobby_standings<S: MatchmakingStore>(store: &S, table_name: &str, lobby: &Lobby) -> Result<Vec<LobbyStanding>, Error> {
+    let items = store.query_partition(table_name, &shared::lobby_pkey(&lobby.code)).await?;
+    let mut keys = Vec::new();
+    for item in items {
+        let is_member = item.get(SKEY).and_then(|x| x.as_s().ok()).is_some_and(|x| x.starts_with("member_"));
+        if is_member {
+            let run_id = get_string_attr(&item, crate::RUN_ID_ATTR)?;
+            keys.push((shared::run_pkey(&run_id), shared::RUN_SKEY.to_string()));
+        }
+    }
+    // one batch read for every member, instead of a read per member
+    let mut runs = store.get_many(table_name, &keys).await?.iter().map(Run::from_item).collect::<Result<Vec<_>, _>>()?;
+    // batches come back in any order. ties are ranked by run id, so every read ranks them the same
+    runs.sort_by(|a, b| b.wins.cmp(&a.wins).then(b.lives.cmp(&a.lives)).then_with(|| a.run_id.cmp(&b.run_id)));
+    Ok(runs.into_iter().enumerate().map(|(i, run)| LobbyStanding {
+        rank: i as u64 + 1,
+        player_id: run.owner,
+        run_id: run.run_id,
+        turn_number: run.turn_number,
+        wins: run.wins,
+        lives: run.lives,
+        status: run.status.into(),
+    }).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        end_turn, record_battle_result, test::TC_TABLE, worker::{run_matchmaking_task, MatchmakingTaskResult, Opponent, WorkerConfig},
+        AsyncMatchmakingRequest, BattleOutcome,
+    };
+
+    const TTL: u64 = 60;
+
+    tc!(friends_join_with_the_code_and_are_ranked; |c| {
+        let now = crate::now_secs();
+        let lobby = create_lobby(c, TC_TABLE, "owner", TTL, now).await.expect("failed to create lobby");
+        assert_eq!(lobby.code.len(), CODE_LEN);
+        let a = join_lobby(c, TC_TABLE, &lobby.code.to_lowercase(), "a", now).await.expect("failed to join");
+        let b = join_lobby(c, TC_TABLE, &lobby.code, "b", now).await.expect("failed to join");
+        assert!(!a.rejoined);
+        assert_eq!(a.run.lobby.as_deref(), Some(lobby.code.as_str()));
+        let again = join_lobby(c, TC_TABLE, &lobby.code, "a", now).await.expect("failed to join");
+        assert!(again.rejoined);
+        assert_eq!(again.run.run_id, a.run.run_id);
+
+        record_battle_result(c, TC_TABLE, &b.run.run_id, 1, BattleOutcome::Win).await.expect("failed to record");
+        let standings = lobby_standings(c, TC_TABLE, &lobby).await.expect("failed to list standings");
+        let ranked: Vec<_> = standings.iter().map(|x| (x.rank, x.player_id.as_str(), x.wins)).collect();
+        assert_eq!(ranked, vec![(1, "b", 1), (2, "a", 0)]);
+
+        let res = join_lobby(c, TC_TABLE, &lobby.code, "c", now + TTL).await;
+        assert!(matches!(res, Err(Error::LobbyExpired(_))), "{:?}", res);
+        assert!(matches!(get_lobby(c, TC_TABLE, "NOPE42", now).await, Err(Error::LobbyNotFound(_))));
+    });
+
+    tc!(lobby_runs_only_meet_each_other; |c| {
+        let now = crate::now_secs();
+        let lobby = create_lobby(c, TC_TABLE, "owner", TTL, now).await.expect("failed to create lobby");
+        let a = join_lobby(c, TC_TABLE, &lobby.code, "a", now).await.expect("failed to join").run.run_id;
+        let b = join_lobby(c, TC_TABLE, &lobby.code, "b", now).await.expect("failed to join").run.run_id;
+        crate::test::ensure_run(c, "public", 1).await;
+        let team = r#"{"units":[]}"#.to_string();
+        let public = end_turn(c, TC_TABLE, 1, "public".to_string(), "public", team.clone(), 4).await.expect("failed to end turn");
+        let _ = end_turn(c, TC_TABLE, 1, a.clone(), "a", team.clone(), 4).await.expect("failed to end turn");
+        let skey = end_turn(c, TC_TABLE, 1, b, "b", team, 4).await.expect("failed to end turn");
+        assert_eq!((skey.shard, skey.pool.lobby.as_deref()), (0, Some(lobby.code.as_str())));
+
+        let config = WorkerConfig { shard_count: 4, ..WorkerConfig::default() };
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, config).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Matched { fight: crate::worker::Fight { opponent: Opponent::Run(ref x), .. }, .. } if x.run_id == a));
+        // the public run finds neither the lobby runs nor their ghosts
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: public, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, config).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Ghost { fight: crate::worker::Fight { opponent: Opponent::Empty, .. }, .. }), "{:?}", res.value);
+    });
+}
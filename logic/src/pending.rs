@@ -0,0 +1,130 @@
+//! battles that were paired but not fought yet. the pairing is written in the same transaction that
+//! takes the entries out of the matchmaking pool, so a task that fails after claiming an entry leaves
+//! enough behind for its retry, or the reaper, to fight the very same battle.
+
+use std::str::FromStr;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{battle::Side, PKEY, SKEY};
+
+use crate::{
+    battle_result::{parse_side, side_str, SEED_ATTR, SIDE_ATTR},
+    get_number_attr, get_string_attr, get_u32_attr, Error, Item, MatchmakingSkey, MatchmakingStore, Pool, Write,
+};
+
+/// attribute on pending battles holding the matchmaking sort key of the run fought against
+pub const OPPONENT_SKEY_ATTR: &str = "opponent_skey";
+
+#[derive(Debug, Clone)]
+pub struct PendingBattle {
+    /// the entry that was claimed
+    pub player: MatchmakingSkey,
+    pub turn_number: u32,
+    /// the entry of the run to fight. None fights a ghost
+    pub opponent: Option<MatchmakingSkey>,
+    /// which side `player` fights on
+    pub side: Side,
+    pub seed: u64,
+    pub created_at: u64,
+}
+
+impl PendingBattle {
+    /// both sides of a match between two entries. they fight the same battle
+    pub fn matched(turn_number: u32, player1: &MatchmakingSkey, player2: &MatchmakingSkey, seed: u64, now: u64) -> [Self; 2] {
+        let side = |player: &MatchmakingSkey, opponent: &MatchmakingSkey, side| Self {
+            player: player.clone(),
+            turn_number,
+            opponent: Some(opponent.clone()),
+            side,
+            seed,
+            created_at: now,
+        };
+        [side(player1, player2, Side::Player1), side(player2, player1, Side::Player2)]
+    }
+
+    pub fn ghost(turn_number: u32, player: &MatchmakingSkey, seed: u64, now: u64) -> Self {
+        Self { player: player.clone(), turn_number, opponent: None, side: Side::Player1, seed, created_at: now }
+    }
+
+    pub fn to_item(&self) -> Item {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(self.player.pending_pkey(self.turn_number)));
+        item.insert(SKEY.to_string(), AttributeValue::S(self.player.format()));
+        item.insert(crate::run::TURN_ATTR.to_string(), AttributeValue::N(self.turn_number.to_string()));
+        if let Some(opponent) = &self.opponent {
+            item.insert(OPPONENT_SKEY_ATTR.to_string(), AttributeValue::S(opponent.format()));
+        }
+        item.insert(SIDE_ATTR.to_string(), AttributeValue::S(side_str(self.side).to_string()));
+        item.insert(SEED_ATTR.to_string(), AttributeValue::N(self.seed.to_string()));
+        item.insert(crate::CREATED_AT_ATTR.to_string(), AttributeValue::N(self.created_at.to_string()));
+        // like the entry it replaced, so a battle nobody can fight anymore does not stay around
+        let expires_at = self.created_at + crate::MATCHMAKING_TTL_SECS;
+        item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N(expires_at.to_string()));
+        item
+    }
+
+    pub fn from_item(item: &Item) -> Result<Self, Error> {
+        let opponent = match item.get(OPPONENT_SKEY_ATTR) {
+            Some(_) => Some(MatchmakingSkey::from_str(&get_string_attr(item, OPPONENT_SKEY_ATTR)?)?),
+            None => None,
+        };
+        Ok(Self {
+            player: MatchmakingSkey::from_str(&get_string_attr(item, SKEY)?)?,
+            turn_number: get_u32_attr(item, crate::run::TURN_ATTR)?,
+            opponent,
+            side: parse_side(&get_string_attr(item, SIDE_ATTR)?)?,
+            seed: get_number_attr(item, SEED_ATTR)?,
+            created_at: get_number_attr(item, crate::CREATED_AT_ATTR)?,
+        })
+    }
+}
+
+/// the battle `player`'s entry was claimed for, if it was not fought to the end yet
+pub async fn get_pending<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player: &MatchmakingSkey,
+    turn_number: u32,
+) -> Result<Option<PendingBattle>, Error> {
+    let item = store.get(table_name, &player.pending_pkey(turn_number), &player.format()).await?;
+    item.as_ref().map(PendingBattle::from_item).transpose()
+}
+
+/// the battles waiting to be fought in one shard of a turn's pool
+pub async fn list_pending<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pool: &Pool,
+    turn_number: u32,
+    shard: u32,
+) -> Result<Vec<PendingBattle>, Error> {
+    let items = store.query_partition(table_name, &pool.pending_battles_pkey(turn_number, shard)).await?;
+    items.iter().map(PendingBattle::from_item).collect()
+}
+
+/// takes `player`'s entry out of the pool to fight a ghost, together with the pending battle.
+/// fails with `Error::ConditionFailed` if the entry is gone, eg. because it was matched in the meantime
+pub async fn claim_for_ghost<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player: &MatchmakingSkey,
+    turn_number: u32,
+    now: u64,
+) -> Result<PendingBattle, Error> {
+    let pending = PendingBattle::ghost(turn_number, player, fastrand::u64(..), now);
+    let writes = vec![
+        Write::DeleteExisting(player.pkey(turn_number), player.format()),
+        Write::PutIfAbsent(pending.to_item()),
+    ];
+    store.transact(table_name, writes).await?;
+    Ok(pending)
+}
+
+/// forgets a battle once both sides have their result
+pub(crate) async fn delete_pending<S: MatchmakingStore>(store: &S, table_name: &str, pending: &PendingBattle) -> Result<(), Error> {
+    store.delete(table_name, &pending.player.pending_pkey(pending.turn_number), &pending.player.format()).await?;
+    if let Some(opponent) = &pending.opponent {
+        store.delete(table_name, &opponent.pending_pkey(pending.turn_number), &opponent.format()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{PKEY, SKEY};
+
+use crate::{
+    get_number_attr, get_run, get_string_attr, get_u32_attr, list_matchmaking_entries, now_secs,
+    pending::{claim_for_ghost, list_pending},
+    run::{CONTENT_VERSION_ATTR, LOBBY_ATTR, TURN_ATTR},
+    worker::{finish_pending, WorkerConfig},
+    AsyncMatchmakingRequest, Error, Item, MatchmakingEntry, MatchmakingStore, PendingBattle, Pool, RunStatus,
+    MATCHMAKING_TTL_SECS,
+};
+
+/// attribute on pool index items holding the shard of the indexed partition
+pub const SHARD_ATTR: &str = "shard";
+/// an index item is rewritten at most this often, so not every ended turn writes the same item
+const INDEX_REFRESH_SECS: u64 = 60 * 60;
+
+/// when a matchmaking entry counts as stale. entries only stay in the pool this long if the
+/// async matchmaking step for them never ran or failed for good.
+#[derive(Debug, Clone, Copy)]
+pub struct ReaperConfig {
+    /// entries older than this get matchmaking enqueued again
+    pub requeue_after_secs: u64,
+    /// entries older than this are taken out of the pool and fight a ghost right away.
+    /// battles that were paired this long ago but are still pending are fought as well
+    pub resolve_after_secs: u64,
+    /// used to resolve entries
+    pub worker: WorkerConfig,
+}
+
+impl Default for ReaperConfig {
+    fn default() -> Self {
+        Self { requeue_after_secs: 60, resolve_after_secs: 5 * 60, worker: WorkerConfig::default() }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReapReport {
+    /// every matchmaking entry that was looked at
+    pub scanned: u32,
+    /// stale entries that should get another matchmaking attempt. enqueuing them is up to the caller
+    pub requeue: Vec<AsyncMatchmakingRequest>,
+    /// stale entries that fought a ghost
+    pub resolved: u32,
+    /// entries of runs that no longer exist or already moved past the turn
+    pub removed: u32,
+    /// pending battles whose task gave up after pairing them, fought here instead
+    pub resumed: u32,
+    /// entries that could not be handled. they are picked up again by the next run
+    pub failed: u32,
+}
+
+enum Reaped {
+    Fresh,
+    Requeue(AsyncMatchmakingRequest),
+    Resolved,
+    Removed,
+    /// somebody else matched the entry while we were looking at it
+    Gone,
+}
+
+/// one matchmaking partition, and the partition of the battles paired in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedPool {
+    pub pool: Pool,
+    pub turn_number: u32,
+    pub shard: u32,
+}
+
+impl IndexedPool {
+    fn to_item(&self, expires_at: u64) -> Item {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(shared::MATCHMAKING_POOLS_PKEY.to_string()));
+        item.insert(SKEY.to_string(), AttributeValue::S(self.pool.matchmaking_pkey(self.turn_number, self.shard)));
+        item.insert(CONTENT_VERSION_ATTR.to_string(), AttributeValue::N(self.pool.content_version.to_string()));
+        if let Some(lobby) = &self.pool.lobby {
+            item.insert(LOBBY_ATTR.to_string(), AttributeValue::S(lobby.clone()));
+        }
+        item.insert(TURN_ATTR.to_string(), AttributeValue::N(self.turn_number.to_string()));
+        item.insert(SHARD_ATTR.to_string(), AttributeValue::N(self.shard.to_string()));
+        item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N(expires_at.to_string()));
+        item
+    }
+
+    fn from_item(item: &Item) -> Result<Self, Error> {
+        Ok(Self {
+            pool: Pool { content_version: get_u32_attr(item, CONTENT_VERSION_ATTR)?, lobby: get_string_attr(item, LOBBY_ATTR).ok() },
+            turn_number: get_u32_attr(item, TURN_ATTR)?,
+            shard: get_u32_attr(item, SHARD_ATTR)?,
+        })
+    }
+}
+
+/// lists the partition an entry is about to be written to, so the reaper scans it.
+/// the index item is kept until every entry written to the partition so far has expired
+pub(crate) async fn index_pool<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    indexed: &IndexedPool,
+    now: u64,
+) -> Result<(), Error> {
+    let skey = indexed.pool.matchmaking_pkey(indexed.turn_number, indexed.shard);
+    let current = store.get(table_name, shared::MATCHMAKING_POOLS_PKEY, &skey).await?;
+    let expires_at = current.map(|x| get_number_attr(&x, shared::EXPIRES_AT_ATTR)).transpose()?.unwrap_or_default();
+    if expires_at >= now + MATCHMAKING_TTL_SECS {
+        return Ok(());
+    }
+    store.put(table_name, indexed.to_item(now + MATCHMAKING_TTL_SECS + INDEX_REFRESH_SECS)).await
+}
+
+/// every matchmaking partition that may still hold entries or pending battles at `now`.
+/// ttl deletes expired index items some time after they expired, so they are skipped here
+pub async fn list_indexed_pools<S: MatchmakingStore>(store: &S, table_name: &str, now: u64) -> Result<Vec<IndexedPool>, Error> {
+    let items = store.query_partition(table_name, shared::MATCHMAKING_POOLS_PKEY).await?;
+    let mut out = Vec::new();
+    for item in items {
+        if get_number_attr(&item, shared::EXPIRES_AT_ATTR)? > now {
+            out.push(IndexedPool::from_item(&item)?);
+        }
+    }
+    Ok(out)
+}
+
+/// scans every matchmaking partition that had an entry written recently for stale entries and
+/// stale pending battles.
+/// only errors listing the partitions are returned, errors handling single entries are counted in `failed`.
+pub async fn reap_stale_entries<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    config: &ReaperConfig,
+) -> Result<ReapReport, Error> {
+    let mut report = ReapReport::default();
+    let now = now_secs();
+    for IndexedPool { pool, turn_number, shard } in list_indexed_pools(store, table_name, now).await? {
+        for entry in list_matchmaking_entries(store, table_name, pool.clone(), turn_number, shard).await? {
+            report.scanned += 1;
+            match reap_entry(store, table_name, config, turn_number, entry, now).await {
+                Ok(Reaped::Fresh | Reaped::Gone) => {}
+                Ok(Reaped::Requeue(request)) => report.requeue.push(request),
+                Ok(Reaped::Resolved) => report.resolved += 1,
+                Ok(Reaped::Removed) => report.removed += 1,
+                Err(_) => report.failed += 1,
+            }
+        }
+        for pending in list_pending(store, table_name, &pool, turn_number, shard).await? {
+            match resume_pending(store, table_name, config, pending, now).await {
+                Ok(true) => report.resumed += 1,
+                Ok(false) => {}
+                Err(_) => report.failed += 1,
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn reap_entry<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    config: &ReaperConfig,
+    turn_number: u32,
+    entry: MatchmakingEntry,
+    now: u64,
+) -> Result<Reaped, Error> {
+    let age = now.saturating_sub(entry.created_at);
+    if age < config.requeue_after_secs {
+        return Ok(Reaped::Fresh);
+    }
+    let pkey = entry.skey.pkey(turn_number);
+    let run = get_run(store, table_name, &entry.skey.run_id).await?;
+    let waiting = run.is_some_and(|x| x.status == RunStatus::Active && x.turn_number == turn_number);
+    if !waiting {
+        // nobody is waiting on this entry anymore, e.g. the ghost fallback failed to remove it
+        store.delete(table_name, &pkey, &entry.skey.format()).await?;
+        return Ok(Reaped::Removed);
+    }
+    if age < config.resolve_after_secs {
+        let request = AsyncMatchmakingRequest { turn_number, skey: entry.skey, rating: entry.rating };
+        return Ok(Reaped::Requeue(request));
+    }
+    // claim the entry first so a matchmaking invocation cannot match it at the same time. if we fail
+    // after that, the pending battle is left for the next run to fight
+    match claim_for_ghost(store, table_name, &entry.skey, turn_number, now).await {
+        Ok(_) => {}
+        Err(Error::ConditionFailed(_)) => return Ok(Reaped::Gone),
+        Err(e) => return Err(e),
+    }
+    finish_pending(store, table_name, &entry.skey, turn_number, config.worker.max_ghost_turn_distance).await?;
+    Ok(Reaped::Resolved)
+}
+
+/// fights a battle that was paired a while ago but never finished. returns whether it was fought here,
+/// false if it is still recent or the other side of the match already took care of it
+async fn resume_pending<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    config: &ReaperConfig,
+    pending: PendingBattle,
+    now: u64,
+) -> Result<bool, Error> {
+    if now.saturating_sub(pending.created_at) < config.resolve_after_secs {
+        return Ok(false);
+    }
+    let distance = config.worker.max_ghost_turn_distance;
+    Ok(finish_pending(store, table_name, &pending.player, pending.turn_number, distance).await?.is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{matchmaking_item, test::{ensure_run, TC_TABLE}, BattleStatus, MatchmakingSkey, Write};
+
+    tc!(stale_entries_are_requeued_resolved_or_removed; |c| {
+        let now = now_secs();
+        let config = ReaperConfig { worker: WorkerConfig { shard_count: 1, ..WorkerConfig::default() }, ..ReaperConfig::default() };
+        let write = async |run_id: &str, turn_number: u32, age: u64| {
+            ensure_run(c, run_id, turn_number).await;
+            crate::run::save_snapshot(c, TC_TABLE, run_id, turn_number, r#"{"units":[]}"#).await.expect("failed to save snapshot");
+            let skey = MatchmakingSkey::new(run_id.to_string(), 0, Pool::public(1));
+            c.put_if_absent(TC_TABLE, matchmaking_item(turn_number, &skey, 100, now - age)).await.expect("failed to write entry");
+            let indexed = IndexedPool { pool: Pool::public(1), turn_number, shard: 0 };
+            index_pool(c, TC_TABLE, &indexed, now).await.expect("failed to index pool");
+            skey
+        };
+        write("fresh", 1, 0).await;
+        write("slow", 1, config.requeue_after_secs).await;
+        write("stuck", 2, config.resolve_after_secs).await;
+        write("done", 1, config.resolve_after_secs).await;
+        // this run already fought its battle for turn 1
+        ensure_run(c, "done", 2).await;
+        // these two were paired, but the task fighting their battle gave up
+        let left = write("left", 1, 0).await;
+        let right = write("right", 1, 0).await;
+        let pending = PendingBattle::matched(1, &left, &right, 7, now - config.resolve_after_secs);
+        let writes = vec![
+            Write::DeleteExisting(left.pkey(1), left.format()),
+            Write::DeleteExisting(right.pkey(1), right.format()),
+            Write::PutIfAbsent(pending[0].to_item()),
+            Write::PutIfAbsent(pending[1].to_item()),
+        ];
+        c.transact(TC_TABLE, writes).await.expect("failed to pair");
+
+        let report = reap_stale_entries(c, TC_TABLE, &config).await.expect("failed to reap");
+        assert_eq!((report.scanned, report.resolved, report.removed, report.resumed, report.failed), (4, 1, 1, 1, 0));
+        assert_eq!(report.requeue.len(), 1);
+        assert_eq!(report.requeue[0].skey.run_id, "slow");
+        let status = crate::get_battle_result(c, TC_TABLE, "stuck", 2).await.expect("lookup failed");
+        assert!(matches!(status, Some(BattleStatus::Complete(_))), "{:?}", status);
+        for run_id in ["left", "right"] {
+            let status = crate::get_battle_result(c, TC_TABLE, run_id, 1).await.expect("lookup failed");
+            assert!(matches!(status, Some(BattleStatus::Complete(_))), "{:?}", status);
+        }
+        assert!(list_pending(c, TC_TABLE, &Pool::public(1), 1, 0).await.expect("failed to list").is_empty());
+        assert!(list_pending(c, TC_TABLE, &Pool::public(1), 2, 0).await.expect("failed to list").is_empty());
+
+        let left: Vec<_> = list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 1, 0).await.expect("failed to list")
+            .into_iter().map(|x| x.skey.run_id).collect();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&"fresh".to_string()) && left.contains(&"slow".to_string()));
+        assert!(list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 2, 0).await.expect("failed to list").is_empty());
+    });
+
+    tc!(ended_turns_index_their_pool; |c| {
+        let now = now_secs();
+        ensure_run(c, "a", 5).await;
+        let skey = crate::end_turn(c, TC_TABLE, 5, "a".to_string(), "a", r#"{"units":[]}"#.to_string(), 4).await.expect("failed to end turn");
+        let indexed = IndexedPool { pool: Pool::public(1), turn_number: 5, shard: skey.shard };
+        assert_eq!(list_indexed_pools(c, TC_TABLE, now).await.expect("failed to list"), vec![indexed.clone()]);
+        // listed as long as the entry can be around, then skipped even if ttl did not delete it yet
+        let listed = list_indexed_pools(c, TC_TABLE, now + MATCHMAKING_TTL_SECS).await.expect("failed to list");
+        assert_eq!(listed, vec![indexed]);
+        let listed = list_indexed_pools(c, TC_TABLE, now + MATCHMAKING_TTL_SECS + INDEX_REFRESH_SECS + 60).await.expect("failed to list");
+        assert!(listed.is_empty());
+    });
+}
@@ -0,0 +1,175 @@
+//! re-simulates recorded battles to check them against what was stored, eg. to look into a
+//! player's claim that a battle went wrong.
+
+use std::fmt;
+
+use shared::{
+    api::TeamSnapshot,
+    battle::{BattleEvent, Outcome, Side, ENGINE_VERSION},
+    replay::{events_hash, Replay, REPLAY_VERSION},
+};
+
+use crate::{
+    battle_result::{get_battle_result, BattleRecord, BattleStatus, REPLAY_ATTR},
+    worker::outcome_for,
+    BattleOutcome, Error, MatchmakingStore,
+};
+
+/// how a recorded battle differs from fighting it again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// a replay format this version cannot read
+    UnsupportedVersion(u32),
+    /// the battle was fought by another engine version, so the results are not comparable
+    EngineVersion { recorded: u32, current: u32 },
+    /// a team in the replay has a snapshot version this version cannot read
+    UnreadableTeam { side: Side, version: u32 },
+    /// the team stored on the record is not the one in the replay
+    Team { side: Side },
+    /// the first event that differs. an event is None when that log already ended
+    Event { index: usize, recorded: Option<BattleEvent>, simulated: Option<BattleEvent> },
+    EventsHash { recorded: String, simulated: String },
+    Outcome { recorded: Outcome, simulated: Outcome },
+    /// the outcome recorded for the run does not match the outcome of its side
+    RunOutcome { recorded: BattleOutcome, simulated: BattleOutcome },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::UnsupportedVersion(version) => write!(f, "unsupported replay version {}", version),
+            Divergence::EngineVersion { recorded, current } => {
+                write!(f, "fought by engine version {}, this is version {}", recorded, current)
+            }
+            Divergence::UnreadableTeam { side, version } => write!(f, "team of {:?} has unsupported snapshot version {}", side, version),
+            Divergence::Team { side } => write!(f, "recorded team of {:?} differs from the replay", side),
+            Divergence::Event { index, recorded, simulated } => {
+                write!(f, "event {} differs: recorded {:?}, simulated {:?}", index, recorded, simulated)
+            }
+            Divergence::EventsHash { recorded, simulated } => write!(f, "event log hash {} differs from {}", recorded, simulated),
+            Divergence::Outcome { recorded, simulated } => write!(f, "recorded outcome {:?}, simulated {:?}", recorded, simulated),
+            Divergence::RunOutcome { recorded, simulated } => {
+                write!(f, "run recorded a {}, its side got a {}", recorded.as_str(), simulated.as_str())
+            }
+        }
+    }
+}
+
+/// fights the replay again and compares the result with the replay and with `events`, the
+/// event log that was recorded along with it
+pub fn verify_replay(replay: &Replay, events: &[BattleEvent]) -> Result<(), Divergence> {
+    if replay.version != REPLAY_VERSION {
+        return Err(Divergence::UnsupportedVersion(replay.version));
+    }
+    if replay.engine_version != ENGINE_VERSION {
+        return Err(Divergence::EngineVersion { recorded: replay.engine_version, current: ENGINE_VERSION });
+    }
+    for (side, team) in [(Side::Player1, &replay.player1), (Side::Player2, &replay.player2)] {
+        if team.version != shared::api::TEAM_SNAPSHOT_VERSION {
+            return Err(Divergence::UnreadableTeam { side, version: team.version });
+        }
+    }
+    let result = replay.simulate().expect("team versions were checked");
+    if let Some(index) = (0..events.len().max(result.events.len())).find(|&i| events.get(i) != result.events.get(i)) {
+        let (recorded, simulated) = (events.get(index).cloned(), result.events.get(index).cloned());
+        return Err(Divergence::Event { index, recorded, simulated });
+    }
+    let simulated = events_hash(&result.events);
+    if simulated != replay.events_hash {
+        return Err(Divergence::EventsHash { recorded: replay.events_hash.clone(), simulated });
+    }
+    if result.outcome != replay.outcome {
+        return Err(Divergence::Outcome { recorded: replay.outcome, simulated: result.outcome });
+    }
+    Ok(())
+}
+
+/// `verify_replay` for a stored battle, also checking the record agrees with its replay
+pub fn verify_record(record: &BattleRecord) -> Result<Option<Divergence>, Error> {
+    let replay = record.replay.as_ref().ok_or_else(|| Error::MissingAttribute(REPLAY_ATTR.to_string()))?;
+    let parse = |team: &str| serde_json::from_str::<TeamSnapshot>(team).map_err(|e| Error::InvalidTeam(Box::new(e)));
+    let (own, opponent) = match record.side {
+        Side::Player1 => (&replay.player1, &replay.player2),
+        Side::Player2 => (&replay.player2, &replay.player1),
+    };
+    if &parse(&record.team)? != own {
+        return Ok(Some(Divergence::Team { side: record.side }));
+    }
+    if &parse(&record.opponent_team)? != opponent {
+        return Ok(Some(Divergence::Team { side: record.side.other() }));
+    }
+    if let Err(divergence) = verify_replay(replay, &record.events) {
+        return Ok(Some(divergence));
+    }
+    let simulated = outcome_for(replay.outcome, record.side);
+    if simulated != record.outcome {
+        return Ok(Some(Divergence::RunOutcome { recorded: record.outcome, simulated }));
+    }
+    Ok(None)
+}
+
+/// re-simulates the battle `run_id` fought at the end of `turn_number`.
+/// returns None if everything matches what was recorded.
+pub async fn verify_battle<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+) -> Result<Option<Divergence>, Error> {
+    match get_battle_result(store, table_name, run_id, turn_number).await? {
+        Some(BattleStatus::Complete(record)) => verify_record(&record),
+        _ => Err(Error::BattleNotFound { run_id: run_id.to_string(), turn_number }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        battle_result::save_battle_record, end_turn, test::{ensure_run, TC_TABLE}, worker::{run_matchmaking_task, WorkerConfig},
+        AsyncMatchmakingRequest,
+    };
+    use shared::api::UnitSnapshot;
+
+    fn team_json(attack: i32, health: i32) -> String {
+        let unit = UnitSnapshot { id: None, kind: "test".to_string(), attack, health, ability: None };
+        serde_json::to_string(&TeamSnapshot { units: vec![unit; 2], ..TeamSnapshot::default() }).expect("failed to serialize team")
+    }
+
+    tc!(recorded_battles_verify_until_tampered_with; |c| {
+        ensure_run(c, "a", 1).await;
+        ensure_run(c, "b", 1).await;
+        let _ = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", team_json(1, 3), 1).await.expect("failed to end turn");
+        let skey = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", team_json(2, 2), 1).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey, rating: 100 };
+        run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        assert_eq!(verify_battle(c, TC_TABLE, "a", 1).await.expect("failed to verify"), None);
+        assert_eq!(verify_battle(c, TC_TABLE, "b", 1).await.expect("failed to verify"), None);
+        let res = verify_battle(c, TC_TABLE, "a", 2).await;
+        assert!(matches!(res, Err(Error::BattleNotFound { .. })), "{:?}", res);
+
+        let Some(BattleStatus::Complete(record)) = get_battle_result(c, TC_TABLE, "a", 1).await.expect("lookup failed") else {
+            panic!("battle missing");
+        };
+        let mut tampered = record.clone();
+        tampered.events.truncate(1);
+        let divergence = verify_record(&tampered).expect("failed to verify");
+        assert!(matches!(divergence, Some(Divergence::Event { index: 1, recorded: None, simulated: Some(_) })), "{:?}", divergence);
+
+        let mut tampered = record.clone();
+        let replay = tampered.replay.as_mut().expect("missing replay");
+        replay.outcome = match replay.outcome {
+            Outcome::Draw => Outcome::Winner(Side::Player1),
+            _ => Outcome::Draw,
+        };
+        save_battle_record(c, TC_TABLE, &tampered).await.expect("failed to save record");
+        let divergence = verify_battle(c, TC_TABLE, "a", 1).await.expect("failed to verify");
+        assert!(matches!(divergence, Some(Divergence::Outcome { .. })), "{:?}", divergence);
+
+        let mut tampered = record;
+        tampered.replay.as_mut().expect("missing replay").engine_version += 1;
+        assert!(matches!(verify_record(&tampered), Ok(Some(Divergence::EngineVersion { .. }))));
+        tampered.replay = None;
+        assert!(matches!(verify_record(&tampered), Err(Error::MissingAttribute(_))));
+    });
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+/// how failed calls are retried. the delay before retry `n` is picked uniformly from
+/// `0..=min(max_delay, base_delay * 2^n)` so concurrent callers spread out instead of retrying in lockstep.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// total attempts including the first one. 1 disables retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// time budget for a call including all of its retries.
+    /// an attempt still running when it runs out is abandoned with `Error::DeadlineExceeded`
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(25),
+            max_delay: Duration::from_secs(1),
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// a single attempt, still bounded by the default deadline
+    pub fn none() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let cap = self.base_delay.saturating_mul(2u32.saturating_pow(retry)).min(self.max_delay);
+        let cap = u64::try_from(cap.as_micros()).unwrap_or(u64::MAX);
+        Duration::from_micros(fastrand::u64(0..=cap))
+    }
+}
+
+/// a value along with how many retries it took to produce it
+#[derive(Debug)]
+pub struct Retried<T> {
+    pub value: T,
+    pub retries: u32,
+}
+
+/// calls `f` until it succeeds, fails with an error `should_retry` rejects, or the policy runs out
+/// of attempts or time. `f` is passed the number of retries so far (0 on the first attempt).
+pub async fn retry_if<T, F, Fut>(
+    policy: &RetryPolicy,
+    should_retry: impl Fn(&Error) -> bool,
+    mut f: F,
+) -> Retried<Result<T, Error>>
+    where F: FnMut(u32) -> Fut,
+          Fut: Future<Output = Result<T, Error>>,
+{
+    let start = Instant::now();
+    let mut retries = 0;
+    loop {
+        let remaining = policy.deadline.saturating_sub(start.elapsed());
+        let value = match tokio::time::timeout(remaining, f(retries)).await {
+            Ok(value) => value,
+            Err(_) => Err(Error::DeadlineExceeded(policy.deadline)),
+        };
+        match value {
+            Err(e) if should_retry(&e) && retries + 1 < policy.max_attempts => {
+                let delay = policy.backoff(retries);
+                if start.elapsed() + delay >= policy.deadline {
+                    return Retried { value: Err(e), retries };
+                }
+                tokio::time::sleep(delay).await;
+                retries += 1;
+            }
+            value => return Retried { value, retries },
+        }
+    }
+}
+
+/// `retry_if` retrying every transient error
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, f: F) -> Retried<Result<T, Error>>
+    where F: FnMut(u32) -> Fut,
+          Fut: Future<Output = Result<T, Error>>,
+{
+    retry_if(policy, Error::is_transient, f).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(2), deadline: Duration::from_secs(1) }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = policy();
+        for retry in 0..40 {
+            assert!(policy.backoff(retry) <= policy.max_delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors_until_success() {
+        let calls = Cell::new(0);
+        let res = retry(&policy(), |retries| {
+            calls.set(calls.get() + 1);
+            async move {
+                if retries < 2 { Err(Error::Throttled("slow down".into())) } else { Ok(retries) }
+            }
+        }).await;
+        assert_eq!(res.value.expect("should succeed"), 2);
+        assert_eq!((res.retries, calls.get()), (2, 3));
+
+        // out of attempts
+        let res = retry(&policy(), |_| async { Err::<(), _>(Error::TransactionConflict("busy".into())) }).await;
+        assert!(matches!(res.value, Err(Error::TransactionConflict(_))));
+        assert_eq!(res.retries, 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let res = retry(&policy(), |_| async { Err::<(), _>(Error::RunNotFound("a".to_string())) }).await;
+        assert!(matches!(res.value, Err(Error::RunNotFound(_))));
+        assert_eq!(res.retries, 0);
+
+        // a slow attempt is abandoned at the deadline
+        let policy = RetryPolicy { deadline: Duration::from_millis(10), ..policy() };
+        let res = retry(&policy, |_| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }).await;
+        assert!(matches!(res.value, Err(Error::DeadlineExceeded(_))));
+    }
+}
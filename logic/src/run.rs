@@ -0,0 +1,433 @@
+use std::str::FromStr;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{api, content::Content, PKEY, SKEY};
+
+use crate::{get_number_attr, get_random_string, get_string_attr, get_u32_attr, now_secs, Error, Item, MatchmakingStore};
+
+/// a run is complete once it reaches this many wins
+pub const MAX_WINS: u32 = 10;
+/// a run starts with this many lives, and is over once they are all lost
+pub const STARTING_LIVES: u32 = 5;
+/// the matchmaking rating of a run that has not fought yet
+pub const BASE_RATING: u32 = 100;
+/// how much every win raises the rating, and every lost life lowers it
+pub const RATING_STEP: u32 = 25;
+
+pub const TURN_ATTR: &str = "turn_number";
+pub const WINS_ATTR: &str = "wins";
+pub const LIVES_ATTR: &str = "lives";
+pub const STATUS_ATTR: &str = "status";
+/// the player that created the run. only they can act on it
+pub const OWNER_ATTR: &str = "owner";
+/// incremented on every write so concurrent updates to a run cannot overwrite each other
+pub const VERSION_ATTR: &str = "version";
+/// the `shared::CONTENT_VERSION` the run was started on
+pub const CONTENT_VERSION_ATTR: &str = "content_version";
+/// join code of the lobby the run plays in. missing for runs outside of lobbies
+pub const LOBBY_ATTR: &str = "lobby";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Active,
+    /// reached `MAX_WINS`
+    Won,
+    /// ran out of lives
+    Lost,
+    /// ended early by the player
+    Abandoned,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Active => "active",
+            RunStatus::Won => "won",
+            RunStatus::Lost => "lost",
+            RunStatus::Abandoned => "abandoned",
+        }
+    }
+}
+
+impl FromStr for RunStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(RunStatus::Active),
+            "won" => Ok(RunStatus::Won),
+            "lost" => Ok(RunStatus::Lost),
+            "abandoned" => Ok(RunStatus::Abandoned),
+            _ => Err(Error::invalid_attribute(STATUS_ATTR, format!("unknown run status '{}'", s))),
+        }
+    }
+}
+
+impl From<RunStatus> for api::RunStatus {
+    fn from(status: RunStatus) -> Self {
+        match status {
+            RunStatus::Active => api::RunStatus::Active,
+            RunStatus::Won => api::RunStatus::Won,
+            RunStatus::Lost => api::RunStatus::Lost,
+            RunStatus::Abandoned => api::RunStatus::Abandoned,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BattleOutcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl BattleOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BattleOutcome::Win => "win",
+            BattleOutcome::Loss => "loss",
+            BattleOutcome::Draw => "draw",
+        }
+    }
+}
+
+impl FromStr for BattleOutcome {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "win" => Ok(BattleOutcome::Win),
+            "loss" => Ok(BattleOutcome::Loss),
+            "draw" => Ok(BattleOutcome::Draw),
+            _ => Err(Error::invalid_attribute(crate::battle_result::OUTCOME_ATTR, format!("unknown battle outcome '{}'", s))),
+        }
+    }
+}
+
+impl From<BattleOutcome> for api::BattleOutcome {
+    fn from(outcome: BattleOutcome) -> Self {
+        match outcome {
+            BattleOutcome::Win => api::BattleOutcome::Win,
+            BattleOutcome::Loss => api::BattleOutcome::Loss,
+            BattleOutcome::Draw => api::BattleOutcome::Draw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub run_id: String,
+    /// the turn the run is currently playing. starts at 1
+    pub turn_number: u32,
+    pub wins: u32,
+    pub lives: u32,
+    pub status: RunStatus,
+    /// player id of whoever created the run
+    pub owner: String,
+    pub created_at: u64,
+    pub version: u64,
+    /// the content the run is played with. it only meets runs and ghosts of the same version
+    pub content_version: u32,
+    /// the lobby the run plays in. it only meets runs and ghosts of the same lobby
+    pub lobby: Option<String>,
+}
+
+impl From<&Run> for api::RunResponse {
+    fn from(run: &Run) -> Self {
+        Self {
+            run_id: run.run_id.clone(),
+            turn_number: run.turn_number,
+            wins: run.wins,
+            lives: run.lives,
+            status: run.status.into(),
+            content_version: run.content_version,
+            lobby: run.lobby.clone(),
+        }
+    }
+}
+
+impl Run {
+    pub fn new(run_id: String, owner: String) -> Self {
+        Self {
+            run_id,
+            turn_number: 1,
+            wins: 0,
+            lives: STARTING_LIVES,
+            status: RunStatus::Active,
+            owner,
+            created_at: now_secs(),
+            version: 0,
+            content_version: shared::CONTENT_VERSION,
+            lobby: None,
+        }
+    }
+
+    pub fn to_item(&self) -> Item {
+        let mut item = Item::new();
+        item.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(&self.run_id)));
+        item.insert(SKEY.to_string(), AttributeValue::S(shared::RUN_SKEY.to_string()));
+        item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(self.run_id.clone()));
+        item.insert(TURN_ATTR.to_string(), AttributeValue::N(self.turn_number.to_string()));
+        item.insert(WINS_ATTR.to_string(), AttributeValue::N(self.wins.to_string()));
+        item.insert(LIVES_ATTR.to_string(), AttributeValue::N(self.lives.to_string()));
+        item.insert(STATUS_ATTR.to_string(), AttributeValue::S(self.status.as_str().to_string()));
+        item.insert(OWNER_ATTR.to_string(), AttributeValue::S(self.owner.clone()));
+        item.insert(crate::CREATED_AT_ATTR.to_string(), AttributeValue::N(self.created_at.to_string()));
+        item.insert(VERSION_ATTR.to_string(), AttributeValue::N(self.version.to_string()));
+        item.insert(CONTENT_VERSION_ATTR.to_string(), AttributeValue::N(self.content_version.to_string()));
+        if let Some(lobby) = &self.lobby {
+            item.insert(LOBBY_ATTR.to_string(), AttributeValue::S(lobby.clone()));
+        }
+        item
+    }
+
+    pub fn from_item(item: &Item) -> Result<Self, Error> {
+        Ok(Self {
+            run_id: get_string_attr(item, crate::RUN_ID_ATTR)?,
+            turn_number: get_u32_attr(item, TURN_ATTR)?,
+            wins: get_u32_attr(item, WINS_ATTR)?,
+            lives: get_u32_attr(item, LIVES_ATTR)?,
+            status: RunStatus::from_str(&get_string_attr(item, STATUS_ATTR)?)?,
+            // runs created before owners were recorded belong to nobody
+            owner: get_string_attr(item, OWNER_ATTR).unwrap_or_default(),
+            created_at: get_number_attr(item, crate::CREATED_AT_ATTR)?,
+            version: get_number_attr(item, VERSION_ATTR)?,
+            // runs created before content versions existed were played on the first one
+            content_version: get_u32_attr(item, CONTENT_VERSION_ATTR).unwrap_or(1),
+            lobby: get_string_attr(item, LOBBY_ATTR).ok(),
+        })
+    }
+
+    pub fn ensure_owner(&self, player_id: &str) -> Result<(), Error> {
+        if self.owner.is_empty() || self.owner != player_id {
+            return Err(Error::NotRunOwner { run_id: self.run_id.clone(), player_id: player_id.to_string() });
+        }
+        Ok(())
+    }
+
+    /// errors unless the run is active and currently playing `turn_number`
+    pub fn ensure_turn(&self, turn_number: u32) -> Result<(), Error> {
+        self.ensure_active()?;
+        if self.turn_number != turn_number {
+            return Err(Error::TurnMismatch { run_id: self.run_id.clone(), current: self.turn_number, requested: turn_number });
+        }
+        Ok(())
+    }
+
+    pub fn ensure_active(&self) -> Result<(), Error> {
+        if self.status != RunStatus::Active {
+            return Err(Error::RunNotActive { run_id: self.run_id.clone(), status: self.status });
+        }
+        Ok(())
+    }
+
+    /// the units, tiers and prices the run is played with
+    pub fn content(&self) -> Result<&'static Content, Error> {
+        let content = shared::content::content_for(self.content_version).map_err(|errors| {
+            Error::InvalidContent(errors.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "))
+        })?;
+        content.ok_or_else(|| Error::UnsupportedContentVersion { run_id: self.run_id.clone(), content_version: self.content_version })
+    }
+
+    /// what the run is matched by, so runs with a similar record meet. derived from the run
+    /// rather than taken from the client, which could otherwise pick its own opponents
+    pub fn rating(&self) -> u32 {
+        let lives_lost = STARTING_LIVES.saturating_sub(self.lives);
+        (BASE_RATING + self.wins * RATING_STEP).saturating_sub(lives_lost * RATING_STEP)
+    }
+
+    /// applies the result of the battle fought on the current turn and advances to the next turn.
+    /// the run finishes once it reaches `MAX_WINS` or runs out of lives.
+    pub fn apply_battle_result(&mut self, outcome: BattleOutcome) {
+        match outcome {
+            BattleOutcome::Win => self.wins += 1,
+            BattleOutcome::Loss => self.lives = self.lives.saturating_sub(1),
+            BattleOutcome::Draw => {}
+        }
+        if self.wins >= MAX_WINS {
+            self.status = RunStatus::Won;
+        } else if self.lives == 0 {
+            self.status = RunStatus::Lost;
+        }
+        self.turn_number += 1;
+    }
+}
+
+pub async fn get_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+) -> Result<Option<Run>, Error> {
+    let item = store.get(table_name, &shared::run_pkey(run_id), shared::RUN_SKEY).await?;
+    item.as_ref().map(Run::from_item).transpose()
+}
+
+/// like `get_run`, but a missing run is an error
+pub async fn load_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+) -> Result<Run, Error> {
+    get_run(store, table_name, run_id).await?.ok_or_else(|| Error::RunNotFound(run_id.to_string()))
+}
+
+/// write `run` with its version incremented, as long as nobody else updated it since it was read
+async fn save_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    mut run: Run,
+) -> Result<Run, Error> {
+    let expected = AttributeValue::N(run.version.to_string());
+    run.version += 1;
+    store.put_if_attr_equals(table_name, run.to_item(), VERSION_ATTR, expected).await?;
+    Ok(run)
+}
+
+pub async fn create_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    owner: &str,
+) -> Result<Run, Error> {
+    let run = Run::new(get_random_string(16), owner.to_string());
+    store.put_if_absent(table_name, run.to_item()).await?;
+    Ok(run)
+}
+
+/// record the outcome of the battle fought on `turn_number`. the turn must be the
+/// run's current turn, so a result can only ever be applied once per turn.
+pub async fn record_battle_result<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+    outcome: BattleOutcome,
+) -> Result<Run, Error> {
+    let mut run = load_run(store, table_name, run_id).await?;
+    run.ensure_turn(turn_number)?;
+    run.apply_battle_result(outcome);
+    let run = save_run(store, table_name, run).await?;
+    if run.status != RunStatus::Active {
+        // the result is saved either way. a run missing from the leaderboards is not worth failing the battle over
+        if let Err(e) = crate::leaderboard::record_run(store, table_name, &run, now_secs()).await {
+            eprintln!("failed to add run '{}' to the leaderboards: {}", run.run_id, e);
+        }
+    }
+    Ok(run)
+}
+
+/// end an active run early
+pub async fn end_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    player_id: &str,
+) -> Result<Run, Error> {
+    let mut run = load_run(store, table_name, run_id).await?;
+    run.ensure_owner(player_id)?;
+    run.ensure_active()?;
+    run.status = RunStatus::Abandoned;
+    save_run(store, table_name, run).await
+}
+
+/// stores the team `run_id` submitted at the end of `turn_number`, so it is still
+/// available to fight with after the matchmaking entry has been consumed
+pub async fn save_snapshot<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+    team: &str,
+) -> Result<(), Error> {
+    store.put(table_name, snapshot_item(run_id, turn_number, team)).await
+}
+
+pub(crate) fn snapshot_item(run_id: &str, turn_number: u32, team: &str) -> Item {
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(run_id)));
+    item.insert(SKEY.to_string(), AttributeValue::S(shared::snapshot_skey(turn_number)));
+    item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(run_id.to_string()));
+    item.insert(TURN_ATTR.to_string(), AttributeValue::N(turn_number.to_string()));
+    item.insert(crate::ghost::TEAM_ATTR.to_string(), AttributeValue::S(team.to_string()));
+    item
+}
+
+pub async fn load_snapshot<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+) -> Result<String, Error> {
+    let item = store.get(table_name, &shared::run_pkey(run_id), &shared::snapshot_skey(turn_number)).await?
+        .ok_or_else(|| Error::SnapshotNotFound { run_id: run_id.to_string(), turn_number })?;
+    get_string_attr(&item, crate::ghost::TEAM_ATTR)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TC_TABLE;
+
+    #[test]
+    fn run_ends_at_max_wins_or_no_lives() {
+        let mut run = Run::new("a".to_string(), "p".to_string());
+        for _ in 0..MAX_WINS {
+            assert_eq!(run.status, RunStatus::Active);
+            run.apply_battle_result(BattleOutcome::Win);
+        }
+        assert_eq!(run.status, RunStatus::Won);
+        assert_eq!(run.turn_number, MAX_WINS + 1);
+        assert_eq!(run.rating(), BASE_RATING + MAX_WINS * RATING_STEP);
+
+        let mut run = Run::new("b".to_string(), "p".to_string());
+        for _ in 0..STARTING_LIVES {
+            assert_eq!(run.status, RunStatus::Active);
+            run.apply_battle_result(BattleOutcome::Draw);
+            run.apply_battle_result(BattleOutcome::Loss);
+        }
+        assert_eq!(run.status, RunStatus::Lost);
+        assert_eq!((run.wins, run.rating()), (0, 0));
+    }
+
+    tc!(run_round_trips; |c| {
+        let run = create_run(c, TC_TABLE, "p").await.expect("failed to create run");
+        let loaded = load_run(c, TC_TABLE, &run.run_id).await.expect("failed to load run");
+        assert_eq!(run, loaded);
+        assert!(get_run(c, TC_TABLE, "missing").await.expect("failed to get run").is_none());
+    });
+
+    tc!(battle_result_must_match_current_turn; |c| {
+        let run = create_run(c, TC_TABLE, "p").await.expect("failed to create run");
+        let run = record_battle_result(c, TC_TABLE, &run.run_id, 1, BattleOutcome::Loss).await.expect("failed to record");
+        assert_eq!(run.turn_number, 2);
+        assert_eq!(run.lives, STARTING_LIVES - 1);
+        // the turn 1 result was already applied
+        let res = record_battle_result(c, TC_TABLE, &run.run_id, 1, BattleOutcome::Win).await;
+        assert!(res.is_err());
+        let run = record_battle_result(c, TC_TABLE, &run.run_id, 2, BattleOutcome::Win).await.expect("failed to record");
+        assert_eq!(run.wins, 1);
+        assert!(crate::rank_of(c, TC_TABLE, api::LeaderboardPeriod::AllTime, &run.run_id).await.expect("failed to rank").is_none());
+        for turn_number in 3..3 + STARTING_LIVES - 1 {
+            record_battle_result(c, TC_TABLE, &run.run_id, turn_number, BattleOutcome::Loss).await.expect("failed to record");
+        }
+        // finished runs are ranked
+        let entry = crate::rank_of(c, TC_TABLE, api::LeaderboardPeriod::AllTime, &run.run_id).await.expect("failed to rank");
+        assert_eq!(entry.map(|x| (x.rank, x.wins, x.lives_lost)), Some((1, 1, STARTING_LIVES)));
+    });
+
+    tc!(ended_runs_reject_updates; |c| {
+        let run = create_run(c, TC_TABLE, "p").await.expect("failed to create run");
+        let res = end_run(c, TC_TABLE, &run.run_id, "q").await;
+        assert!(matches!(res, Err(Error::NotRunOwner { .. })), "only the owner can end a run: {:?}", res);
+        let run = end_run(c, TC_TABLE, &run.run_id, "p").await.expect("failed to end run");
+        assert_eq!(run.status, RunStatus::Abandoned);
+        assert!(end_run(c, TC_TABLE, &run.run_id, "p").await.is_err());
+        assert!(record_battle_result(c, TC_TABLE, &run.run_id, 1, BattleOutcome::Win).await.is_err());
+    });
+
+    tc!(stale_writes_are_rejected; |c| {
+        let run = create_run(c, TC_TABLE, "p").await.expect("failed to create run");
+        let _ = save_run(c, TC_TABLE, run.clone()).await.expect("first write should succeed");
+        // the version has moved on, so writing the old copy again must fail
+        assert!(save_run(c, TC_TABLE, run).await.is_err());
+    });
+}
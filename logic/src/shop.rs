@@ -0,0 +1,447 @@
+//! the shop a run builds its team in. every action is validated here, and the shop only depends on
+//! the run, the turn and the actions taken so far, so replaying the stored action log of a turn
+//! always ends up with the same board. units, prices and tiers come from the `shared::content` the
+//! run is played with.
+
+use std::fmt;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::{Deserialize, Serialize};
+use shared::{
+    api::{ShopAction, ShopOffer, ShopView, TeamSnapshot, UnitSnapshot},
+    battle::Rng,
+    content::{Content, UnitDef},
+    PKEY, SKEY,
+};
+
+use crate::{get_number_attr, get_string_attr, run, Error, Item, MatchmakingStore, Write};
+
+/// attribute on shop items holding the `ShopStart` of the turn as json
+pub const START_ATTR: &str = "start";
+/// attribute on shop items holding every action taken so far as json
+pub const ACTIONS_ATTR: &str = "actions";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShopError {
+    NotEnoughGold { needed: u32, available: u32 },
+    NoSuchOffer(usize),
+    /// there is no unit at this board position
+    NoSuchUnit(usize),
+    /// a unit cannot be placed at this board position
+    InvalidPosition(usize),
+    /// the board already holds this many units, the most a team can have
+    TeamFull(usize),
+}
+
+impl fmt::Display for ShopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShopError::NotEnoughGold { needed, available } => write!(f, "needs {} gold, but only {} is left", needed, available),
+            ShopError::NoSuchOffer(offer) => write!(f, "there is no offer {}", offer),
+            ShopError::NoSuchUnit(position) => write!(f, "there is no unit at position {}", position),
+            ShopError::InvalidPosition(position) => write!(f, "position {} is outside the board", position),
+            ShopError::TeamFull(max) => write!(f, "the team already has {} units", max),
+        }
+    }
+}
+
+/// what a turn's shop starts out with: the board and the frozen offers of the previous turn
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShopStart {
+    pub board: TeamSnapshot,
+    /// kinds of the offers that were frozen
+    pub frozen: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Offer {
+    unit: &'static UnitDef,
+    frozen: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Shop {
+    content: &'static Content,
+    pub turn_number: u32,
+    pub gold: u32,
+    pub board: Vec<UnitSnapshot>,
+    offers: Vec<Offer>,
+    /// units bought this turn, so every unit gets its own id
+    bought: u32,
+    rng: Rng,
+}
+
+/// the shop of every run and turn gets its own sequence of offers
+fn shop_seed(run_id: &str, turn_number: u32) -> u64 {
+    // fnv-1a, so the seed is the same on every platform and rust version
+    let hash = run_id.bytes().fold(0xcbf29ce484222325u64, |hash, x| (hash ^ x as u64).wrapping_mul(0x100000001b3));
+    hash ^ (turn_number as u64).wrapping_mul(0x9E3779B97F4A7C15)
+}
+
+impl Shop {
+    pub fn new(content: &'static Content, run_id: &str, turn_number: u32, start: ShopStart) -> Self {
+        let frozen = start.frozen.iter()
+            .filter_map(|x| content.unit(x))
+            .map(|unit| Offer { unit, frozen: true })
+            .take(content.shop_size(turn_number))
+            .collect();
+        let mut shop = Self {
+            content,
+            turn_number,
+            gold: content.gold_per_turn,
+            board: start.board.units,
+            offers: frozen,
+            bought: 0,
+            rng: Rng::new(shop_seed(run_id, turn_number)),
+        };
+        shop.fill();
+        shop
+    }
+
+    /// the shop after taking every action in order. fails on the first invalid one
+    pub fn replay(content: &'static Content, run_id: &str, turn_number: u32, start: ShopStart, actions: &[ShopAction]) -> Result<Self, Error> {
+        let mut shop = Self::new(content, run_id, turn_number, start);
+        for (index, action) in actions.iter().enumerate() {
+            shop.apply(*action).map_err(|reason| Error::InvalidShopAction { index, reason })?;
+        }
+        Ok(shop)
+    }
+
+    fn fill(&mut self) {
+        let max_tier = self.content.max_tier(self.turn_number);
+        let available: Vec<_> = self.content.units.iter().filter(|x| x.tier <= max_tier).collect();
+        while self.offers.len() < self.content.shop_size(self.turn_number) {
+            let unit = available[self.rng.below(available.len())];
+            self.offers.push(Offer { unit, frozen: false });
+        }
+    }
+
+    fn spend(&mut self, amount: u32) -> Result<(), ShopError> {
+        if self.gold < amount {
+            return Err(ShopError::NotEnoughGold { needed: amount, available: self.gold });
+        }
+        self.gold -= amount;
+        Ok(())
+    }
+
+    /// takes a single action. the shop is left unchanged if it is invalid
+    pub fn apply(&mut self, action: ShopAction) -> Result<(), ShopError> {
+        match action {
+            ShopAction::Roll => {
+                self.spend(self.content.roll_cost)?;
+                self.offers.retain(|x| x.frozen);
+                self.fill();
+            }
+            ShopAction::Buy { offer, position } => {
+                let unit = self.offers.get(offer).ok_or(ShopError::NoSuchOffer(offer))?.unit;
+                if self.board.len() >= self.content.max_team_size {
+                    return Err(ShopError::TeamFull(self.content.max_team_size));
+                }
+                if position > self.board.len() {
+                    return Err(ShopError::InvalidPosition(position));
+                }
+                self.spend(self.content.unit_cost)?;
+                self.offers.remove(offer);
+                let id = Some(format!("{}_{}", self.turn_number, self.bought));
+                self.bought += 1;
+                let ability = self.content.unit_ability(unit);
+                let unit = UnitSnapshot { id, kind: unit.kind.clone(), attack: unit.attack, health: unit.health, ability };
+                self.board.insert(position, unit);
+            }
+            ShopAction::Sell { position } => {
+                if position >= self.board.len() {
+                    return Err(ShopError::NoSuchUnit(position));
+                }
+                self.board.remove(position);
+                self.gold += self.content.sell_value;
+            }
+            ShopAction::Move { from, to } => {
+                if from >= self.board.len() {
+                    return Err(ShopError::NoSuchUnit(from));
+                }
+                if to >= self.board.len() {
+                    return Err(ShopError::InvalidPosition(to));
+                }
+                let unit = self.board.remove(from);
+                self.board.insert(to, unit);
+            }
+            ShopAction::Freeze { offer } | ShopAction::Unfreeze { offer } => {
+                let x = self.offers.get_mut(offer).ok_or(ShopError::NoSuchOffer(offer))?;
+                x.frozen = matches!(action, ShopAction::Freeze { .. });
+            }
+        }
+        Ok(())
+    }
+
+    /// the team the board is submitted as at the end of the turn
+    pub fn team(&self) -> TeamSnapshot {
+        TeamSnapshot { units: self.board.clone(), ..TeamSnapshot::default() }
+    }
+
+    /// what the next turn starts with, if the turn ended now
+    pub fn end(&self) -> ShopStart {
+        let frozen = self.offers.iter().filter(|x| x.frozen).map(|x| x.unit.kind.clone()).collect();
+        ShopStart { board: self.team(), frozen }
+    }
+
+    pub fn view(&self) -> ShopView {
+        let offers = self.offers.iter().map(|x| ShopOffer {
+            kind: x.unit.kind.clone(),
+            tier: x.unit.tier,
+            attack: x.unit.attack,
+            health: x.unit.health,
+            cost: self.content.unit_cost,
+            frozen: x.frozen,
+        }).collect();
+        ShopView { turn_number: self.turn_number, gold: self.gold, max_tier: self.content.max_tier(self.turn_number), board: self.team(), offers }
+    }
+}
+
+/// the action log of a turn, as it is stored
+#[derive(Debug, Clone)]
+struct StoredShop {
+    start: ShopStart,
+    actions: Vec<ShopAction>,
+    version: u64,
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("shop types always serialize")
+}
+
+fn from_json<T: for<'a> Deserialize<'a>>(item: &Item, name: &str) -> Result<T, Error> {
+    serde_json::from_str(&get_string_attr(item, name)?).map_err(|e| Error::invalid_attribute(name, e))
+}
+
+fn shop_item(run_id: &str, turn_number: u32, stored: &StoredShop) -> Item {
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(shared::run_pkey(run_id)));
+    item.insert(SKEY.to_string(), AttributeValue::S(shared::shop_skey(turn_number)));
+    item.insert(START_ATTR.to_string(), AttributeValue::S(to_json(&stored.start)));
+    item.insert(ACTIONS_ATTR.to_string(), AttributeValue::S(to_json(&stored.actions)));
+    item.insert(run::VERSION_ATTR.to_string(), AttributeValue::N(stored.version.to_string()));
+    item
+}
+
+async fn load_stored<S: MatchmakingStore>(store: &S, table_name: &str, run_id: &str, turn_number: u32) -> Result<Option<StoredShop>, Error> {
+    let Some(item) = store.get(table_name, &shared::run_pkey(run_id), &shared::shop_skey(turn_number)).await? else {
+        return Ok(None);
+    };
+    Ok(Some(StoredShop {
+        start: from_json(&item, START_ATTR)?,
+        actions: from_json(&item, ACTIONS_ATTR)?,
+        version: get_number_attr(&item, run::VERSION_ATTR)?,
+    }))
+}
+
+/// the board submitted last turn, and whatever was frozen in last turn's shop
+async fn start_of_turn<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    content: &'static Content,
+    run_id: &str,
+    turn_number: u32,
+) -> Result<ShopStart, Error> {
+    if turn_number <= 1 {
+        return Ok(ShopStart::default());
+    }
+    let previous = turn_number - 1;
+    let board = match run::load_snapshot(store, table_name, run_id, previous).await {
+        Ok(team) => serde_json::from_str(&team).map_err(|e| Error::InvalidTeam(Box::new(e)))?,
+        Err(Error::SnapshotNotFound { .. }) => TeamSnapshot::default(),
+        Err(e) => return Err(e),
+    };
+    let frozen = match load_stored(store, table_name, run_id, previous).await? {
+        Some(stored) => Shop::replay(content, run_id, previous, stored.start, &stored.actions)?.end().frozen,
+        None => vec![],
+    };
+    Ok(ShopStart { board, frozen })
+}
+
+/// the turn's action log, created on first use
+async fn load_or_start<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    content: &'static Content,
+    run_id: &str,
+    turn_number: u32,
+) -> Result<StoredShop, Error> {
+    if let Some(stored) = load_stored(store, table_name, run_id, turn_number).await? {
+        return Ok(stored);
+    }
+    let start = start_of_turn(store, table_name, content, run_id, turn_number).await?;
+    let stored = StoredShop { start, actions: vec![], version: 0 };
+    match store.put_if_absent(table_name, shop_item(run_id, turn_number, &stored)).await {
+        Ok(()) => Ok(stored),
+        // a concurrent request created it first
+        Err(Error::ConditionFailed(e)) => load_stored(store, table_name, run_id, turn_number).await?.ok_or(Error::ConditionFailed(e)),
+        Err(e) => Err(e),
+    }
+}
+
+async fn load_shop_run<S: MatchmakingStore>(store: &S, table_name: &str, run_id: &str, player_id: &str) -> Result<run::Run, Error> {
+    let run = run::load_run(store, table_name, run_id).await?;
+    run.ensure_owner(player_id)?;
+    run.ensure_active()?;
+    Ok(run)
+}
+
+/// the shop of the run's current turn, after every action taken so far
+pub async fn get_shop<S: MatchmakingStore>(store: &S, table_name: &str, run_id: &str, player_id: &str) -> Result<Shop, Error> {
+    let run = load_shop_run(store, table_name, run_id, player_id).await?;
+    let content = run.content()?;
+    let stored = load_or_start(store, table_name, content, run_id, run.turn_number).await?;
+    Shop::replay(content, run_id, run.turn_number, stored.start, &stored.actions)
+}
+
+/// validates and stores `actions`. either all of them are taken or none are.
+/// `InvalidShopAction` reports the index of the offending action within `actions`.
+pub async fn apply_shop_actions<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    player_id: &str,
+    turn_number: u32,
+    actions: &[ShopAction],
+) -> Result<Shop, Error> {
+    let run = load_shop_run(store, table_name, run_id, player_id).await?;
+    run.ensure_turn(turn_number)?;
+    // the board was already submitted
+    if store.get(table_name, &shared::run_pkey(run_id), &shared::end_turn_skey(turn_number)).await?.is_some() {
+        return Err(Error::TurnAlreadyEnded { run_id: run_id.to_string(), turn_number });
+    }
+    let content = run.content()?;
+    let mut stored = load_or_start(store, table_name, content, run_id, turn_number).await?;
+    let mut shop = Shop::replay(content, run_id, turn_number, stored.start.clone(), &stored.actions)?;
+    for (index, action) in actions.iter().enumerate() {
+        shop.apply(*action).map_err(|reason| Error::InvalidShopAction { index, reason })?;
+    }
+    let expected = AttributeValue::N(stored.version.to_string());
+    stored.actions.extend_from_slice(actions);
+    stored.version += 1;
+    store.put_if_attr_equals(table_name, shop_item(run_id, turn_number, &stored), run::VERSION_ATTR, expected).await?;
+    Ok(shop)
+}
+
+/// a board built in the shop, and the version of the action log it was built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShopBoard {
+    pub team: TeamSnapshot,
+    pub version: u64,
+}
+
+/// the board built in the shop on `turn_number`, as the team to end the turn with. earlier turns
+/// give the board they were ended with, so a retried submission still finds its team
+pub async fn shop_team<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    player_id: &str,
+    turn_number: u32,
+) -> Result<ShopBoard, Error> {
+    let run = run::load_run(store, table_name, run_id).await?;
+    run.ensure_owner(player_id)?;
+    if turn_number == 0 || turn_number > run.turn_number {
+        return Err(Error::TurnMismatch { run_id: run_id.to_string(), current: run.turn_number, requested: turn_number });
+    }
+    let content = run.content()?;
+    let stored = load_or_start(store, table_name, content, run_id, turn_number).await?;
+    let team = Shop::replay(content, run_id, turn_number, stored.start, &stored.actions)?.team();
+    Ok(ShopBoard { team, version: stored.version })
+}
+
+/// closes the action log of the turn at `version`, for the write that ends the turn. it bumps
+/// the version, so an action racing the end of the turn fails instead of changing a submitted board
+pub(crate) async fn close_shop<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    run_id: &str,
+    turn_number: u32,
+    version: u64,
+) -> Result<Write, Error> {
+    let mut stored = load_stored(store, table_name, run_id, turn_number).await?
+        .filter(|x| x.version == version)
+        .ok_or_else(|| Error::ConditionFailed(format!("the shop of run '{}' changed since version {}", run_id, version).into()))?;
+    stored.version += 1;
+    let expected = AttributeValue::N(version.to_string());
+    Ok(Write::PutIfAttrEquals(shop_item(run_id, turn_number, &stored), run::VERSION_ATTR.to_string(), expected))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{end_turn_with_retry, record_battle_result, test::{ensure_run, TC_TABLE}, BattleOutcome, RetryPolicy};
+
+    fn content() -> &'static Content {
+        shared::content::content().expect("embedded content is invalid")
+    }
+
+    #[test]
+    fn actions_are_validated_and_replayable() {
+        let content = content();
+        let mut shop = Shop::new(content, "a", 1, ShopStart::default());
+        assert_eq!((shop.gold, shop.offers.len()), (content.gold_per_turn, 3));
+        assert!(shop.offers.iter().all(|x| x.unit.tier == 1));
+        let actions = [
+            ShopAction::Buy { offer: 0, position: 0 },
+            ShopAction::Freeze { offer: 1 },
+            ShopAction::Roll,
+            ShopAction::Buy { offer: 1, position: 0 },
+            ShopAction::Move { from: 1, to: 0 },
+            ShopAction::Buy { offer: 1, position: 2 },
+        ];
+        for action in actions {
+            shop.apply(action).expect("valid action");
+        }
+        assert_eq!(shop.gold, content.gold_per_turn - 3 * content.unit_cost - content.roll_cost);
+        assert_eq!(shop.board.len(), 3);
+        // the frozen offer survived the roll
+        assert!(shop.offers[0].frozen);
+        assert_eq!(shop.apply(ShopAction::Sell { position: 3 }), Err(ShopError::NoSuchUnit(3)));
+        assert_eq!(shop.apply(ShopAction::Buy { offer: 0, position: 4 }), Err(ShopError::InvalidPosition(4)));
+        assert_eq!(shop.apply(ShopAction::Buy { offer: 0, position: 0 }), Err(ShopError::NotEnoughGold { needed: content.unit_cost, available: 0 }));
+        assert_eq!(shop.apply(ShopAction::Freeze { offer: 1 }), Err(ShopError::NoSuchOffer(1)));
+
+        let replayed = Shop::replay(content, "a", 1, ShopStart::default(), &actions).expect("replay failed");
+        assert_eq!(replayed.view(), shop.view());
+        // another run gets other offers
+        let differs = (0..16).any(|i| Shop::new(content, &format!("b{}", i), 1, ShopStart::default()).view().offers != Shop::new(content, "a", 1, ShopStart::default()).view().offers);
+        assert!(differs);
+    }
+
+    tc!(the_shop_board_carries_over_to_the_next_turn; |c| {
+        ensure_run(c, "a", 1).await;
+        let res = apply_shop_actions(c, TC_TABLE, "a", "b", 1, &[ShopAction::Roll]).await;
+        assert!(matches!(res, Err(Error::NotRunOwner { .. })), "{:?}", res);
+        let res = apply_shop_actions(c, TC_TABLE, "a", "a", 1, &[ShopAction::Roll, ShopAction::Sell { position: 0 }]).await;
+        assert!(matches!(res, Err(Error::InvalidShopAction { index: 1, reason: ShopError::NoSuchUnit(0) })), "{:?}", res);
+        // nothing of a rejected request is kept
+        assert_eq!(get_shop(c, TC_TABLE, "a", "a").await.expect("failed to get shop").gold, content().gold_per_turn);
+
+        let actions = [ShopAction::Buy { offer: 0, position: 0 }, ShopAction::Freeze { offer: 0 }];
+        let shop = apply_shop_actions(c, TC_TABLE, "a", "a", 1, &actions).await.expect("failed to apply actions");
+        let frozen = shop.view().offers[0].kind.clone();
+        let board = shop_team(c, TC_TABLE, "a", "a", 1).await.expect("failed to get team");
+        let team = board.team;
+        assert_eq!(team, shop.team());
+        // an action taken after the board was read keeps the turn from ending with the stale board
+        let _ = apply_shop_actions(c, TC_TABLE, "a", "a", 1, &[ShopAction::Freeze { offer: 1 }]).await.expect("failed to apply actions");
+        let res = end_turn_with_retry(c, TC_TABLE, 1, "a".to_string(), "a", to_json(&team), Some(board.version), 1, None, &RetryPolicy::default()).await.value;
+        assert!(matches!(res, Err(Error::ConditionFailed(_))), "{:?}", res);
+        let _ = apply_shop_actions(c, TC_TABLE, "a", "a", 1, &[ShopAction::Freeze { offer: 1 }]).await.expect("failed to apply actions");
+        let board = shop_team(c, TC_TABLE, "a", "a", 1).await.expect("failed to get team");
+        end_turn_with_retry(c, TC_TABLE, 1, "a".to_string(), "a", to_json(&team), Some(board.version), 1, None, &RetryPolicy::default())
+            .await.value.expect("failed to end turn");
+        let res = apply_shop_actions(c, TC_TABLE, "a", "a", 1, &[ShopAction::Roll]).await;
+        assert!(matches!(res, Err(Error::TurnAlreadyEnded { .. })), "{:?}", res);
+
+        record_battle_result(c, TC_TABLE, "a", 1, BattleOutcome::Win).await.expect("failed to record");
+        let next = get_shop(c, TC_TABLE, "a", "a").await.expect("failed to get shop");
+        assert_eq!((next.turn_number, next.gold), (2, content().gold_per_turn));
+        assert_eq!(shop_team(c, TC_TABLE, "a", "a", 1).await.expect("failed to get team").team, team);
+        assert!(matches!(shop_team(c, TC_TABLE, "a", "a", 3).await, Err(Error::TurnMismatch { .. })));
+        assert_eq!(next.team(), team);
+        assert!(next.view().offers[0].frozen);
+        assert_eq!(next.view().offers[0].kind, frozen);
+    });
+}
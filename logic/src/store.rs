@@ -0,0 +1,488 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, ops::Bound, sync::Mutex};
+
+use aws_sdk_dynamodb::{
+    operation::transact_write_items::TransactWriteItemsError,
+    types::{AttributeValue, Delete, KeysAndAttributes, Put, Select, TransactWriteItem},
+    Client,
+};
+use shared::{PKEY, SKEY};
+
+use crate::Error;
+
+/// a single stored item. keyed by attribute name, and must contain
+/// at least the `PKEY` and `SKEY` attributes as strings.
+pub type Item = HashMap<String, AttributeValue>;
+
+/// one conditional write of a `transact` call
+#[derive(Debug, Clone)]
+pub enum Write {
+    /// only if no item with the same primary key exists yet
+    PutIfAbsent(Item),
+    /// only if an item with the same primary key exists and its `attr` currently equals the value
+    PutIfAttrEquals(Item, String, AttributeValue),
+    /// only if the item with this pkey and skey exists
+    DeleteExisting(String, String),
+}
+
+#[derive(Debug)]
+pub enum TransactError {
+    /// the condition of the write at this index did not hold. if several did not, the first is reported
+    ConditionFailed(usize),
+    Other(Error),
+}
+
+impl From<TransactError> for Error {
+    fn from(e: TransactError) -> Self {
+        match e {
+            TransactError::ConditionFailed(i) => Error::ConditionFailed(format!("the condition of write {} failed", i).into()),
+            TransactError::Other(e) => e,
+        }
+    }
+}
+
+/// one page of a partition read by `query_page`
+#[derive(Debug, Default)]
+pub struct Page {
+    pub items: Vec<Item>,
+    /// whether the partition has more items after the last one in `items`
+    pub more: bool,
+}
+
+/// the storage operations needed by matchmaking. the dynamodb client implements this directly,
+/// and `MemoryStore` implements it in-process so logic can be exercised without a live table.
+pub trait MatchmakingStore: Sync {
+    /// write the item only if no item with the same primary key exists yet.
+    fn put_if_absent(&self, table_name: &str, item: Item) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// write all items in a single transaction, only if none of them exist yet.
+    /// fails with `Error::ConditionFailed` if any of them does.
+    fn put_all_if_absent(&self, table_name: &str, items: Vec<Item>) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// all writes in a single transaction: either every condition holds and all are written, or none are.
+    fn transact(&self, table_name: &str, writes: Vec<Write>) -> impl Future<Output = Result<(), TransactError>> + Send;
+
+    /// unconditional write. replaces any existing item with the same primary key.
+    fn put(&self, table_name: &str, item: Item) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// write the item only if an item with the same primary key exists
+    /// and its `attr` attribute currently equals `expected`.
+    fn put_if_attr_equals(
+        &self,
+        table_name: &str,
+        item: Item,
+        attr: &str,
+        expected: AttributeValue,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// strongly consistent read of a single item
+    fn get(&self, table_name: &str, pkey: &str, skey: &str) -> impl Future<Output = Result<Option<Item>, Error>> + Send;
+
+    /// strongly consistent read of many items at once. items that do not exist are left out,
+    /// the rest are returned in no particular order
+    fn get_many(&self, table_name: &str, keys: &[(String, String)]) -> impl Future<Output = Result<Vec<Item>, Error>> + Send;
+
+    /// returns the items of a single partition, sorted by sort key.
+    /// only one page is fetched.
+    fn query_partition(&self, table_name: &str, pkey: &str) -> impl Future<Output = Result<Vec<Item>, Error>> + Send;
+
+    /// returns up to `limit` items of a partition whose sort key sorts after `after`
+    /// (or from the start of the partition if None), sorted by sort key.
+    fn query_page(
+        &self,
+        table_name: &str,
+        pkey: &str,
+        after: Option<&str>,
+        limit: u32,
+    ) -> impl Future<Output = Result<Page, Error>> + Send;
+
+    /// how many items of a partition have a sort key that sorts before `skey`. reads every page
+    fn count_before(&self, table_name: &str, pkey: &str, skey: &str) -> impl Future<Output = Result<u64, Error>> + Send;
+
+    /// unconditional delete. succeeds even if the item does not exist.
+    fn delete(&self, table_name: &str, pkey: &str, skey: &str) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+impl MatchmakingStore for Client {
+    async fn put_if_absent(&self, table_name: &str, item: Item) -> Result<(), Error> {
+        self.put_item()
+            .table_name(table_name)
+            .set_item(Some(item))
+            .condition_expression(format!("attribute_not_exists({PKEY})"))
+            .send().await?;
+        Ok(())
+    }
+
+    async fn put_all_if_absent(&self, table_name: &str, items: Vec<Item>) -> Result<(), Error> {
+        let mut transaction = self.transact_write_items();
+        for item in items {
+            let put = Put::builder()
+                .table_name(table_name)
+                .set_item(Some(item))
+                .condition_expression(format!("attribute_not_exists({PKEY})"))
+                .build().expect("transaction builder failure!");
+            transaction = transaction.transact_items(TransactWriteItem::builder().put(put).build());
+        }
+        let e = match transaction.send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if let Some(TransactWriteItemsError::TransactionCanceledException(canceled)) = e.as_service_error() {
+            let reasons = canceled.cancellation_reasons.clone().unwrap_or_default();
+            // any item existing trumps other reasons, as retrying would not help
+            let code = reasons.iter().filter_map(|x| x.code.clone()).filter(|x| x != "None")
+                .max_by_key(|x| x == "ConditionalCheckFailed");
+            return Err(Error::from_code(code.as_deref(), Box::new(e)));
+        }
+        Err(e.into())
+    }
+
+    async fn transact(&self, table_name: &str, writes: Vec<Write>) -> Result<(), TransactError> {
+        let mut transaction = self.transact_write_items();
+        for write in writes {
+            let item = match write {
+                Write::PutIfAbsent(item) => {
+                    let put = Put::builder()
+                        .table_name(table_name)
+                        .set_item(Some(item))
+                        .condition_expression(format!("attribute_not_exists({PKEY})"))
+                        .build().expect("transaction builder failure!");
+                    TransactWriteItem::builder().put(put).build()
+                }
+                Write::PutIfAttrEquals(item, attr, expected) => {
+                    let put = Put::builder()
+                        .table_name(table_name)
+                        .set_item(Some(item))
+                        .condition_expression("#attr = :expected")
+                        .expression_attribute_names("#attr", attr)
+                        .expression_attribute_values(":expected", expected)
+                        .build().expect("transaction builder failure!");
+                    TransactWriteItem::builder().put(put).build()
+                }
+                Write::DeleteExisting(pkey, skey) => {
+                    let delete = Delete::builder()
+                        .table_name(table_name)
+                        .key(PKEY, AttributeValue::S(pkey))
+                        .key(SKEY, AttributeValue::S(skey))
+                        .condition_expression(format!("attribute_exists({PKEY})"))
+                        .build().expect("transaction builder failure!");
+                    TransactWriteItem::builder().delete(delete).build()
+                }
+            };
+            transaction = transaction.transact_items(item);
+        }
+        let e = match transaction.send().await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if let Some(TransactWriteItemsError::TransactionCanceledException(canceled)) = e.as_service_error() {
+            // the reasons are in the order of the writes
+            let reasons = canceled.cancellation_reasons.clone().unwrap_or_default();
+            if let Some(i) = reasons.iter().position(|x| x.code.as_deref() == Some("ConditionalCheckFailed")) {
+                return Err(TransactError::ConditionFailed(i));
+            }
+            let code = reasons.iter().filter_map(|x| x.code.clone()).find(|x| x != "None");
+            return Err(TransactError::Other(Error::from_code(code.as_deref(), Box::new(e))));
+        }
+        Err(TransactError::Other(e.into()))
+    }
+
+    async fn put(&self, table_name: &str, item: Item) -> Result<(), Error> {
+        self.put_item()
+            .table_name(table_name)
+            .set_item(Some(item))
+            .send().await?;
+        Ok(())
+    }
+
+    async fn put_if_attr_equals(
+        &self,
+        table_name: &str,
+        item: Item,
+        attr: &str,
+        expected: AttributeValue,
+    ) -> Result<(), Error> {
+        self.put_item()
+            .table_name(table_name)
+            .set_item(Some(item))
+            .condition_expression("#attr = :expected")
+            .expression_attribute_names("#attr", attr)
+            .expression_attribute_values(":expected", expected)
+            .send().await?;
+        Ok(())
+    }
+
+    async fn get(&self, table_name: &str, pkey: &str, skey: &str) -> Result<Option<Item>, Error> {
+        let out = self.get_item()
+            .table_name(table_name)
+            .key(PKEY, AttributeValue::S(pkey.to_string()))
+            .key(SKEY, AttributeValue::S(skey.to_string()))
+            .consistent_read(true)
+            .send().await?;
+        Ok(out.item)
+    }
+
+    async fn get_many(&self, table_name: &str, keys: &[(String, String)]) -> Result<Vec<Item>, Error> {
+        let mut out = Vec::with_capacity(keys.len());
+        // a batch reads at most 100 items
+        for chunk in keys.chunks(100) {
+            let mut request = KeysAndAttributes::builder().consistent_read(true);
+            for (pkey, skey) in chunk {
+                let key = HashMap::from([
+                    (PKEY.to_string(), AttributeValue::S(pkey.clone())),
+                    (SKEY.to_string(), AttributeValue::S(skey.clone())),
+                ]);
+                request = request.keys(key);
+            }
+            let mut pending = Some(HashMap::from([(table_name.to_string(), request.build().expect("batch builder failure!"))]));
+            // keys dynamodb did not get to, eg. when throttled, are handed back to be asked for again
+            while let Some(request) = pending.take().filter(|x| !x.is_empty()) {
+                let res = self.batch_get_item().set_request_items(Some(request)).send().await?;
+                if let Some(mut responses) = res.responses {
+                    out.extend(responses.remove(table_name).unwrap_or_default());
+                }
+                pending = res.unprocessed_keys;
+            }
+        }
+        Ok(out)
+    }
+
+    async fn query_partition(&self, table_name: &str, pkey: &str) -> Result<Vec<Item>, Error> {
+        let out = self.query()
+            .table_name(table_name)
+            .key_condition_expression(format!("{} = :pkey", PKEY))
+            .expression_attribute_values(":pkey", AttributeValue::S(pkey.to_string()))
+            .send().await?;
+        Ok(out.items.unwrap_or_default())
+    }
+
+    async fn query_page(&self, table_name: &str, pkey: &str, after: Option<&str>, limit: u32) -> Result<Page, Error> {
+        let mut query = self.query()
+            .table_name(table_name)
+            .expression_attribute_values(":pkey", AttributeValue::S(pkey.to_string()))
+            .limit(i32::try_from(limit).unwrap_or(i32::MAX));
+        query = match after {
+            Some(after) => query
+                .key_condition_expression(format!("{} = :pkey AND {} > :after", PKEY, SKEY))
+                .expression_attribute_values(":after", AttributeValue::S(after.to_string())),
+            None => query.key_condition_expression(format!("{} = :pkey", PKEY)),
+        };
+        let out = query.send().await?;
+        Ok(Page { items: out.items.unwrap_or_default(), more: out.last_evaluated_key.is_some() })
+    }
+
+    async fn count_before(&self, table_name: &str, pkey: &str, skey: &str) -> Result<u64, Error> {
+        let mut count = 0;
+        let mut start = None;
+        loop {
+            let out = self.query()
+                .table_name(table_name)
+                .key_condition_expression(format!("{} = :pkey AND {} < :skey", PKEY, SKEY))
+                .expression_attribute_values(":pkey", AttributeValue::S(pkey.to_string()))
+                .expression_attribute_values(":skey", AttributeValue::S(skey.to_string()))
+                .select(Select::Count)
+                .set_exclusive_start_key(start)
+                .send().await?;
+            count += out.count as u64;
+            start = out.last_evaluated_key;
+            if start.is_none() {
+                return Ok(count);
+            }
+        }
+    }
+
+    async fn delete(&self, table_name: &str, pkey: &str, skey: &str) -> Result<(), Error> {
+        self.delete_item()
+            .table_name(table_name)
+            .key(PKEY, AttributeValue::S(pkey.to_string()))
+            .key(SKEY, AttributeValue::S(skey.to_string()))
+            .send().await?;
+        Ok(())
+    }
+}
+
+fn condition_failed() -> Error {
+    Error::ConditionFailed("ConditionalCheckFailedException: The conditional request failed".into())
+}
+
+type Partitions = BTreeMap<String, BTreeMap<String, Item>>;
+
+/// an in-process store that mimics the dynamodb semantics relied upon by matchmaking.
+/// only tables passed to `new` exist; any other table name fails the same
+/// way dynamodb does for a missing table.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    tables: Mutex<HashMap<String, Partitions>>,
+}
+
+impl MemoryStore {
+    pub fn new(table_names: &[&str]) -> Self {
+        let tables = table_names.iter().map(|x| (x.to_string(), Partitions::new())).collect();
+        Self { tables: Mutex::new(tables) }
+    }
+
+    fn with_table<T>(&self, table_name: &str, cb: impl FnOnce(&mut Partitions) -> T) -> Result<T, Error> {
+        let mut tables = self.tables.lock().map_err(|e| Error::Service(e.to_string().into()))?;
+        match tables.get_mut(table_name) {
+            Some(table) => Ok(cb(table)),
+            None => Err(Error::TableNotFound(format!("ResourceNotFoundException: Requested resource not found: Table: {} not found", table_name).into())),
+        }
+    }
+}
+
+fn get_key(item: &Item, key: &str) -> Result<String, Error> {
+    match item.get(key) {
+        Some(AttributeValue::S(s)) => Ok(s.clone()),
+        _ => Err(Error::MissingAttribute(key.to_string())),
+    }
+}
+
+impl MatchmakingStore for MemoryStore {
+    async fn put_if_absent(&self, table_name: &str, item: Item) -> Result<(), Error> {
+        let pkey = get_key(&item, PKEY)?;
+        let skey = get_key(&item, SKEY)?;
+        self.with_table(table_name, |table| {
+            let partition = table.entry(pkey).or_default();
+            if partition.contains_key(&skey) {
+                return Err(condition_failed());
+            }
+            partition.insert(skey, item);
+            Ok(())
+        })?
+    }
+
+    async fn put_all_if_absent(&self, table_name: &str, items: Vec<Item>) -> Result<(), Error> {
+        let mut keys = Vec::with_capacity(items.len());
+        for item in &items {
+            keys.push((get_key(item, PKEY)?, get_key(item, SKEY)?));
+        }
+        self.with_table(table_name, |table| {
+            let distinct: HashSet<_> = keys.iter().collect();
+            if distinct.len() != keys.len() {
+                return Err(Error::Service("ValidationException: Transaction request cannot include multiple operations on one item".into()));
+            }
+            if keys.iter().any(|(pkey, skey)| table.get(pkey).is_some_and(|x| x.contains_key(skey))) {
+                return Err(condition_failed());
+            }
+            for ((pkey, skey), item) in keys.into_iter().zip(items) {
+                table.entry(pkey).or_default().insert(skey, item);
+            }
+            Ok(())
+        })?
+    }
+
+    async fn transact(&self, table_name: &str, writes: Vec<Write>) -> Result<(), TransactError> {
+        let mut keys = Vec::with_capacity(writes.len());
+        for write in &writes {
+            let key = match write {
+                Write::PutIfAbsent(item) | Write::PutIfAttrEquals(item, ..) => (get_key(item, PKEY), get_key(item, SKEY)),
+                Write::DeleteExisting(pkey, skey) => (Ok(pkey.clone()), Ok(skey.clone())),
+            };
+            keys.push((key.0.map_err(TransactError::Other)?, key.1.map_err(TransactError::Other)?));
+        }
+        self.with_table(table_name, |table| {
+            let distinct: HashSet<_> = keys.iter().collect();
+            if distinct.len() != keys.len() {
+                return Err(TransactError::Other(Error::Service("ValidationException: Transaction request cannot include multiple operations on one item".into())));
+            }
+            for (i, ((pkey, skey), write)) in keys.iter().zip(&writes).enumerate() {
+                let existing = table.get(pkey).and_then(|x| x.get(skey));
+                let holds = match write {
+                    Write::PutIfAbsent(_) => existing.is_none(),
+                    Write::PutIfAttrEquals(_, attr, expected) => existing.and_then(|x| x.get(attr)) == Some(expected),
+                    Write::DeleteExisting(..) => existing.is_some(),
+                };
+                if !holds {
+                    return Err(TransactError::ConditionFailed(i));
+                }
+            }
+            for ((pkey, skey), write) in keys.into_iter().zip(writes) {
+                match write {
+                    Write::PutIfAbsent(item) | Write::PutIfAttrEquals(item, ..) => {
+                        table.entry(pkey).or_default().insert(skey, item);
+                    }
+                    Write::DeleteExisting(..) => {
+                        if let Some(partition) = table.get_mut(&pkey) {
+                            partition.remove(&skey);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }).map_err(TransactError::Other)?
+    }
+
+    async fn put(&self, table_name: &str, item: Item) -> Result<(), Error> {
+        let pkey = get_key(&item, PKEY)?;
+        let skey = get_key(&item, SKEY)?;
+        self.with_table(table_name, |table| {
+            table.entry(pkey).or_default().insert(skey, item);
+        })
+    }
+
+    async fn put_if_attr_equals(
+        &self,
+        table_name: &str,
+        item: Item,
+        attr: &str,
+        expected: AttributeValue,
+    ) -> Result<(), Error> {
+        let pkey = get_key(&item, PKEY)?;
+        let skey = get_key(&item, SKEY)?;
+        self.with_table(table_name, |table| {
+            let partition = table.entry(pkey).or_default();
+            let matches = partition.get(&skey).and_then(|x| x.get(attr)) == Some(&expected);
+            if !matches {
+                return Err(condition_failed());
+            }
+            partition.insert(skey, item);
+            Ok(())
+        })?
+    }
+
+    async fn get(&self, table_name: &str, pkey: &str, skey: &str) -> Result<Option<Item>, Error> {
+        self.with_table(table_name, |table| {
+            table.get(pkey).and_then(|x| x.get(skey)).cloned()
+        })
+    }
+
+    async fn get_many(&self, table_name: &str, keys: &[(String, String)]) -> Result<Vec<Item>, Error> {
+        self.with_table(table_name, |table| {
+            keys.iter().filter_map(|(pkey, skey)| table.get(pkey).and_then(|x| x.get(skey)).cloned()).collect()
+        })
+    }
+
+    async fn query_partition(&self, table_name: &str, pkey: &str) -> Result<Vec<Item>, Error> {
+        self.with_table(table_name, |table| {
+            table.get(pkey).map(|x| x.values().cloned().collect()).unwrap_or_default()
+        })
+    }
+
+    async fn query_page(&self, table_name: &str, pkey: &str, after: Option<&str>, limit: u32) -> Result<Page, Error> {
+        self.with_table(table_name, |table| {
+            let Some(partition) = table.get(pkey) else {
+                return Page::default();
+            };
+            let start = match after {
+                Some(after) => Bound::Excluded(after.to_string()),
+                None => Bound::Unbounded,
+            };
+            let mut range = partition.range((start, Bound::Unbounded));
+            let items = range.by_ref().take(limit as usize).map(|(_, x)| x.clone()).collect();
+            Page { items, more: range.next().is_some() }
+        })
+    }
+
+    async fn count_before(&self, table_name: &str, pkey: &str, skey: &str) -> Result<u64, Error> {
+        self.with_table(table_name, |table| {
+            table.get(pkey).map(|x| x.range((Bound::Unbounded, Bound::Excluded(skey.to_string()))).count() as u64).unwrap_or_default()
+        })
+    }
+
+    async fn delete(&self, table_name: &str, pkey: &str, skey: &str) -> Result<(), Error> {
+        self.with_table(table_name, |table| {
+            if let Some(partition) = table.get_mut(pkey) {
+                partition.remove(skey);
+            }
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! checks submitted teams against the rules of their turn, so a client cannot send a board the
+//! shop could never have produced. rejections are kept per player to spot abusive clients.
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use shared::{
+    api::{TeamRejection, TeamSnapshot},
+    content::Content,
+    PKEY, SKEY,
+};
+
+use crate::{get_number_attr, get_string_attr, get_u32_attr, run, Error, Item, MatchmakingStore};
+
+/// rejections are deleted by dynamodb ttl after this long
+pub const REJECTION_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub const REASON_ATTR: &str = "reason";
+pub const TURN_ATTR: &str = "turn_number";
+
+/// the first rule `team` breaks on `turn_number`, if any
+pub fn validate_team(content: &Content, team: &TeamSnapshot, turn_number: u32) -> Result<(), TeamRejection> {
+    if team.units.len() > content.max_team_size {
+        return Err(TeamRejection::TooManyUnits { count: team.units.len(), max: content.max_team_size });
+    }
+    let max_tier = content.max_tier(turn_number);
+    let mut ids = Vec::with_capacity(team.units.len());
+    for (position, unit) in team.units.iter().enumerate() {
+        let kind = unit.kind.clone();
+        let def = content.unit(&unit.kind).ok_or_else(|| TeamRejection::UnknownUnit { position, kind: kind.clone() })?;
+        if def.tier > max_tier {
+            return Err(TeamRejection::TierNotAvailable { position, kind, tier: def.tier, max_tier });
+        }
+        if unit.ability != content.unit_ability(def) {
+            return Err(TeamRejection::AbilityMismatch { position, kind });
+        }
+        // stats only change in battle, so a board unit always has its base stats
+        let (max_attack, max_health) = (def.attack, def.health);
+        if unit.attack < 0 || unit.health < 1 || unit.attack > max_attack || unit.health > max_health {
+            return Err(TeamRejection::StatsOutOfRange { position, kind, attack: unit.attack, health: unit.health, max_attack, max_health });
+        }
+        if let Some(id) = &unit.id {
+            if ids.contains(&id) {
+                return Err(TeamRejection::DuplicateUnitId { id: id.clone() });
+            }
+            ids.push(id);
+        }
+    }
+    Ok(())
+}
+
+/// a team that was turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectionRecord {
+    pub run_id: String,
+    pub turn_number: u32,
+    pub rejection: TeamRejection,
+    /// the submitted team, as json
+    pub team: String,
+    pub created_at: u64,
+}
+
+fn rejection_skey(created_at: u64) -> String {
+    // the random part keeps two rejections in the same second apart
+    format!("rejection_{:010}_{}", created_at, crate::get_random_string(6))
+}
+
+pub async fn record_rejection<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player_id: &str,
+    record: &RejectionRecord,
+) -> Result<(), Error> {
+    let reason = serde_json::to_string(&record.rejection).map_err(|e| Error::invalid_attribute(REASON_ATTR, e))?;
+    let mut item = Item::new();
+    item.insert(PKEY.to_string(), AttributeValue::S(shared::player_pkey(player_id)));
+    item.insert(SKEY.to_string(), AttributeValue::S(rejection_skey(record.created_at)));
+    item.insert(crate::RUN_ID_ATTR.to_string(), AttributeValue::S(record.run_id.clone()));
+    item.insert(TURN_ATTR.to_string(), AttributeValue::N(record.turn_number.to_string()));
+    item.insert(REASON_ATTR.to_string(), AttributeValue::S(reason));
+    item.insert(crate::ghost::TEAM_ATTR.to_string(), AttributeValue::S(record.team.clone()));
+    item.insert(crate::CREATED_AT_ATTR.to_string(), AttributeValue::N(record.created_at.to_string()));
+    item.insert(shared::EXPIRES_AT_ATTR.to_string(), AttributeValue::N((record.created_at + REJECTION_TTL_SECS).to_string()));
+    store.put(table_name, item).await
+}
+
+/// every rejection of the player that is not expired yet, oldest first
+pub async fn list_rejections<S: MatchmakingStore>(store: &S, table_name: &str, player_id: &str) -> Result<Vec<RejectionRecord>, Error> {
+    let items = store.query_partition(table_name, &shared::player_pkey(player_id)).await?;
+    let mut out = Vec::new();
+    for item in items {
+        let is_rejection = item.get(SKEY).and_then(|x| x.as_s().ok()).is_some_and(|x| x.starts_with("rejection_"));
+        if !is_rejection {
+            continue;
+        }
+        out.push(RejectionRecord {
+            run_id: get_string_attr(&item, crate::RUN_ID_ATTR)?,
+            turn_number: get_u32_attr(&item, TURN_ATTR)?,
+            rejection: serde_json::from_str(&get_string_attr(&item, REASON_ATTR)?).map_err(|e| Error::invalid_attribute(REASON_ATTR, e))?,
+            team: get_string_attr(&item, crate::ghost::TEAM_ATTR)?,
+            created_at: get_number_attr(&item, crate::CREATED_AT_ATTR)?,
+        });
+    }
+    Ok(out)
+}
+
+/// validates a team `player_id` submitted for `run_id` against the content the run is played with.
+/// it must also be the `board` built in the shop. a rejected team is recorded and fails with `Error::TeamRejected`
+pub async fn check_team<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player_id: &str,
+    run_id: &str,
+    turn_number: u32,
+    team: &TeamSnapshot,
+    board: &TeamSnapshot,
+) -> Result<(), Error> {
+    let content = run::load_run(store, table_name, run_id).await?.content()?;
+    let res = validate_team(content, team, turn_number).and_then(|()| if team == board { Ok(()) } else { Err(TeamRejection::NotShopBoard) });
+    let Err(rejection) = res else {
+        return Ok(());
+    };
+    eprintln!("rejected team of run '{}' on turn {} from player '{}': {}", run_id, turn_number, player_id, rejection);
+    let record = RejectionRecord {
+        run_id: run_id.to_string(),
+        turn_number,
+        rejection: rejection.clone(),
+        team: serde_json::to_string(team).unwrap_or_default(),
+        created_at: crate::now_secs(),
+    };
+    // the team is rejected either way
+    if let Err(e) = record_rejection(store, table_name, player_id, &record).await {
+        eprintln!("failed to record rejection: {}", e);
+    }
+    Err(Error::TeamRejected(rejection))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{shop, test::{ensure_run, TC_TABLE}, Run};
+    use shared::api::{ShopAction, UnitSnapshot};
+
+    fn content() -> &'static Content {
+        shared::content::content().expect("embedded content is invalid")
+    }
+
+    fn unit(kind: &str, attack: i32, health: i32) -> UnitSnapshot {
+        let def = content().unit(kind).expect("unknown kind");
+        UnitSnapshot { id: None, kind: kind.to_string(), attack, health, ability: content().unit_ability(def) }
+    }
+
+    fn team(units: Vec<UnitSnapshot>) -> TeamSnapshot {
+        TeamSnapshot { units, ..TeamSnapshot::default() }
+    }
+
+    #[test]
+    fn impossible_teams_are_rejected() {
+        assert_eq!(validate_team(content(), &team(vec![unit("squire", 2, 2); 5]), 1), Ok(()));
+        let res = validate_team(content(), &team(vec![unit("squire", 2, 2); 6]), 1);
+        assert_eq!(res, Err(TeamRejection::TooManyUnits { count: 6, max: content().max_team_size }));
+        let res = validate_team(content(), &team(vec![unit("squire", 2, 2), unit("titan", 8, 8)]), 3);
+        assert_eq!(res, Err(TeamRejection::TierNotAvailable { position: 1, kind: "titan".to_string(), tier: 6, max_tier: 2 }));
+        assert_eq!(validate_team(content(), &team(vec![unit("titan", 8, 8)]), 11), Ok(()));
+        let res = validate_team(content(), &team(vec![unit("squire", 3, 2)]), 1);
+        assert!(matches!(res, Err(TeamRejection::StatsOutOfRange { max_attack: 2, .. })), "{:?}", res);
+        // not even on later turns
+        let res = validate_team(content(), &team(vec![unit("squire", 3, 2)]), 2);
+        assert!(matches!(res, Err(TeamRejection::StatsOutOfRange { max_attack: 2, max_health: 2, .. })), "{:?}", res);
+        assert_eq!(validate_team(content(), &team(vec![unit("squire", 1, 1)]), 2), Ok(()));
+        assert!(matches!(validate_team(content(), &team(vec![unit("squire", 2, 0)]), 2), Err(TeamRejection::StatsOutOfRange { .. })));
+        let mut stolen = unit("squire", 2, 2);
+        stolen.ability = unit("slinger", 1, 2).ability;
+        assert!(matches!(validate_team(content(), &team(vec![stolen]), 1), Err(TeamRejection::AbilityMismatch { position: 0, .. })));
+        let res = validate_team(content(), &team(vec![UnitSnapshot { kind: "dragon".to_string(), ..unit("squire", 2, 2) }]), 1);
+        assert!(matches!(res, Err(TeamRejection::UnknownUnit { .. })));
+        let with_id = UnitSnapshot { id: Some("1_0".to_string()), ..unit("squire", 2, 2) };
+        let res = validate_team(content(), &team(vec![with_id.clone(), with_id]), 1);
+        assert_eq!(res, Err(TeamRejection::DuplicateUnitId { id: "1_0".to_string() }));
+
+        // anything the shop builds is allowed
+        let actions = [ShopAction::Buy { offer: 0, position: 0 }, ShopAction::Buy { offer: 0, position: 1 }, ShopAction::Buy { offer: 0, position: 0 }];
+        let shop = shop::Shop::replay(content(), "a", 1, shop::ShopStart::default(), &actions).expect("valid actions");
+        assert_eq!(validate_team(content(), &shop.team(), 1), Ok(()));
+    }
+
+    tc!(rejections_are_recorded_per_player; |c| {
+        ensure_run(c, "a", 1).await;
+        let board = team(vec![unit("squire", 2, 2)]);
+        let res = check_team(c, TC_TABLE, "p", "a", 1, &team(vec![unit("titan", 8, 8)]), &board).await;
+        assert!(matches!(res, Err(Error::TeamRejected(TeamRejection::TierNotAvailable { .. }))), "{:?}", res);
+        // allowed on this turn, but not what the shop built
+        let res = check_team(c, TC_TABLE, "p", "a", 1, &team(vec![unit("slinger", 1, 2)]), &board).await;
+        assert!(matches!(res, Err(Error::TeamRejected(TeamRejection::NotShopBoard))), "{:?}", res);
+        check_team(c, TC_TABLE, "p", "a", 1, &board, &board).await.expect("valid team");
+        let rejections = list_rejections(c, TC_TABLE, "p").await.expect("failed to list");
+        assert_eq!(rejections.len(), 2);
+        assert_eq!((rejections[0].run_id.as_str(), rejections[0].turn_number), ("a", 1));
+        assert!(list_rejections(c, TC_TABLE, "q").await.expect("failed to list").is_empty());
+
+        // runs of a content version this build does not have are not checked against the current one
+        let mut run = Run::new("b".to_string(), "p".to_string());
+        run.content_version = shared::CONTENT_VERSION + 1;
+        c.put(TC_TABLE, run.to_item()).await.expect("failed to write run");
+        let res = check_team(c, TC_TABLE, "p", "b", 1, &board, &board).await;
+        assert!(matches!(res, Err(Error::UnsupportedContentVersion { .. })), "{:?}", res);
+    });
+}
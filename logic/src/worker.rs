@@ -0,0 +1,455 @@
+use shared::{api::TeamSnapshot, battle::{BattleResult, Outcome, Side, Team}, replay::Replay};
+
+use crate::{
+    attempt_matchmaking, attempt_matchmaking_sliced, battle_result::{get_battle_result, save_battle_record, BattleRecord, BattleStatus, OpponentRef},
+    list_matchmaking_entries, now_secs, pending::{claim_for_ghost, delete_pending, get_pending, PendingBattle}, run::{load_snapshot, record_battle_result},
+    sample_ghost, AsyncMatchmakingRequest, BattleOutcome, Error, Ghost, MatchmakingResult, MatchmakingSkey, MatchmakingStore, Pool, RatingWindow,
+    Retried, RetryPolicy, SliceConfig,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerConfig {
+    pub window: RatingWindow,
+    /// how many turns away from our own turn we look for a ghost
+    pub max_ghost_turn_distance: u32,
+    /// applied to listing the matchmaking pool and to each match attempt
+    pub retry: RetryPolicy,
+    /// read the matchmaking pool in bounded random slices. None reads a single page of it instead
+    pub slices: Option<SliceConfig>,
+    /// how many shards the matchmaking pool is spread over
+    pub shard_count: u32,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            window: RatingWindow::default(),
+            max_ghost_turn_distance: 2,
+            retry: RetryPolicy::default(),
+            slices: Some(SliceConfig::default()),
+            shard_count: shared::DEFAULT_MATCHMAKING_SHARDS,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Opponent {
+    Run(MatchmakingSkey),
+    Ghost(Ghost),
+    /// there was nobody to fight at all, not even a ghost
+    Empty,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fight {
+    pub opponent: Opponent,
+    pub seed: u64,
+    /// the outcome from the point of view of the player that was matchmaking
+    pub outcome: BattleOutcome,
+}
+
+#[derive(Debug)]
+pub enum MatchmakingTaskResult {
+    /// fought against another live run. both runs had the result recorded
+    Matched { fight: Fight, rating_distance: u32 },
+    /// fought against a ghost. `reason` is the error that prevented a live match, if any
+    Ghost { fight: Fight, reason: Option<Error> },
+    /// an earlier attempt claimed the player's entry but failed before the battle was over.
+    /// this attempt fought the battle it was paired for
+    Resumed { fight: Fight },
+    /// the player was already matched by another invocation, or by an earlier attempt of this task
+    Dropped,
+}
+
+fn unsupported_version(version: u32) -> Error {
+    Error::InvalidTeam(format!("unsupported team snapshot version {}", version).into())
+}
+
+/// decodes a stored `TeamSnapshot` into the team the engine fights with
+pub fn parse_team(team: &str) -> Result<Team, Error> {
+    let snapshot: TeamSnapshot = serde_json::from_str(team).map_err(|e| Error::InvalidTeam(Box::new(e)))?;
+    snapshot.into_team().map_err(unsupported_version)
+}
+
+/// fights two stored teams, along with the replay of the battle
+fn fight(team1: &str, team2: &str, seed: u64) -> Result<(Replay, BattleResult), Error> {
+    let parse = |team: &str| serde_json::from_str::<TeamSnapshot>(team).map_err(|e| Error::InvalidTeam(Box::new(e)));
+    Replay::fight(parse(team1)?, parse(team2)?, seed).map_err(unsupported_version)
+}
+
+pub(crate) fn outcome_for(outcome: Outcome, side: Side) -> BattleOutcome {
+    match outcome {
+        Outcome::Winner(x) if x == side => BattleOutcome::Win,
+        Outcome::Winner(_) => BattleOutcome::Loss,
+        Outcome::Draw => BattleOutcome::Draw,
+    }
+}
+
+/// the same battle from the opponent's side. only battles against a live run have one
+fn mirrored(record: &BattleRecord) -> Option<BattleRecord> {
+    let OpponentRef::Run(opponent) = &record.opponent else {
+        return None;
+    };
+    let outcome = match record.outcome {
+        BattleOutcome::Win => BattleOutcome::Loss,
+        BattleOutcome::Loss => BattleOutcome::Win,
+        BattleOutcome::Draw => BattleOutcome::Draw,
+    };
+    Some(BattleRecord {
+        run_id: opponent.clone(),
+        turn_number: record.turn_number,
+        opponent: OpponentRef::Run(record.run_id.clone()),
+        team: record.opponent_team.clone(),
+        opponent_team: record.team.clone(),
+        seed: record.seed,
+        side: record.side.other(),
+        outcome,
+        events: record.events.clone(),
+        replay: record.replay.clone(),
+    })
+}
+
+/// records the outcome of a saved battle on its run, unless that already happened. a run only
+/// moves past a turn by recording its battle, so a run on a later turn already has it.
+async fn apply_record<S: MatchmakingStore>(store: &S, table_name: &str, record: &BattleRecord) -> Result<(), Error> {
+    let record_result = || record_battle_result(store, table_name, &record.run_id, record.turn_number, record.outcome);
+    let res = match record_result().await {
+        // another invocation finishing the same battle saved the run first
+        Err(Error::ConditionFailed(_)) => record_result().await,
+        res => res,
+    };
+    match res {
+        Ok(_) => Ok(()),
+        Err(Error::TurnMismatch { current, requested, .. }) if current > requested => Ok(()),
+        // the battle finished the run, or it was abandoned while matchmaking. nothing is left to record
+        Err(Error::RunNotActive { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// saves the battle for every run that took part and records the outcome on their runs. every
+/// step can be repeated, so a task that failed halfway finishes the battle when it is retried.
+async fn settle_battle<S: MatchmakingStore>(store: &S, table_name: &str, record: &BattleRecord) -> Result<(), Error> {
+    let other = mirrored(record);
+    // the records are written before the runs advance, so once a client sees
+    // the next turn on its run, the battle for the previous turn can be fetched.
+    save_battle_record(store, table_name, record).await?;
+    if let Some(other) = &other {
+        save_battle_record(store, table_name, other).await?;
+    }
+    // record both before reporting errors so one failure does not prevent the other run from advancing
+    let res1 = apply_record(store, table_name, record).await;
+    let res2 = match &other {
+        Some(other) => apply_record(store, table_name, other).await,
+        None => Ok(()),
+    };
+    res1?;
+    res2
+}
+
+/// fights `pending` against the run it was paired with, on the side it was paired on
+async fn fight_pending_run<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pending: &PendingBattle,
+    opponent: &MatchmakingSkey,
+) -> Result<Fight, Error> {
+    let turn_number = pending.turn_number;
+    let own = load_snapshot(store, table_name, &pending.player.run_id, turn_number).await?;
+    let other = load_snapshot(store, table_name, &opponent.run_id, turn_number).await?;
+    // both sides of a pairing fight the exact same battle, whichever of them gets to it
+    let (replay, result) = match pending.side {
+        Side::Player1 => fight(&own, &other, pending.seed)?,
+        Side::Player2 => fight(&other, &own, pending.seed)?,
+    };
+    let outcome = outcome_for(result.outcome, pending.side);
+    let record = BattleRecord {
+        run_id: pending.player.run_id.clone(),
+        turn_number,
+        opponent: OpponentRef::Run(opponent.run_id.clone()),
+        team: own,
+        opponent_team: other,
+        seed: pending.seed,
+        side: pending.side,
+        outcome,
+        events: result.events,
+        replay: Some(replay),
+    };
+    settle_battle(store, table_name, &record).await?;
+    Ok(Fight { opponent: Opponent::Run(opponent.clone()), seed: pending.seed, outcome })
+}
+
+/// fights the battle `player`'s entry was claimed for and records it on every run that took part.
+/// every step can be repeated, so it is safe to call again after a failure, and by either side.
+/// None if there is nothing left to fight
+pub(crate) async fn finish_pending<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    player: &MatchmakingSkey,
+    turn_number: u32,
+    max_ghost_turn_distance: u32,
+) -> Result<Option<Fight>, Error> {
+    let Some(pending) = get_pending(store, table_name, player, turn_number).await? else {
+        return Ok(None);
+    };
+    // an earlier attempt fought it, but failed before every run had the result
+    if let Some(BattleStatus::Complete(record)) = get_battle_result(store, table_name, &player.run_id, turn_number).await? {
+        settle_battle(store, table_name, &record).await?;
+        delete_pending(store, table_name, &pending).await?;
+        return Ok(None);
+    }
+    let fight = match &pending.opponent {
+        Some(opponent) => fight_pending_run(store, table_name, &pending, opponent).await?,
+        None => fight_ghost(store, table_name, &player.pool, turn_number, &player.run_id, pending.seed, max_ghost_turn_distance).await?,
+    };
+    delete_pending(store, table_name, &pending).await?;
+    Ok(Some(fight))
+}
+
+/// the async matchmaking step that runs after `end_turn`: find an opponent (or a ghost),
+/// simulate the battle and record the result on every run that took part.
+/// also reports how many retries matchmaking needed.
+pub async fn run_matchmaking_task<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    request: AsyncMatchmakingRequest,
+    config: WorkerConfig,
+) -> Result<Retried<MatchmakingTaskResult>, Error> {
+    let turn_number = request.turn_number;
+    let player1 = request.skey.clone();
+    let res = match config.slices {
+        Some(slices) => attempt_matchmaking_sliced(store, table_name, request, config.window, &config.retry, config.shard_count, slices).await?,
+        None => attempt_matchmaking(store, table_name, request, config.window, &config.retry, config.shard_count, list_matchmaking_entries).await?,
+    };
+    let distance = config.max_ghost_turn_distance;
+    // our entry is gone, so somebody else matched us. if that was an earlier attempt of this task
+    // which failed before the battle was over, the battle it paired us for is finished here
+    let resume = async || -> Result<_, Error> {
+        Ok(match finish_pending(store, table_name, &player1, turn_number, distance).await? {
+            Some(fight) => MatchmakingTaskResult::Resumed { fight },
+            None => MatchmakingTaskResult::Dropped,
+        })
+    };
+    let value = match res.value {
+        MatchmakingResult::CanDrop => resume().await?,
+        MatchmakingResult::Matched(_, rating_distance) => {
+            match finish_pending(store, table_name, &player1, turn_number, distance).await? {
+                Some(fight) => MatchmakingTaskResult::Matched { fight, rating_distance },
+                // the other side got to the battle first
+                None => MatchmakingTaskResult::Dropped,
+            }
+        }
+        MatchmakingResult::FakeSimulate(reason) => {
+            // our entry is still in the pool. claim it first, so nobody else matches against a
+            // player that is about to fight a ghost
+            match claim_for_ghost(store, table_name, &player1, turn_number, now_secs()).await {
+                Ok(_) => match finish_pending(store, table_name, &player1, turn_number, distance).await? {
+                    Some(fight) => MatchmakingTaskResult::Ghost { fight, reason },
+                    None => MatchmakingTaskResult::Dropped,
+                },
+                // matched in the meantime, the battle belongs to whoever matched us
+                Err(Error::ConditionFailed(_)) => resume().await?,
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    Ok(Retried { value, retries: res.retries })
+}
+
+/// fights the team `run_id` submitted for `turn_number` against a random ghost of its `pool`
+/// (or an empty team if there are none) and records the result. the caller is responsible for the matchmaking entry.
+pub async fn fight_ghost<S: MatchmakingStore>(
+    store: &S,
+    table_name: &str,
+    pool: &Pool,
+    turn_number: u32,
+    run_id: &str,
+    seed: u64,
+    max_ghost_turn_distance: u32,
+) -> Result<Fight, Error> {
+    let snapshot1 = load_snapshot(store, table_name, run_id, turn_number).await?;
+    let ghost = sample_ghost(store, table_name, pool, turn_number, run_id, max_ghost_turn_distance).await?;
+    let (opponent_ref, snapshot2) = match &ghost {
+        Some(ghost) => {
+            let opponent_ref = OpponentRef::Ghost { run_id: ghost.run_id.clone(), turn_number: ghost.turn_number };
+            (opponent_ref, ghost.team.clone())
+        }
+        None => {
+            let empty = serde_json::to_string(&TeamSnapshot::default()).map_err(|e| Error::InvalidTeam(Box::new(e)))?;
+            (OpponentRef::Empty, empty)
+        }
+    };
+    let (replay, result) = fight(&snapshot1, &snapshot2, seed)?;
+    let outcome = outcome_for(result.outcome, Side::Player1);
+    let record = BattleRecord {
+        run_id: run_id.to_string(),
+        turn_number,
+        opponent: opponent_ref,
+        team: snapshot1,
+        opponent_team: snapshot2,
+        seed,
+        side: Side::Player1,
+        outcome,
+        events: result.events,
+        replay: Some(replay),
+    };
+    settle_battle(store, table_name, &record).await?;
+    let opponent = ghost.map(Opponent::Ghost).unwrap_or(Opponent::Empty);
+    Ok(Fight { opponent, seed, outcome })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{end_turn, get_run, test::{ensure_run, TC_TABLE}};
+    use shared::battle::Unit;
+
+    fn team_json(attack: i32, health: i32) -> String {
+        let team = Team { units: vec![Unit { kind: "test".to_string(), attack, health, ability: None }] };
+        serde_json::to_string(&team).expect("failed to serialize team")
+    }
+
+    tc!(matched_players_both_get_a_result; |c| {
+        ensure_run(c, "a", 1).await;
+        ensure_run(c, "b", 1).await;
+        let _ = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", team_json(1, 1), 1).await.expect("failed to end turn");
+        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", team_json(5, 5), 1).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: player1, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        match res.value {
+            MatchmakingTaskResult::Matched { fight, .. } => {
+                assert!(matches!(fight.opponent, Opponent::Run(ref x) if x.run_id == "b"));
+                assert_eq!(fight.outcome, BattleOutcome::Win);
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        let a = get_run(c, TC_TABLE, "a").await.expect("failed to get run").expect("run a missing");
+        let b = get_run(c, TC_TABLE, "b").await.expect("failed to get run").expect("run b missing");
+        assert_eq!((a.turn_number, a.wins), (2, 1));
+        assert_eq!((b.turn_number, b.wins, b.lives), (2, 0, crate::run::STARTING_LIVES - 1));
+        match get_battle_result(c, TC_TABLE, "b", 1).await.expect("lookup failed") {
+            Some(BattleStatus::Complete(record)) => {
+                assert_eq!(record.opponent, OpponentRef::Run("a".to_string()));
+                assert_eq!(record.outcome, BattleOutcome::Loss);
+                assert_eq!(record.side, Side::Player2);
+                assert!(!record.events.is_empty());
+            }
+            e => panic!("unexpected battle status: {:?}", e),
+        }
+    });
+
+    tc!(a_battle_interrupted_after_matching_is_finished_on_retry; |c| {
+        ensure_run(c, "a", 1).await;
+        ensure_run(c, "b", 1).await;
+        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", team_json(1, 1), 1).await.expect("failed to end turn");
+        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", team_json(5, 5), 1).await.expect("failed to end turn");
+        // an earlier attempt matched the two and saved its battle, then failed before the runs had the result
+        crate::attempt_match(c, TC_TABLE, 1, player1.clone(), player2).await;
+        let (replay, result) = fight(&team_json(5, 5), &team_json(1, 1), 7).expect("failed to fight");
+        let record = BattleRecord {
+            run_id: "a".to_string(),
+            turn_number: 1,
+            opponent: OpponentRef::Run("b".to_string()),
+            team: team_json(5, 5),
+            opponent_team: team_json(1, 1),
+            seed: 7,
+            side: Side::Player1,
+            outcome: outcome_for(result.outcome, Side::Player1),
+            events: result.events,
+            replay: Some(replay),
+        };
+        save_battle_record(c, TC_TABLE, &record).await.expect("failed to save record");
+
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: player1, rating: 100 };
+        for _ in 0..2 {
+            let res = run_matchmaking_task(c, TC_TABLE, request.clone(), WorkerConfig::default()).await.expect("task failed");
+            assert!(matches!(res.value, MatchmakingTaskResult::Dropped), "{:?}", res.value);
+        }
+        let a = get_run(c, TC_TABLE, "a").await.expect("failed to get run").expect("run a missing");
+        let b = get_run(c, TC_TABLE, "b").await.expect("failed to get run").expect("run b missing");
+        assert_eq!((a.turn_number, a.wins, b.turn_number, b.wins), (2, 1, 2, 0));
+        let res = get_battle_result(c, TC_TABLE, "b", 1).await.expect("lookup failed");
+        assert!(matches!(res, Some(BattleStatus::Complete(ref x)) if x.outcome == BattleOutcome::Loss && x.side == Side::Player2), "{:?}", res);
+    });
+
+    tc!(a_battle_claimed_but_not_fought_is_fought_on_retry; |c| {
+        ensure_run(c, "a", 1).await;
+        ensure_run(c, "b", 1).await;
+        let player2 = end_turn(c, TC_TABLE, 1, "b".to_string(), "b", team_json(1, 1), 1).await.expect("failed to end turn");
+        let player1 = end_turn(c, TC_TABLE, 1, "a".to_string(), "a", team_json(5, 5), 1).await.expect("failed to end turn");
+        // an earlier attempt matched the two, then failed before fighting
+        assert!(matches!(crate::attempt_match(c, TC_TABLE, 1, player1, player2.clone()).await, crate::MatchResult::Matched(..)));
+        // either side finishes it, here the one that was matched against
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: player2.clone(), rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request.clone(), WorkerConfig::default()).await.expect("task failed");
+        match res.value {
+            MatchmakingTaskResult::Resumed { fight } => {
+                assert!(matches!(fight.opponent, Opponent::Run(ref x) if x.run_id == "a"));
+                assert_eq!(fight.outcome, BattleOutcome::Loss);
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        let a = get_run(c, TC_TABLE, "a").await.expect("failed to get run").expect("run a missing");
+        let b = get_run(c, TC_TABLE, "b").await.expect("failed to get run").expect("run b missing");
+        assert_eq!((a.turn_number, a.wins, b.turn_number, b.wins), (2, 1, 2, 0));
+        let res = get_battle_result(c, TC_TABLE, "a", 1).await.expect("lookup failed");
+        assert!(matches!(res, Some(BattleStatus::Complete(ref x)) if x.side == Side::Player1 && x.outcome == BattleOutcome::Win), "{:?}", res);
+        assert!(get_pending(c, TC_TABLE, &player2, 1).await.expect("lookup failed").is_none());
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Dropped), "{:?}", res.value);
+
+        // the same for an entry claimed to fight a ghost
+        ensure_run(c, "c", 1).await;
+        let player = end_turn(c, TC_TABLE, 1, "c".to_string(), "c", team_json(1, 1), 1).await.expect("failed to end turn");
+        claim_for_ghost(c, TC_TABLE, &player, 1, now_secs()).await.expect("failed to claim");
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: player, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Resumed { fight: Fight { opponent: Opponent::Ghost(_) | Opponent::Empty, .. } }), "{:?}", res.value);
+        let run = get_run(c, TC_TABLE, "c").await.expect("failed to get run").expect("run c missing");
+        assert_eq!(run.turn_number, 2);
+    });
+
+    tc!(lone_player_fights_a_ghost; |c| {
+        // a ghost from a previous run on a nearby turn
+        crate::ghost::archive_ghost(c, TC_TABLE, &Ghost { pool: Pool::public(1), turn_number: 2, run_id: "old".to_string(), rating: 100, team: team_json(9, 9) })
+            .await.expect("failed to archive ghost");
+        ensure_run(c, "a", 3).await;
+        let player1 = end_turn(c, TC_TABLE, 3, "a".to_string(), "a", team_json(1, 1), 1).await.expect("failed to end turn");
+        let request = AsyncMatchmakingRequest { turn_number: 3, skey: player1, rating: 100 };
+        let status = get_battle_result(c, TC_TABLE, "a", 3).await.expect("lookup failed");
+        assert_eq!(status, Some(BattleStatus::Pending));
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        match res.value {
+            MatchmakingTaskResult::Ghost { fight, reason } => {
+                assert!(reason.is_none());
+                assert!(matches!(fight.opponent, Opponent::Ghost(ref x) if x.run_id == "old"));
+                assert_eq!(fight.outcome, BattleOutcome::Loss);
+            }
+            e => panic!("unexpected result: {:?}", e),
+        }
+        // our entry was consumed, so nobody else can match against us this turn
+        let entries = list_matchmaking_entries(c, TC_TABLE, Pool::public(1), 3, 0).await.expect("failed to list");
+        assert!(entries.is_empty());
+    });
+
+    tc!(runs_of_different_content_versions_are_not_matched; |c| {
+        ensure_run(c, "old", 1).await;
+        let mut run = crate::Run::new("new".to_string(), "new".to_string());
+        run.content_version = 2;
+        c.put(TC_TABLE, run.to_item()).await.expect("failed to write run");
+        let ghost = Ghost { pool: Pool::public(1), turn_number: 1, run_id: "ghost".to_string(), rating: 100, team: team_json(9, 9) };
+        crate::ghost::archive_ghost(c, TC_TABLE, &ghost).await.expect("failed to archive ghost");
+
+        let new = end_turn(c, TC_TABLE, 1, "new".to_string(), "new", team_json(1, 1), 1).await.expect("failed to end turn");
+        let old = end_turn(c, TC_TABLE, 1, "old".to_string(), "old", team_json(1, 1), 1).await.expect("failed to end turn");
+        assert_eq!((old.pool.content_version, new.pool.content_version), (1, 2));
+        // the old run finishes its turn against a ghost of its own version
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: old, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Ghost { fight: Fight { opponent: Opponent::Ghost(ref x), .. }, .. } if x.run_id == "ghost"));
+        // the ghosts of version 1 are out of reach for the new run, and its own ghost does not count
+        let request = AsyncMatchmakingRequest { turn_number: 1, skey: new, rating: 100 };
+        let res = run_matchmaking_task(c, TC_TABLE, request, WorkerConfig::default()).await.expect("task failed");
+        assert!(matches!(res.value, MatchmakingTaskResult::Ghost { fight: Fight { opponent: Opponent::Empty, .. }, .. }), "{:?}", res.value);
+    });
+}
@@ -0,0 +1,146 @@
+//! stateless session tokens. a token is `{player_id}.{expires_at}.{signature}`, where the signature
+//! is the hex encoded hmac-sha256 of `{player_id}.{expires_at}` under the session secret.
+
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// how long a guest session stays valid
+pub const SESSION_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub player_id: String,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// no `Authorization: Bearer` header
+    Missing,
+    /// not in the token format at all
+    Malformed,
+    /// the signature does not match, eg. the token was tampered with or the secret changed
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing bearer token"),
+            AuthError::Malformed => write!(f, "malformed session token"),
+            AuthError::BadSignature => write!(f, "invalid session token"),
+            AuthError::Expired => write!(f, "session expired"),
+        }
+    }
+}
+
+/// signs and verifies session tokens
+#[derive(Clone)]
+pub struct SessionKey {
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+impl SessionKey {
+    pub fn new(secret: &[u8]) -> Self {
+        Self { secret: secret.to_vec() }
+    }
+
+    /// reads the secret the deployment put into the environment. None if it is not set
+    pub fn from_env() -> Option<Self> {
+        let secret = std::env::var(shared::SESSION_SECRET_ENV).ok().filter(|x| !x.is_empty())?;
+        Some(Self::new(secret.as_bytes()))
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        // hmac accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac key of any length");
+        mac.update(message);
+        mac
+    }
+
+    fn sign(&self, message: &[u8]) -> [u8; 32] {
+        self.mac(message).finalize().into_bytes().into()
+    }
+
+    /// `player_id` must not contain a `.`
+    pub fn issue(&self, player_id: &str, expires_at: u64) -> String {
+        let payload = format!("{}.{}", player_id, expires_at);
+        let signature = to_hex(&self.sign(payload.as_bytes()));
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify(&self, token: &str, now: u64) -> Result<Session, AuthError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+        let (player_id, expires_at) = payload.split_once('.').ok_or(AuthError::Malformed)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| AuthError::Malformed)?;
+        let signature = from_hex(signature).ok_or(AuthError::Malformed)?;
+        // constant time, so the time taken does not tell how much of the signature matched
+        self.mac(payload.as_bytes()).verify_slice(&signature).map_err(|_| AuthError::BadSignature)?;
+        if expires_at <= now {
+            return Err(AuthError::Expired);
+        }
+        Ok(Session { player_id: player_id.to_string(), expires_at })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// the token of an `Authorization: Bearer {token}` header
+pub fn bearer_token(authorization: Option<&str>) -> Result<&str, AuthError> {
+    let value = authorization.ok_or(AuthError::Missing)?;
+    let (scheme, token) = value.split_once(' ').ok_or(AuthError::Malformed)?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(AuthError::Malformed);
+    }
+    Ok(token.trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // test case 2
+        let key = SessionKey::new(b"Jefe");
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(to_hex(&key.sign(b"what do ya want for nothing?")), expected);
+        // test case 6, a key longer than the block size
+        let key = SessionKey::new(&[0xaa; 131]);
+        let expected = "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54";
+        assert_eq!(to_hex(&key.sign(b"Test Using Larger Than Block-Size Key - Hash Key First")), expected);
+    }
+
+    #[test]
+    fn tokens_are_verified() {
+        let key = SessionKey::new(b"secret");
+        let token = key.issue("guest_abc", 100);
+        assert_eq!(key.verify(&token, 50), Ok(Session { player_id: "guest_abc".to_string(), expires_at: 100 }));
+        assert_eq!(key.verify(&token, 100), Err(AuthError::Expired));
+        assert_eq!(SessionKey::new(b"other").verify(&token, 50), Err(AuthError::BadSignature));
+        let forged = token.replacen("guest_abc", "guest_abd", 1);
+        assert_eq!(key.verify(&forged, 50), Err(AuthError::BadSignature));
+        assert_eq!(key.verify(&token[..token.len() - 2], 50), Err(AuthError::BadSignature));
+        assert_eq!(key.verify("nope", 50), Err(AuthError::Malformed));
+        assert_eq!(bearer_token(Some(&format!("Bearer {}", token))), Ok(token.as_str()));
+        assert_eq!(bearer_token(None), Err(AuthError::Missing));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use shared::api::ErrorBody;
+
+/// the parts of a lambda function url event that the router needs.
+/// see: https://docs.aws.amazon.com/lambda/latest/dg/urls-invocation.html#urls-payloads
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// header names are lowercase, as delivered by function urls
+    pub headers: HashMap<String, String>,
+    /// parsed from `rawQueryString`. values are not percent-decoded
+    pub query: HashMap<String, String>,
+    pub body: Option<Value>,
+}
+
+impl HttpRequest {
+    pub fn from_event(event: &Value) -> Result<Self, HttpResponse> {
+        let http = &event["requestContext"]["http"];
+        let method = http["method"].as_str()
+            .ok_or_else(|| HttpResponse::error(400, "missing requestContext.http.method"))?
+            .to_uppercase();
+        let path = event["rawPath"].as_str().or(http["path"].as_str()).unwrap_or("/").to_string();
+        let headers = event["headers"].as_object()
+            .map(|x| x.iter().filter_map(|(k, v)| Some((k.to_lowercase(), v.as_str()?.to_string()))).collect())
+            .unwrap_or_default();
+        let query = event["rawQueryString"].as_str().unwrap_or_default()
+            .split('&')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.split_once('=').unwrap_or((x, "")))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let body = match event["body"].as_str() {
+            None | Some("") => None,
+            Some(_) if event["isBase64Encoded"].as_bool() == Some(true) => {
+                return Err(HttpResponse::error(415, "request body must be sent with content-type application/json"));
+            }
+            Some(body) => {
+                let value = serde_json::from_str(body)
+                    .map_err(|e| HttpResponse::error(400, &format!("invalid json body: {}", e)))?;
+                Some(value)
+            }
+        };
+        Ok(Self { method, path, headers, query, body })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|x| x.as_str())
+    }
+
+    /// the non-empty path segments, eg: `/runs/abc/` => `["runs", "abc"]`
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|x| !x.is_empty()).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: Value) -> Self {
+        Self { status, body }
+    }
+
+    /// a response with one of the `shared::api` types as its body
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        match serde_json::to_value(body) {
+            Ok(body) => Self::new(status, body),
+            Err(e) => Self::error(500, &format!("failed to serialize response: {}", e)),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::new(status, json!(ErrorBody { error: message.to_string(), rejection: None }))
+    }
+
+    /// the response shape expected by function urls
+    pub fn into_event(self) -> Value {
+        json!({
+            "statusCode": self.status,
+            "headers": { "content-type": "application/json" },
+            "body": self.body.to_string(),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use logic::{MatchmakingStore, RetryPolicy};
+use serde_json::Value;
+
+pub mod auth;
+pub mod http;
+pub mod local;
+pub mod queue;
+pub mod router;
+pub mod worker;
+
+use auth::SessionKey;
+use http::HttpRequest;
+use queue::{LambdaQueue, MatchmakingQueue};
+
+/// created once per cold start and shared by every invocation
+pub struct State<S = Client, Q = LambdaQueue> {
+    pub store: S,
+    pub table_name: String,
+    pub queue: Q,
+    /// applied to store calls made while handling a request or matchmaking task
+    pub retry: RetryPolicy,
+    /// how many shards new matchmaking entries are spread over
+    pub shard_count: u32,
+    pub sessions: SessionKey,
+    /// how long new lobbies can be joined for, at most
+    pub lobby_ttl_secs: u64,
+}
+
+impl State {
+    pub async fn new() -> Self {
+        let table_name = std::env::var(shared::TABLE_NAME_ENV).unwrap_or_else(|_| shared::DEFAULT_TABLE_NAME.to_string());
+        let shard_count = std::env::var(shared::MATCHMAKING_SHARDS_ENV).ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(shared::DEFAULT_MATCHMAKING_SHARDS);
+        let lobby_ttl_secs = std::env::var(shared::LOBBY_TTL_SECS_ENV).ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(shared::DEFAULT_LOBBY_TTL_SECS);
+        Self {
+            store: logic::get_client().await,
+            table_name,
+            queue: LambdaQueue::new().await,
+            retry: RetryPolicy::default(),
+            shard_count,
+            sessions: SessionKey::from_env()
+                .unwrap_or_else(|| panic!("{} must be set to sign session tokens", shared::SESSION_SECRET_ENV)),
+            lobby_ttl_secs,
+        }
+    }
+}
+
+/// handles every kind of event the function is invoked with. the local server goes through here too
+pub async fn handle_event<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, event: Value) -> Result<Value, Error> {
+    if let Some(task) = event.get(worker::MATCHMAKING_EVENT_KEY) {
+        return worker::handle(state, task).await;
+    }
+    if event.get(shared::REAP_EVENT_KEY).is_some() {
+        return worker::handle_reap(state).await;
+    }
+    let response = match HttpRequest::from_event(&event) {
+        Ok(request) => router::handle(state, request).await,
+        Err(response) => response,
+    };
+    Ok(response.into_event())
+}
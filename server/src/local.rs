@@ -0,0 +1,215 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use lambda_runtime::Error;
+use logic::{AsyncMatchmakingRequest, MatchmakingStore, MemoryStore, RetryPolicy};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{auth::SessionKey, handle_event, queue::MatchmakingQueue, worker, State};
+
+/// first argument that switches the binary to the local server
+pub const LOCAL_FLAG: &str = "--local";
+pub const DEFAULT_ADDR: &str = "127.0.0.1:3000";
+/// function urls reject larger payloads too
+const MAX_BODY_BYTES: usize = 6 * 1024 * 1024;
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// runs matchmaking in the background of the same process, in place of an async lambda invocation
+pub struct LocalQueue(mpsc::UnboundedSender<AsyncMatchmakingRequest>);
+
+impl MatchmakingQueue for LocalQueue {
+    async fn enqueue(&self, request: &AsyncMatchmakingRequest) -> Result<(), String> {
+        self.0.send(request.clone()).map_err(|_| "local matchmaking queue is closed".to_string())
+    }
+}
+
+pub async fn run(addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!("serving on http://{}", listener.local_addr()?);
+    serve(listener).await
+}
+
+/// serves the lambda's routes over plain http/1.1, one request per connection.
+/// every request is turned into a function url event and passed to the same handler the lambda uses.
+pub async fn serve(listener: TcpListener) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(State {
+        store: MemoryStore::new(&[shared::DEFAULT_TABLE_NAME]),
+        table_name: shared::DEFAULT_TABLE_NAME.to_string(),
+        queue: LocalQueue(sender),
+        retry: RetryPolicy::default(),
+        shard_count: shared::DEFAULT_MATCHMAKING_SHARDS,
+        // tokens only need to outlive the process, unless a secret is given to keep them across restarts
+        sessions: SessionKey::from_env().unwrap_or_else(|| SessionKey::new(logic::get_random_string(32).as_bytes())),
+        lobby_ttl_secs: std::env::var(shared::LOBBY_TTL_SECS_ENV).ok().and_then(|x| x.parse().ok()).unwrap_or(shared::DEFAULT_LOBBY_TTL_SECS),
+    });
+    tokio::spawn(process_queue(Arc::clone(&state), receiver));
+    tokio::spawn(reap_periodically(Arc::clone(&state)));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&state, stream).await {
+                eprintln!("failed to handle connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn process_queue<S: MatchmakingStore + Send + Sync + 'static>(
+    state: Arc<State<S, LocalQueue>>,
+    mut receiver: mpsc::UnboundedReceiver<AsyncMatchmakingRequest>,
+) {
+    while let Some(request) = receiver.recv().await {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            // lambda would retry a failed task. here the reaper picks the entry up eventually
+            if let Err(e) = handle_event(&state, worker::to_event(&request)).await {
+                eprintln!("matchmaking task failed: {}", e);
+            }
+        });
+    }
+}
+
+/// stands in for the schedule that sends reap events to the lambda
+async fn reap_periodically<S: MatchmakingStore>(state: Arc<State<S, LocalQueue>>) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = handle_event(&state, json!({ shared::REAP_EVENT_KEY: {} })).await {
+            eprintln!("reaper failed: {}", e);
+        }
+    }
+}
+
+async fn handle_connection<S: MatchmakingStore>(state: &State<S, LocalQueue>, stream: TcpStream) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(format!("malformed request line '{}'", line.trim_end()).into());
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length: usize = headers.get("content-length").and_then(|x| x.parse().ok()).unwrap_or(0);
+    let (status, body) = if content_length > MAX_BODY_BYTES {
+        (413, json!({ "error": "request body too large" }).to_string())
+    } else if method == "OPTIONS" {
+        // cors preflight. function urls answer these before the handler is invoked
+        (204, String::new())
+    } else {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let event = json!({
+            "rawPath": path,
+            "rawQueryString": query,
+            "requestContext": { "http": { "method": method, "path": path } },
+            "headers": headers,
+            "body": String::from_utf8_lossy(&body),
+            "isBase64Encoded": false,
+        });
+        let response = handle_event(state, event).await?;
+        let status = response["statusCode"].as_u64().and_then(|x| u16::try_from(x).ok()).unwrap_or(500);
+        (status, response["body"].as_str().unwrap_or_default().to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\naccess-control-allow-origin: *\r\n\
+         access-control-allow-methods: GET, POST, OPTIONS\r\naccess-control-allow-headers: *\r\nconnection: close\r\n\r\n{}",
+        status, reason_phrase(status), body.len(), body,
+    );
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+
+    async fn request(addr: std::net::SocketAddr, token: &str, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+        let body = body.map(|x| x.to_string()).unwrap_or_default();
+        let mut stream = TcpStream::connect(addr).await.expect("failed to connect");
+        let request = format!(
+            "{} {} HTTP/1.1\r\nhost: localhost\r\nauthorization: Bearer {}\r\ncontent-length: {}\r\n\r\n{}",
+            method, path, token, body.len(), body,
+        );
+        stream.write_all(request.as_bytes()).await.expect("failed to send");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.expect("failed to read");
+        let (head, body) = response.split_once("\r\n\r\n").expect("malformed response");
+        let status = head.split_whitespace().nth(1).and_then(|x| x.parse().ok()).expect("missing status");
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn serves_routes_and_matchmaking_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind");
+        let addr = listener.local_addr().expect("no local addr");
+        tokio::spawn(serve(listener));
+
+        let (status, body) = request(addr, "", "POST", "/sessions/guest", None).await;
+        assert_eq!(status, 201);
+        let token = body["token"].as_str().expect("missing token").to_string();
+        let (status, body) = request(addr, &token, "POST", "/runs", None).await;
+        assert_eq!(status, 201);
+        let run_id = body["run_id"].as_str().expect("missing run_id").to_string();
+        let end_turn = json!({ "turn_number": 1, "team": { "units": [] } });
+        let (status, _) = request(addr, &token, "POST", &format!("/runs/{}/end-turn", run_id), Some(end_turn)).await;
+        assert_eq!(status, 202);
+        // nobody else is playing, so the queued task fights an empty team in the background
+        let battle_path = format!("/runs/{}/battle/1?poll=1", run_id);
+        for _ in 0..100 {
+            let (status, body) = request(addr, &token, "GET", &battle_path, None).await;
+            if status == 200 {
+                assert_eq!(body["turn_number"], 1);
+                return;
+            }
+            assert_eq!(status, 202);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("battle was never fought");
+    }
+
+    #[test]
+    fn auth_and_lobby_statuses_have_reason_phrases() {
+        assert_eq!((reason_phrase(401), reason_phrase(403), reason_phrase(410)), ("Unauthorized", "Forbidden", "Gone"));
+        assert_eq!(reason_phrase(500), "Internal Server Error");
+    }
+}
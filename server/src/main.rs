@@ -1,19 +1,18 @@
 use std::sync::Arc;
 
 use lambda_runtime::{service_fn, LambdaEvent, Error};
-use serde_json::{json, Value};
-
-pub struct State {
-
-}
-
-impl State {
-    fn new() -> Self { Self {} }
-}
+use serde_json::Value;
+use server::{handle_event, local, State};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let state = Arc::new(State::new());
+    // `server --local [addr]` serves plain http with an in-memory store instead of running as a lambda
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|x| x.as_str()) == Some(local::LOCAL_FLAG) {
+        let addr = args.get(1).map(|x| x.as_str()).unwrap_or(local::DEFAULT_ADDR);
+        return local::run(addr).await;
+    }
+    let state = Arc::new(State::new().await);
     let func = service_fn(move |event| {
         // // Clone Arc to pass the handler to each request
         let state = Arc::clone(&state);
@@ -25,10 +24,8 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
-async fn entrypoint(_state: Arc<State>, event: LambdaEvent<Value>) -> Result<Value, Error> {
+async fn entrypoint(state: Arc<State>, event: LambdaEvent<Value>) -> Result<Value, Error> {
     let (event, _context) = event.into_parts();
-    let first_name = event["firstName"].as_str().unwrap_or("world");
-    println!("{:?}", event);
-
-    Ok(json!({ "message": format!("Hello, {}!", first_name) }))
+    // boxed, otherwise computing the layout of the lambda's future overflows the compiler's query depth
+    Box::pin(handle_event(&state, event)).await
 }
@@ -0,0 +1,39 @@
+use aws_sdk_lambda::{primitives::Blob, types::InvocationType};
+use logic::AsyncMatchmakingRequest;
+
+use crate::worker;
+
+/// hands an `AsyncMatchmakingRequest` off to be processed after the response
+/// to the player has been sent.
+pub trait MatchmakingQueue: Sync {
+    fn enqueue(&self, request: &AsyncMatchmakingRequest) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// enqueues by invoking a lambda function asynchronously (fire and forget).
+/// lambda retries failed async invocations on its own.
+pub struct LambdaQueue {
+    pub client: aws_sdk_lambda::Client,
+    pub function_name: String,
+}
+
+impl LambdaQueue {
+    /// invokes the currently running function, so the same deployment serves both event types
+    #[allow(deprecated)]
+    pub async fn new() -> Self {
+        let config = aws_config::load_from_env().await;
+        let function_name = std::env::var("AWS_LAMBDA_FUNCTION_NAME").unwrap_or_else(|_| "mygamething".to_string());
+        Self { client: aws_sdk_lambda::Client::new(&config), function_name }
+    }
+}
+
+impl MatchmakingQueue for LambdaQueue {
+    async fn enqueue(&self, request: &AsyncMatchmakingRequest) -> Result<(), String> {
+        let payload = worker::to_event(request).to_string();
+        self.client.invoke()
+            .function_name(&self.function_name)
+            .invocation_type(InvocationType::Event)
+            .payload(Blob::new(payload))
+            .send().await.map_err(|e| format!("failed to enqueue matchmaking: {:?}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,594 @@
+use logic::{AsyncMatchmakingRequest, BattleRecord, BattleStatus, Error, MatchmakingStore, OpponentRef, Run};
+use shared::api::{
+    Battle, BattleResponse, CreateLobbyRequest, EndTurnRequest, EndTurnResponse, ErrorBody, LeaderboardPeriod, LeaderboardResponse,
+    LobbyResponse, Opponent, RunResponse, SessionResponse, ShopActionsRequest, TeamSnapshot, TEAM_SNAPSHOT_VERSION,
+};
+
+use crate::{
+    auth::{bearer_token, Session, SESSION_TTL_SECS},
+    http::{HttpRequest, HttpResponse},
+    queue::MatchmakingQueue,
+    State,
+};
+
+/// lets clients retry ending a turn without ending it twice
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// how many entries `GET /leaderboards/{period}` returns without a `limit` query parameter
+pub const DEFAULT_LEADERBOARD_LIMIT: u32 = 10;
+
+/// teams are stored as the json of their snapshot
+fn snapshot(team: &str) -> Result<TeamSnapshot, HttpResponse> {
+    serde_json::from_str(team).map_err(|e| {
+        eprintln!("stored team is not a valid snapshot: {}", e);
+        HttpResponse::error(500, "stored team is not a valid snapshot")
+    })
+}
+
+fn battle(record: &BattleRecord) -> Result<Battle, HttpResponse> {
+    let opponent = match &record.opponent {
+        OpponentRef::Run(run_id) => Opponent::Run { run_id: run_id.clone() },
+        OpponentRef::Ghost { run_id, turn_number } => Opponent::Ghost { run_id: run_id.clone(), turn_number: *turn_number },
+        OpponentRef::Empty => Opponent::Empty,
+    };
+    Ok(Battle {
+        run_id: record.run_id.clone(),
+        turn_number: record.turn_number,
+        opponent,
+        team: snapshot(&record.team)?,
+        opponent_team: snapshot(&record.opponent_team)?,
+        seed: record.seed,
+        side: record.side,
+        outcome: record.outcome.into(),
+        events: record.events.clone(),
+    })
+}
+
+/// maps a logic error to the status code the client sees. transient errors get a 503 so
+/// clients know the same request can be retried.
+pub fn error_response(e: &Error) -> HttpResponse {
+    if let Error::TeamRejected(rejection) = e {
+        let body = ErrorBody { error: e.to_string(), rejection: Some(rejection.clone()) };
+        return HttpResponse::json(400, &body);
+    }
+    let status = match e {
+        Error::RunNotFound(_) | Error::SnapshotNotFound { .. } | Error::BattleNotFound { .. } | Error::LobbyNotFound(_) => 404,
+        Error::LobbyExpired(_) => 410,
+        Error::NotRunOwner { .. } => 403,
+        Error::RunNotActive { .. } | Error::TurnMismatch { .. } | Error::TurnAlreadyEnded { .. } | Error::ConditionFailed(_) => 409,
+        Error::UnsupportedContentVersion { .. } => 409,
+        Error::InvalidTeam(_) | Error::InvalidShopAction { .. } => 400,
+        e if e.is_transient() => 503,
+        _ => 500,
+    };
+    if status >= 500 {
+        eprintln!("request failed: {}", e);
+    }
+    HttpResponse::error(status, &e.to_string())
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(request: &HttpRequest) -> Result<T, HttpResponse> {
+    if let Some(content_type) = request.header("content-type") && !content_type.contains("json") {
+        return Err(HttpResponse::error(415, "request body must be sent with content-type application/json"));
+    }
+    let body = request.body.clone().ok_or_else(|| HttpResponse::error(400, "missing request body"))?;
+    serde_json::from_value(body).map_err(|e| HttpResponse::error(400, &format!("invalid request body: {}", e)))
+}
+
+/// routes a request to its endpoint. any path that exists but is called with the
+/// wrong method gets a 405, everything else unknown gets a 404.
+/// everything under `/runs` and `/lobbies` needs a session token, and runs only work for their owner.
+/// leaderboards are public.
+pub async fn handle<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, request: HttpRequest) -> HttpResponse {
+    let segments = request.segments();
+    let res = match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["sessions", "guest"]) => Ok(guest_session(state)),
+        (_, ["sessions", "guest"]) => Err(method_not_allowed(&request)),
+        ("GET", ["leaderboards", period]) => leaderboard(state, &request, period).await,
+        ("GET", ["leaderboards", period, "runs", run_id]) => rank_of(state, period, run_id).await,
+        (_, ["leaderboards", _] | ["leaderboards", _, "runs", _]) => Err(method_not_allowed(&request)),
+        (_, ["runs", ..]) => match authenticate(state, &request) {
+            Ok(session) => handle_runs(state, &session.player_id, &request, &segments).await,
+            Err(e) => Err(e),
+        },
+        (_, ["lobbies", ..]) => match authenticate(state, &request) {
+            Ok(session) => handle_lobbies(state, &session.player_id, &request, &segments).await,
+            Err(e) => Err(e),
+        },
+        _ => Err(HttpResponse::error(404, &format!("no route for {}", request.path))),
+    };
+    res.unwrap_or_else(|e| e)
+}
+
+async fn handle_runs<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    request: &HttpRequest,
+    segments: &[&str],
+) -> Result<HttpResponse, HttpResponse> {
+    match (request.method.as_str(), segments) {
+        ("POST", ["runs"]) => create_run(state, player_id).await,
+        ("GET", ["runs", run_id]) => get_run(state, player_id, run_id).await,
+        ("POST", ["runs", run_id, "end"]) => end_run(state, player_id, run_id).await,
+        ("POST", ["runs", run_id, "end-turn"]) => end_turn(state, player_id, run_id, request).await,
+        ("GET", ["runs", run_id, "battle", turn]) => get_battle(state, player_id, run_id, turn).await,
+        ("GET", ["runs", run_id, "shop"]) => get_shop(state, player_id, run_id).await,
+        ("POST", ["runs", run_id, "shop"]) => shop_actions(state, player_id, run_id, request).await,
+        (_, ["runs"] | ["runs", _] | ["runs", _, "end" | "end-turn" | "shop"] | ["runs", _, "battle", _]) => Err(method_not_allowed(request)),
+        _ => Err(HttpResponse::error(404, &format!("no route for {}", request.path))),
+    }
+}
+
+/// anybody with the join code of a lobby can join it and see its standings
+async fn handle_lobbies<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    request: &HttpRequest,
+    segments: &[&str],
+) -> Result<HttpResponse, HttpResponse> {
+    match (request.method.as_str(), segments) {
+        ("POST", ["lobbies"]) => create_lobby(state, player_id, request).await,
+        ("GET", ["lobbies", code]) => get_lobby(state, code).await,
+        ("POST", ["lobbies", code, "join"]) => join_lobby(state, player_id, code).await,
+        (_, ["lobbies"] | ["lobbies", _] | ["lobbies", _, "join"]) => Err(method_not_allowed(request)),
+        _ => Err(HttpResponse::error(404, &format!("no route for {}", request.path))),
+    }
+}
+
+fn method_not_allowed(request: &HttpRequest) -> HttpResponse {
+    HttpResponse::error(405, &format!("method {} not allowed on {}", request.method, request.path))
+}
+
+fn authenticate<S, Q>(state: &State<S, Q>, request: &HttpRequest) -> Result<Session, HttpResponse> {
+    bearer_token(request.header("authorization"))
+        .and_then(|token| state.sessions.verify(token, logic::now_secs()))
+        .map_err(|e| HttpResponse::error(401, &e.to_string()))
+}
+
+/// signs in as a new guest player. the player only exists as long as its token is kept
+fn guest_session<S, Q>(state: &State<S, Q>) -> HttpResponse {
+    let player_id = format!("guest_{}", logic::get_random_string(16));
+    let expires_at = logic::now_secs() + SESSION_TTL_SECS;
+    let token = state.sessions.issue(&player_id, expires_at);
+    HttpResponse::json(201, &SessionResponse { player_id, token, expires_at })
+}
+
+async fn create_run<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str) -> Result<HttpResponse, HttpResponse> {
+    let run = logic::create_run(&state.store, &state.table_name, player_id).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(201, &RunResponse::from(&run)))
+}
+
+/// loads a run owned by `player_id`
+async fn load_run<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str, run_id: &str) -> Result<Run, HttpResponse> {
+    let run = logic::get_run(&state.store, &state.table_name, run_id).await
+        .map_err(|e| error_response(&e))?
+        .ok_or_else(|| HttpResponse::error(404, &format!("run '{}' does not exist", run_id)))?;
+    run.ensure_owner(player_id).map_err(|e| error_response(&e))?;
+    Ok(run)
+}
+
+async fn get_run<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str, run_id: &str) -> Result<HttpResponse, HttpResponse> {
+    let run = load_run(state, player_id, run_id).await?;
+    Ok(HttpResponse::json(200, &RunResponse::from(&run)))
+}
+
+async fn end_run<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str, run_id: &str) -> Result<HttpResponse, HttpResponse> {
+    let run = logic::end_run(&state.store, &state.table_name, run_id, player_id).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(200, &RunResponse::from(&run)))
+}
+
+async fn end_turn<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    run_id: &str,
+    request: &HttpRequest,
+) -> Result<HttpResponse, HttpResponse> {
+    let body: EndTurnRequest = parse_body(request)?;
+    if let Some(team) = body.team.as_ref().filter(|x| x.version != TEAM_SNAPSHOT_VERSION) {
+        return Err(HttpResponse::error(400, &format!("unsupported team snapshot version {}", team.version)));
+    }
+    // the board built in the shop is what fights. a team sent along only has to agree with it
+    let board = logic::shop_team(&state.store, &state.table_name, run_id, player_id, body.turn_number).await
+        .map_err(|e| error_response(&e))?;
+    let team = body.team.as_ref().unwrap_or(&board.team);
+    logic::check_team(&state.store, &state.table_name, player_id, run_id, body.turn_number, team, &board.team).await
+        .map_err(|e| error_response(&e))?;
+    let team = serde_json::to_string(&board.team).map_err(|e| HttpResponse::error(400, &format!("invalid team: {}", e)))?;
+    let idempotency_key = request.header(IDEMPOTENCY_KEY_HEADER).map(|x| x.to_string()).or(body.idempotency_key);
+    // a shop action between reading the board and ending the turn fails the end of the turn with a 409
+    let res = logic::end_turn_with_retry(
+        &state.store, &state.table_name, body.turn_number, run_id.to_string(), player_id, team,
+        Some(board.version), state.shard_count, idempotency_key, &state.retry,
+    ).await;
+    if res.retries > 0 {
+        eprintln!("end turn for run '{}' needed {} retries", run_id, res.retries);
+    }
+    let ended = res.value.map_err(|e| error_response(&e))?;
+    // the first submission already enqueued matchmaking. if that got lost the reaper picks the entry up
+    if !ended.replayed {
+        let request = AsyncMatchmakingRequest { turn_number: body.turn_number, skey: ended.skey, rating: ended.rating };
+        if let Err(e) = state.queue.enqueue(&request).await {
+            // the turn has ended regardless. the matchmaking entry stays in the pool and can still
+            // be matched by other players, so we do not fail the request over this.
+            eprintln!("{}", e);
+        }
+    }
+    let response = EndTurnResponse { run_id: run_id.to_string(), turn_number: body.turn_number, replayed: ended.replayed };
+    Ok(HttpResponse::json(202, &response))
+}
+
+async fn get_shop<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str, run_id: &str) -> Result<HttpResponse, HttpResponse> {
+    let shop = logic::get_shop(&state.store, &state.table_name, run_id, player_id).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(200, &shop.view()))
+}
+
+/// takes all of the actions, or none of them if any is not allowed
+async fn shop_actions<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    run_id: &str,
+    request: &HttpRequest,
+) -> Result<HttpResponse, HttpResponse> {
+    let body: ShopActionsRequest = parse_body(request)?;
+    let shop = logic::apply_shop_actions(&state.store, &state.table_name, run_id, player_id, body.turn_number, &body.actions).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(200, &shop.view()))
+}
+
+fn lobby_response(lobby: logic::Lobby, standings: Vec<shared::api::LobbyStanding>) -> LobbyResponse {
+    LobbyResponse { code: lobby.code, owner: lobby.owner, created_at: lobby.created_at, expires_at: lobby.expires_at, standings }
+}
+
+/// the body is optional. `ttl_secs` can only shorten the server's lobby ttl
+async fn create_lobby<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    request: &HttpRequest,
+) -> Result<HttpResponse, HttpResponse> {
+    let body: CreateLobbyRequest = match request.body {
+        Some(_) => parse_body(request)?,
+        None => CreateLobbyRequest::default(),
+    };
+    let ttl_secs = body.ttl_secs.unwrap_or(state.lobby_ttl_secs).min(state.lobby_ttl_secs);
+    if ttl_secs == 0 {
+        return Err(HttpResponse::error(400, "ttl_secs must be at least 1"));
+    }
+    let lobby = logic::create_lobby(&state.store, &state.table_name, player_id, ttl_secs, logic::now_secs()).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(201, &lobby_response(lobby, vec![])))
+}
+
+async fn get_lobby<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, code: &str) -> Result<HttpResponse, HttpResponse> {
+    let lobby = logic::get_lobby(&state.store, &state.table_name, code, logic::now_secs()).await
+        .map_err(|e| error_response(&e))?;
+    let standings = logic::lobby_standings(&state.store, &state.table_name, &lobby).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(200, &lobby_response(lobby, standings)))
+}
+
+/// 201 with the new lobby run, or 200 with the run the player joined with before
+async fn join_lobby<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, player_id: &str, code: &str) -> Result<HttpResponse, HttpResponse> {
+    let joined = logic::join_lobby(&state.store, &state.table_name, code, player_id, logic::now_secs()).await
+        .map_err(|e| error_response(&e))?;
+    let status = if joined.rejoined { 200 } else { 201 };
+    Ok(HttpResponse::json(status, &RunResponse::from(&joined.run)))
+}
+
+fn period(period: &str) -> Result<LeaderboardPeriod, HttpResponse> {
+    LeaderboardPeriod::parse(period).ok_or_else(|| HttpResponse::error(404, &format!("no leaderboard '{}'", period)))
+}
+
+/// the best runs of the current period. `?limit=` picks how many, up to `logic::leaderboard::MAX_TOP_N`
+async fn leaderboard<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    request: &HttpRequest,
+    period_str: &str,
+) -> Result<HttpResponse, HttpResponse> {
+    let period = period(period_str)?;
+    let limit = match request.query.get("limit") {
+        Some(x) => x.parse().map_err(|_| HttpResponse::error(400, &format!("invalid limit '{}'", x)))?,
+        None => DEFAULT_LEADERBOARD_LIMIT,
+    };
+    let entries = logic::top_n(&state.store, &state.table_name, period, logic::now_secs(), limit).await
+        .map_err(|e| error_response(&e))?;
+    Ok(HttpResponse::json(200, &LeaderboardResponse { period, entries }))
+}
+
+/// the rank of a run on the leaderboard of the period it finished in
+async fn rank_of<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>, period_str: &str, run_id: &str) -> Result<HttpResponse, HttpResponse> {
+    let period = period(period_str)?;
+    let entry = logic::rank_of(&state.store, &state.table_name, period, run_id).await
+        .map_err(|e| error_response(&e))?
+        .ok_or_else(|| HttpResponse::error(404, &format!("run '{}' is not on the {} leaderboard", run_id, period.as_str())))?;
+    Ok(HttpResponse::json(200, &entry))
+}
+
+/// 200 with the battle once it was fought, 202 while matchmaking is still running,
+/// and 404 if the run never ended that turn.
+async fn get_battle<S: MatchmakingStore, Q: MatchmakingQueue>(
+    state: &State<S, Q>,
+    player_id: &str,
+    run_id: &str,
+    turn: &str,
+) -> Result<HttpResponse, HttpResponse> {
+    let turn_number: u32 = turn.parse().map_err(|_| HttpResponse::error(400, &format!("invalid turn number '{}'", turn)))?;
+    load_run(state, player_id, run_id).await?;
+    let status = logic::get_battle_result(&state.store, &state.table_name, run_id, turn_number).await
+        .map_err(|e| error_response(&e))?;
+    match status {
+        Some(BattleStatus::Complete(record)) => {
+            Ok(HttpResponse::json(200, &BattleResponse::Complete(Box::new(battle(&record)?))))
+        }
+        Some(BattleStatus::Pending) => {
+            Ok(HttpResponse::json(202, &BattleResponse::Pending { run_id: run_id.to_string(), turn_number }))
+        }
+        None => Err(HttpResponse::error(404, &format!("run '{}' has not ended turn {}", run_id, turn_number))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::SessionKey;
+    use logic::MemoryStore;
+    use serde_json::{json, Value};
+
+    const TC_TABLE: &str = "mygametable2025";
+
+    /// collects the requests instead of processing them
+    #[derive(Default)]
+    struct TestQueue(std::sync::Mutex<Vec<AsyncMatchmakingRequest>>);
+
+    impl MatchmakingQueue for TestQueue {
+        async fn enqueue(&self, request: &AsyncMatchmakingRequest) -> Result<(), String> {
+            self.0.lock().expect("poisoned").push(request.clone());
+            Ok(())
+        }
+    }
+
+    fn state() -> State<MemoryStore, TestQueue> {
+        State { store: MemoryStore::new(&[TC_TABLE]), table_name: TC_TABLE.to_string(), queue: TestQueue::default(), retry: logic::RetryPolicy::default(), shard_count: 2, sessions: SessionKey::new(b"test"), lobby_ttl_secs: 60 }
+    }
+
+    fn event(method: &str, path: &str, body: Option<Value>) -> HttpRequest {
+        let event = json!({
+            "rawPath": path,
+            "requestContext": { "http": { "method": method, "path": path } },
+            "headers": { "Content-Type": "application/json" },
+            "body": body.map(|x| x.to_string()),
+            "isBase64Encoded": false,
+        });
+        HttpRequest::from_event(&event).expect("failed to parse event")
+    }
+
+    /// `event` with the session token of a guest sign in
+    fn authed(token: &str, method: &str, path: &str, body: Option<Value>) -> HttpRequest {
+        let mut request = event(method, path, body);
+        request.headers.insert("authorization".to_string(), format!("Bearer {}", token));
+        request
+    }
+
+    async fn sign_in<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>) -> String {
+        let res = handle(state, event("POST", "/sessions/guest", None)).await;
+        assert_eq!(res.status, 201);
+        res.body["token"].as_str().expect("missing token").to_string()
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().expect("aa").block_on(f)
+    }
+
+    #[test]
+    fn parses_function_url_events() {
+        let request = event("post", "/runs/abc/", Some(json!({ "a": 1 })));
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.segments(), vec!["runs", "abc"]);
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.body, Some(json!({ "a": 1 })));
+        let mut event = json!({ "rawPath": "/x", "rawQueryString": "limit=5&flag", "requestContext": { "http": { "method": "GET" } } });
+        let request = HttpRequest::from_event(&event).expect("failed to parse event");
+        assert_eq!((request.query.get("limit").map(|x| x.as_str()), request.query.get("flag").map(|x| x.as_str())), (Some("5"), Some("")));
+        event["rawQueryString"] = json!("");
+        assert!(HttpRequest::from_event(&event).expect("failed to parse event").query.is_empty());
+    }
+
+    #[test]
+    fn run_lifecycle_routes() {
+        block_on(async {
+            let state = state();
+            assert_eq!(handle(&state, event("POST", "/runs", None)).await.status, 401);
+            let token = sign_in(&state).await;
+            let event = |method: &str, path: &str, body: Option<Value>| authed(&token, method, path, body);
+            let res = handle(&state, event("POST", "/runs", None)).await;
+            assert_eq!(res.status, 201);
+            let run_id = res.body["run_id"].as_str().expect("missing run_id").to_string();
+            // somebody else's run
+            let other = sign_in(&state).await;
+            assert_eq!(handle(&state, authed(&other, "GET", &format!("/runs/{}", run_id), None)).await.status, 403);
+            let body = json!({ "turn_number": 1, "team": { "units": [] } });
+            assert_eq!(handle(&state, authed(&other, "POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await.status, 403);
+
+            let res = handle(&state, event("GET", &format!("/runs/{}", run_id), None)).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["turn_number"], 1);
+
+            let body = json!({ "turn_number": 2, "team": { "units": [] } });
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await;
+            assert_eq!(res.status, 409);
+
+            let battle_path = format!("/runs/{}/battle/1", run_id);
+            assert_eq!(handle(&state, event("GET", &battle_path, None)).await.status, 404);
+
+            let body = json!({ "turn_number": 1, "team": { "units": [] }, "idempotency_key": "k1" });
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body.clone()))).await;
+            assert_eq!(res.status, 202);
+            assert_eq!(res.body["replayed"], false);
+            // a client retry is accepted without queueing matchmaking again, a different submission is not
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await;
+            assert_eq!((res.status, &res.body["replayed"]), (202, &json!(true)));
+            let body = json!({ "turn_number": 1, "team": { "units": [] }, "idempotency_key": "k2" });
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await;
+            assert_eq!(res.status, 409);
+
+            let res = handle(&state, event("GET", &battle_path, None)).await;
+            assert_eq!(res.status, 202);
+            assert_eq!(res.body["status"], "pending");
+            {
+                let queued = state.queue.0.lock().expect("poisoned");
+                assert_eq!(queued.len(), 1);
+                assert_eq!(queued[0].skey.run_id, run_id);
+            }
+
+            let res = handle(&state, event("POST", &format!("/runs/{}/end", run_id), None)).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["status"], "abandoned");
+            let res = handle(&state, event("POST", &format!("/runs/{}/end", run_id), None)).await;
+            assert_eq!(res.status, 409);
+        });
+    }
+
+    #[test]
+    fn shop_routes() {
+        block_on(async {
+            let state = state();
+            let token = sign_in(&state).await;
+            let event = |method: &str, path: &str, body: Option<Value>| authed(&token, method, path, body);
+            let res = handle(&state, event("POST", "/runs", None)).await;
+            let run_id = res.body["run_id"].as_str().expect("missing run_id").to_string();
+            let shop_path = format!("/runs/{}/shop", run_id);
+
+            let res = handle(&state, event("GET", &shop_path, None)).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["offers"].as_array().map(|x| x.len()), Some(3));
+            let kind = res.body["offers"][0]["kind"].clone();
+            let body = json!({ "turn_number": 1, "actions": [{ "action": "buy", "offer": 0, "position": 0 }, { "action": "sell", "position": 4 }] });
+            assert_eq!(handle(&state, event("POST", &shop_path, Some(body))).await.status, 400);
+            let body = json!({ "turn_number": 1, "actions": [{ "action": "buy", "offer": 0, "position": 0 }] });
+            let res = handle(&state, event("POST", &shop_path, Some(body))).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["board"]["units"][0]["kind"], kind);
+            assert_eq!(handle(&state, event("PUT", &shop_path, None)).await.status, 405);
+
+            let body = json!({ "turn_number": 1, "team": { "units": [{ "kind": "titan", "attack": 8, "health": 8 }] } });
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await;
+            assert_eq!((res.status, &res.body["rejection"]["reason"]), (400, &json!("tier_not_available")));
+            let player_id = token.split('.').next().expect("malformed token");
+            // a team the rules allow is still turned away if the shop did not build it
+            let body = json!({ "turn_number": 1, "team": { "units": [] } });
+            let res = handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await;
+            assert_eq!((res.status, &res.body["rejection"]["reason"]), (400, &json!("not_shop_board")));
+            let rejections = logic::list_rejections(&state.store, TC_TABLE, player_id).await.expect("failed to list rejections");
+            assert_eq!(rejections.len(), 2);
+
+            // without a team, the board built in the shop is submitted
+            let body = json!({ "turn_number": 1 });
+            assert_eq!(handle(&state, event("POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await.status, 202);
+            let team = logic::run::load_snapshot(&state.store, TC_TABLE, &run_id, 1).await.expect("missing snapshot");
+            let team: TeamSnapshot = serde_json::from_str(&team).expect("invalid snapshot");
+            assert_eq!(team.units.len(), 1);
+            let body = json!({ "turn_number": 1, "actions": [{ "action": "roll" }] });
+            assert_eq!(handle(&state, event("POST", &shop_path, Some(body))).await.status, 409);
+        });
+    }
+
+    #[test]
+    fn leaderboard_routes() {
+        block_on(async {
+            let state = state();
+            let token = sign_in(&state).await;
+            let res = handle(&state, authed(&token, "POST", "/runs", None)).await;
+            let run_id = res.body["run_id"].as_str().expect("missing run_id").to_string();
+            for turn_number in 1..=logic::run::STARTING_LIVES {
+                logic::record_battle_result(&state.store, TC_TABLE, &run_id, turn_number, logic::BattleOutcome::Loss).await
+                    .expect("failed to record");
+            }
+
+            let res = handle(&state, event("GET", "/leaderboards/daily", None)).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["entries"][0]["run_id"], run_id.as_str());
+            let mut request = event("GET", "/leaderboards/weekly", None);
+            request.query.insert("limit".to_string(), "0".to_string());
+            assert_eq!(handle(&state, request).await.body["entries"], json!([]));
+            let res = handle(&state, event("GET", &format!("/leaderboards/all_time/runs/{}", run_id), None)).await;
+            assert_eq!((res.status, &res.body["rank"]), (200, &json!(1)));
+            assert_eq!(handle(&state, event("GET", "/leaderboards/all_time/runs/missing", None)).await.status, 404);
+            assert_eq!(handle(&state, event("GET", "/leaderboards/yearly", None)).await.status, 404);
+            assert_eq!(handle(&state, event("POST", "/leaderboards/daily", None)).await.status, 405);
+        });
+    }
+
+    #[test]
+    fn lobby_routes() {
+        block_on(async {
+            let state = state();
+            let owner = sign_in(&state).await;
+            let friend = sign_in(&state).await;
+            let res = handle(&state, authed(&owner, "POST", "/lobbies", Some(json!({ "ttl_secs": 3600 })))).await;
+            assert_eq!(res.status, 201);
+            let code = res.body["code"].as_str().expect("missing code").to_string();
+            let expires_in = res.body["expires_at"].as_u64().expect("missing expires_at") - res.body["created_at"].as_u64().expect("missing created_at");
+            assert_eq!(expires_in, state.lobby_ttl_secs);
+
+            let join = format!("/lobbies/{}/join", code.to_lowercase());
+            let res = handle(&state, authed(&friend, "POST", &join, None)).await;
+            assert_eq!((res.status, &res.body["lobby"]), (201, &json!(code)));
+            let run_id = res.body["run_id"].clone();
+            let res = handle(&state, authed(&friend, "POST", &join, None)).await;
+            assert_eq!((res.status, &res.body["run_id"]), (200, &run_id));
+            // lobby runs are played like any other run
+            let res = handle(&state, authed(&friend, "GET", &format!("/runs/{}/shop", run_id.as_str().expect("missing run_id")), None)).await;
+            assert_eq!(res.status, 200);
+
+            let res = handle(&state, authed(&owner, "GET", &format!("/lobbies/{}", code), None)).await;
+            assert_eq!(res.status, 200);
+            assert_eq!(res.body["standings"].as_array().map(|x| x.len()), Some(1));
+            assert_eq!(res.body["standings"][0]["run_id"], run_id);
+            assert_eq!(handle(&state, authed(&owner, "GET", "/lobbies/NOPE42", None)).await.status, 404);
+            assert_eq!(handle(&state, authed(&owner, "DELETE", &format!("/lobbies/{}", code), None)).await.status, 405);
+            assert_eq!(handle(&state, event("GET", &format!("/lobbies/{}", code), None)).await.status, 401);
+        });
+    }
+
+    #[test]
+    fn unknown_routes_and_bad_bodies() {
+        block_on(async {
+            let state = state();
+            assert_eq!(handle(&state, event("GET", "/nope", None)).await.status, 404);
+            assert_eq!(handle(&state, event("GET", "/sessions/guest", None)).await.status, 405);
+            assert_eq!(handle(&state, authed("forged.1.00", "GET", "/runs/missing", None)).await.status, 401);
+            let token = sign_in(&state).await;
+            let event = |method: &str, path: &str, body: Option<Value>| authed(&token, method, path, body);
+            assert_eq!(handle(&state, event("DELETE", "/runs", None)).await.status, 405);
+            assert_eq!(handle(&state, event("GET", "/runs/missing", None)).await.status, 404);
+            assert_eq!(handle(&state, event("POST", "/runs/missing/end", None)).await.status, 404);
+            assert_eq!(handle(&state, event("GET", "/runs/missing/battle/x", None)).await.status, 400);
+            let res = handle(&state, event("POST", "/runs/missing/end-turn", Some(json!({ "turn_number": "x" })))).await;
+            assert_eq!(res.status, 400);
+            assert!(res.body["error"].is_string());
+        });
+    }
+
+    #[test]
+    fn reap_events_requeue_stale_entries() {
+        block_on(async {
+            let state = state();
+            let token = sign_in(&state).await;
+            let res = handle(&state, authed(&token, "POST", "/runs", None)).await;
+            let run_id = res.body["run_id"].as_str().expect("missing run_id").to_string();
+            let body = json!({ "turn_number": 1, "team": { "units": [] } });
+            assert_eq!(handle(&state, authed(&token, "POST", &format!("/runs/{}/end-turn", run_id), Some(body))).await.status, 202);
+            let request = state.queue.0.lock().expect("poisoned").remove(0);
+            // the queued task got lost a while ago
+            let stale = logic::matchmaking_item(1, &request.skey, request.rating, logic::now_secs() - 120);
+            state.store.put(TC_TABLE, stale).await.expect("failed to age entry");
+
+            let res = crate::handle_event(&state, json!({ shared::REAP_EVENT_KEY: {} })).await.expect("failed to reap");
+            assert_eq!((&res["scanned"], &res["requeued"], &res["failed"]), (&json!(1), &json!(1), &json!(0)));
+            let queued = state.queue.0.lock().expect("poisoned");
+            assert_eq!(queued.len(), 1);
+            assert_eq!(queued[0].skey.format(), request.skey.format());
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use std::str::FromStr;
+
+use logic::{
+    reap_stale_entries, worker::{run_matchmaking_task, MatchmakingTaskResult, Opponent, WorkerConfig}, AsyncMatchmakingRequest, MatchmakingSkey,
+    MatchmakingStore, ReaperConfig,
+};
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{queue::MatchmakingQueue, State};
+
+/// events carrying this key are matchmaking tasks rather than http requests
+pub const MATCHMAKING_EVENT_KEY: &str = "matchmaking";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchmakingEvent {
+    pub turn_number: u32,
+    /// the formatted `MatchmakingSkey`
+    pub skey: String,
+    pub rating: u32,
+}
+
+pub fn to_event(request: &AsyncMatchmakingRequest) -> Value {
+    let event = MatchmakingEvent {
+        turn_number: request.turn_number,
+        skey: request.skey.format(),
+        rating: request.rating,
+    };
+    json!({ MATCHMAKING_EVENT_KEY: event })
+}
+
+pub fn from_event(event: &Value) -> Result<AsyncMatchmakingRequest, Error> {
+    let event: MatchmakingEvent = serde_json::from_value(event.clone())
+        .map_err(|e| format!("invalid matchmaking event: {}", e))?;
+    Ok(AsyncMatchmakingRequest {
+        turn_number: event.turn_number,
+        skey: MatchmakingSkey::from_str(&event.skey)?,
+        rating: event.rating,
+    })
+}
+
+fn opponent_json(opponent: &Opponent) -> Value {
+    match opponent {
+        Opponent::Run(skey) => json!({ "run_id": skey.run_id }),
+        Opponent::Ghost(ghost) => json!({ "ghost_of": ghost.run_id, "turn_number": ghost.turn_number }),
+        Opponent::Empty => Value::Null,
+    }
+}
+
+fn worker_config<S, Q>(state: &State<S, Q>) -> WorkerConfig {
+    WorkerConfig { retry: state.retry, shard_count: state.shard_count, ..WorkerConfig::default() }
+}
+
+/// runs one matchmaking task. errors are returned to the lambda runtime so that
+/// the async invocation gets retried.
+pub async fn handle<S: MatchmakingStore, Q>(state: &State<S, Q>, event: &Value) -> Result<Value, Error> {
+    let request = from_event(event)?;
+    let res = run_matchmaking_task(&state.store, &state.table_name, request, worker_config(state)).await?;
+    let mut out = match res.value {
+        MatchmakingTaskResult::Matched { fight, rating_distance } => json!({
+            "result": "matched",
+            "opponent": opponent_json(&fight.opponent),
+            "rating_distance": rating_distance,
+        }),
+        MatchmakingTaskResult::Ghost { fight, reason } => {
+            if let Some(reason) = &reason {
+                // we want to track this and minimize it
+                eprintln!("matchmaking fell back to a ghost due to an error: {}", reason);
+            }
+            let reason = reason.map(|x| x.to_string());
+            json!({ "result": "ghost", "opponent": opponent_json(&fight.opponent), "error": reason })
+        }
+        MatchmakingTaskResult::Resumed { fight } => json!({ "result": "resumed", "opponent": opponent_json(&fight.opponent) }),
+        MatchmakingTaskResult::Dropped => json!({ "result": "dropped" }),
+    };
+    out["retries"] = json!(res.retries);
+    Ok(out)
+}
+
+/// cleans up stale matchmaking entries. entries that get another chance are enqueued like after `end_turn`.
+pub async fn handle_reap<S: MatchmakingStore, Q: MatchmakingQueue>(state: &State<S, Q>) -> Result<Value, Error> {
+    let config = ReaperConfig { worker: worker_config(state), ..ReaperConfig::default() };
+    let report = reap_stale_entries(&state.store, &state.table_name, &config).await?;
+    let mut requeued = 0;
+    for request in &report.requeue {
+        match state.queue.enqueue(request).await {
+            Ok(()) => requeued += 1,
+            Err(e) => eprintln!("failed to requeue matchmaking for run '{}': {}", request.skey.run_id, e),
+        }
+    }
+    let out = json!({
+        "scanned": report.scanned,
+        "requeued": requeued,
+        "resolved": report.resolved,
+        "removed": report.removed,
+        "resumed": report.resumed,
+        "failed": report.failed,
+    });
+    // logged so the counts end up in the function's log group
+    eprintln!("reaped stale matchmaking entries: {}", out);
+    Ok(out)
+}
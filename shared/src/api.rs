@@ -0,0 +1,360 @@
+//! request and response bodies of the http api. the server and the frontend both build
+//! against these, so nothing in here may depend on aws or tokio.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::battle::{Ability, BattleEvent, Side, Team, Unit};
+
+/// the team snapshot format produced by this version of the crate
+pub const TEAM_SNAPSHOT_VERSION: u32 = 1;
+
+fn default_snapshot_version() -> u32 {
+    // snapshots stored before the version field existed are all version 1
+    1
+}
+
+/// a unit as it is sent over the wire and stored in snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnitSnapshot {
+    /// stays the same for as long as the unit is on the board. units from before ids existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub kind: String,
+    pub attack: i32,
+    pub health: i32,
+    #[serde(default)]
+    pub ability: Option<Ability>,
+}
+
+/// a team as submitted at the end of a turn. the first unit is at the front.
+/// `version` lets the format change without breaking snapshots that are already stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeamSnapshot {
+    #[serde(default = "default_snapshot_version")]
+    pub version: u32,
+    pub units: Vec<UnitSnapshot>,
+}
+
+impl Default for TeamSnapshot {
+    fn default() -> Self {
+        Self { version: TEAM_SNAPSHOT_VERSION, units: Vec::new() }
+    }
+}
+
+impl TeamSnapshot {
+    /// the team the battle engine fights with. fails with the version if it is not one we can read
+    pub fn into_team(self) -> Result<Team, u32> {
+        if self.version != TEAM_SNAPSHOT_VERSION {
+            return Err(self.version);
+        }
+        let units = self.units.into_iter()
+            .map(|x| Unit { kind: x.kind, attack: x.attack, health: x.health, ability: x.ability })
+            .collect();
+        Ok(Team { units })
+    }
+}
+
+impl From<Team> for TeamSnapshot {
+    fn from(team: Team) -> Self {
+        let units = team.units.into_iter()
+            .map(|x| UnitSnapshot { id: None, kind: x.kind, attack: x.attack, health: x.health, ability: x.ability })
+            .collect();
+        Self { version: TEAM_SNAPSHOT_VERSION, units }
+    }
+}
+
+/// returned by `POST /sessions/guest`. every other request sends `token` as `Authorization: Bearer {token}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub player_id: String,
+    pub token: String,
+    /// unix time (seconds) after which the token is rejected
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Active,
+    Won,
+    Lost,
+    Abandoned,
+}
+
+/// returned by `POST /runs`, `GET /runs/{id}`, `POST /runs/{id}/end` and `POST /lobbies/{code}/join`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunResponse {
+    pub run_id: String,
+    /// the turn the run is currently playing. starts at 1
+    pub turn_number: u32,
+    pub wins: u32,
+    pub lives: u32,
+    pub status: RunStatus,
+    /// the content version the run is played on. it is only matched against runs of the same version
+    pub content_version: u32,
+    /// join code of the lobby the run plays in. it is only matched against runs of the same lobby
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lobby: Option<String>,
+}
+
+/// body of `POST /runs/{id}/end-turn`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndTurnRequest {
+    pub turn_number: u32,
+    /// the board built in the shop this turn, as the client sees it. the server always submits its
+    /// own copy of the board, a team that differs from it is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<TeamSnapshot>,
+    /// alternative to the `Idempotency-Key` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndTurnResponse {
+    pub run_id: String,
+    pub turn_number: u32,
+    /// the turn had already been ended by an earlier request with the same idempotency key
+    #[serde(default)]
+    pub replayed: bool,
+}
+
+/// something a player does in the shop. offers and board units are referred to by their
+/// current index, so indices shift as units are bought, sold or moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ShopAction {
+    /// replaces every offer that is not frozen
+    Roll,
+    /// moves an offer onto the board, in front of the unit at `position` (or at the back)
+    Buy { offer: usize, position: usize },
+    Sell { position: usize },
+    Move { from: usize, to: usize },
+    /// frozen offers survive rolls and are still offered next turn
+    Freeze { offer: usize },
+    Unfreeze { offer: usize },
+}
+
+/// body of `POST /runs/{id}/shop`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShopActionsRequest {
+    pub turn_number: u32,
+    pub actions: Vec<ShopAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShopOffer {
+    pub kind: String,
+    pub tier: u32,
+    pub attack: i32,
+    pub health: i32,
+    pub cost: u32,
+    pub frozen: bool,
+}
+
+/// returned by `GET /runs/{id}/shop` and `POST /runs/{id}/shop`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShopView {
+    pub turn_number: u32,
+    pub gold: u32,
+    /// the highest unit tier the shop offers this turn
+    pub max_tier: u32,
+    pub board: TeamSnapshot,
+    pub offers: Vec<ShopOffer>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BattleOutcome {
+    Win,
+    Loss,
+    Draw,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Opponent {
+    Run { run_id: String },
+    /// the team another run submitted on `turn_number`
+    Ghost { run_id: String, turn_number: u32 },
+    /// nobody to fight, not even a ghost
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Battle {
+    pub run_id: String,
+    pub turn_number: u32,
+    pub opponent: Opponent,
+    pub team: TeamSnapshot,
+    pub opponent_team: TeamSnapshot,
+    pub seed: u64,
+    /// which side the run's team was on in the simulation
+    pub side: Side,
+    /// from the point of view of the run
+    pub outcome: BattleOutcome,
+    pub events: Vec<BattleEvent>,
+}
+
+/// returned by `GET /runs/{id}/battle/{turn}`. 202 while pending, 200 once complete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BattleResponse {
+    Pending { run_id: String, turn_number: u32 },
+    Complete(Box<Battle>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardPeriod {
+    /// runs completed on the same utc day
+    Daily,
+    /// runs completed in the same week, starting on monday (utc)
+    Weekly,
+    AllTime,
+}
+
+impl LeaderboardPeriod {
+    pub const ALL: [LeaderboardPeriod; 3] = [LeaderboardPeriod::Daily, LeaderboardPeriod::Weekly, LeaderboardPeriod::AllTime];
+
+    /// as used in paths, eg. `/leaderboards/all_time`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LeaderboardPeriod::Daily => "daily",
+            LeaderboardPeriod::Weekly => "weekly",
+            LeaderboardPeriod::AllTime => "all_time",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.as_str() == s)
+    }
+}
+
+/// a completed run on a leaderboard. ranks start at 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub rank: u64,
+    pub run_id: String,
+    pub player_id: String,
+    pub wins: u32,
+    pub lives_lost: u32,
+    /// unix time (seconds) the run finished
+    pub completed_at: u64,
+}
+
+/// returned by `GET /leaderboards/{period}`. `GET /leaderboards/{period}/runs/{id}` returns a single `LeaderboardEntry`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaderboardResponse {
+    pub period: LeaderboardPeriod,
+    /// best first
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+/// body of `POST /lobbies`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateLobbyRequest {
+    /// how long the lobby can be joined for. capped at, and defaults to, the server's lobby ttl
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// a lobby member's run. ranks start at 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyStanding {
+    pub rank: u64,
+    pub player_id: String,
+    pub run_id: String,
+    pub turn_number: u32,
+    pub wins: u32,
+    pub lives: u32,
+    pub status: RunStatus,
+}
+
+/// returned by `POST /lobbies` and `GET /lobbies/{code}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyResponse {
+    /// what friends join with
+    pub code: String,
+    pub owner: String,
+    pub created_at: u64,
+    /// unix time (seconds) after which the lobby can no longer be joined or viewed
+    pub expires_at: u64,
+    /// most wins first, then most lives left
+    pub standings: Vec<LobbyStanding>,
+}
+
+/// why a submitted team is not one the rules allow on its turn. `position` is the index of the unit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum TeamRejection {
+    TooManyUnits { count: usize, max: usize },
+    UnknownUnit { position: usize, kind: String },
+    /// the shop does not offer this tier yet
+    TierNotAvailable { position: usize, kind: String, tier: u32, max_tier: u32 },
+    /// the unit claims an ability its kind does not have
+    AbilityMismatch { position: usize, kind: String },
+    /// more than the unit could have gained by this turn, or no health at all
+    StatsOutOfRange { position: usize, kind: String, attack: i32, health: i32, max_attack: i32, max_health: i32 },
+    DuplicateUnitId { id: String },
+    /// the team is not the board built in the shop this turn
+    NotShopBoard,
+}
+
+impl fmt::Display for TeamRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TeamRejection::TooManyUnits { count, max } => write!(f, "team has {} units, at most {} are allowed", count, max),
+            TeamRejection::UnknownUnit { position, kind } => write!(f, "unit {} is of unknown kind '{}'", position, kind),
+            TeamRejection::TierNotAvailable { position, kind, tier, max_tier } => {
+                write!(f, "unit {} ({}) is tier {}, only up to tier {} is available", position, kind, tier, max_tier)
+            }
+            TeamRejection::AbilityMismatch { position, kind } => write!(f, "unit {} does not have the ability of a {}", position, kind),
+            TeamRejection::StatsOutOfRange { position, kind, attack, health, max_attack, max_health } => write!(
+                f,
+                "unit {} ({}) has {}/{}, allowed is at most {}/{} with at least 1 health",
+                position, kind, attack, health, max_attack, max_health,
+            ),
+            TeamRejection::DuplicateUnitId { id } => write!(f, "unit id '{}' is used more than once", id),
+            TeamRejection::NotShopBoard => write!(f, "team differs from the board built in the shop"),
+        }
+    }
+}
+
+/// body of every response with a 4xx or 5xx status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: String,
+    /// set when a submitted team was rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<TeamRejection>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshots_without_a_version_are_version_1() {
+        let snapshot: TeamSnapshot = serde_json::from_str(r#"{"units":[{"kind":"a","attack":1,"health":2}]}"#)
+            .expect("failed to parse");
+        assert_eq!(snapshot.version, 1);
+        let team = snapshot.clone().into_team().expect("version 1 is supported");
+        assert_eq!(TeamSnapshot::from(team), snapshot);
+        let future = TeamSnapshot { version: TEAM_SNAPSHOT_VERSION + 1, ..snapshot };
+        assert_eq!(future.into_team(), Err(TEAM_SNAPSHOT_VERSION + 1));
+    }
+
+    #[test]
+    fn battle_responses_are_tagged_by_status() {
+        let pending = BattleResponse::Pending { run_id: "a".to_string(), turn_number: 2 };
+        let value = serde_json::to_value(&pending).expect("failed to serialize");
+        assert_eq!(value, serde_json::json!({ "status": "pending", "run_id": "a", "turn_number": 2 }));
+        let opponent = serde_json::to_value(Opponent::Ghost { run_id: "b".to_string(), turn_number: 1 }).expect("failed to serialize");
+        assert_eq!(opponent, serde_json::json!({ "kind": "ghost", "run_id": "b", "turn_number": 1 }));
+        let back: BattleResponse = serde_json::from_value(value).expect("failed to parse");
+        assert_eq!(back, pending);
+    }
+}
@@ -0,0 +1,376 @@
+//! deterministic battle simulation. given the same two teams and seed, `simulate`
+//! always produces the same outcome and event log. there is no I/O in here so it
+//! can run in the lambda, in tests and in the frontend alike.
+
+use serde::{Deserialize, Serialize};
+
+/// battles that have not finished after this many attack rounds are a draw
+pub const MAX_ROUNDS: u32 = 200;
+
+/// bump whenever a change to `simulate` can change the result of the same teams and seed.
+/// replays fought by another version cannot be verified.
+pub const ENGINE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    Player1,
+    Player2,
+}
+
+impl Side {
+    pub fn other(self) -> Self {
+        match self {
+            Side::Player1 => Side::Player2,
+            Side::Player2 => Side::Player1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trigger {
+    /// fires once before the first attack
+    StartOfBattle,
+    /// fires when this unit faints
+    Faint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    FrontEnemy,
+    RandomEnemy,
+    AllEnemies,
+    RandomFriend,
+    /// the friend directly behind this unit. for a fainted unit this is the
+    /// friend that was behind it when it fainted.
+    FriendBehind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Effect {
+    Damage { target: Target, amount: i32 },
+    Buff { target: Target, attack: i32, health: i32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ability {
+    pub trigger: Trigger,
+    pub effect: Effect,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Unit {
+    /// which kind of unit this is. not interpreted by the engine
+    pub kind: String,
+    pub attack: i32,
+    pub health: i32,
+    pub ability: Option<Ability>,
+}
+
+/// a team as it was at the end of a turn. the first unit is at the front.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Team {
+    pub units: Vec<Unit>,
+}
+
+/// identifies a unit for the duration of a battle: its side and its
+/// position in the team it was submitted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UnitRef {
+    pub side: Side,
+    pub slot: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BattleEvent {
+    AbilityTriggered { unit: UnitRef, trigger: Trigger },
+    Attack { attacker: UnitRef, defender: UnitRef },
+    Damage { unit: UnitRef, amount: i32, health_left: i32 },
+    Buff { unit: UnitRef, attack: i32, health: i32 },
+    Faint { unit: UnitRef },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    Winner(Side),
+    Draw,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BattleResult {
+    pub outcome: Outcome,
+    pub events: Vec<BattleEvent>,
+}
+
+/// small splitmix64 generator. we need the exact same sequence on every
+/// platform, so we do not rely on an external rng crate.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// returns a value in `0..len`. `len` must not be 0
+    pub fn below(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Fighter {
+    unit_ref: UnitRef,
+    attack: i32,
+    health: i32,
+    ability: Option<Ability>,
+}
+
+struct Battle {
+    sides: [Vec<Fighter>; 2],
+    rng: Rng,
+    events: Vec<BattleEvent>,
+}
+
+fn side_index(side: Side) -> usize {
+    match side {
+        Side::Player1 => 0,
+        Side::Player2 => 1,
+    }
+}
+
+impl Battle {
+    fn new(team1: &Team, team2: &Team, seed: u64) -> Self {
+        let fighters = |team: &Team, side| team.units.iter().enumerate().map(|(slot, unit)| Fighter {
+            unit_ref: UnitRef { side, slot },
+            attack: unit.attack,
+            health: unit.health,
+            ability: unit.ability,
+        }).collect();
+        Self {
+            sides: [fighters(team1, Side::Player1), fighters(team2, Side::Player2)],
+            rng: Rng::new(seed),
+            events: vec![],
+        }
+    }
+
+    fn team(&self, side: Side) -> &Vec<Fighter> {
+        &self.sides[side_index(side)]
+    }
+
+    fn find_mut(&mut self, unit: UnitRef) -> Option<&mut Fighter> {
+        self.sides[side_index(unit.side)].iter_mut().find(|x| x.unit_ref == unit)
+    }
+
+    /// resolves a target to the units currently alive that it refers to.
+    /// `position` is where the source unit is (or was, if it fainted) in its team.
+    fn resolve(&mut self, source: UnitRef, position: usize, target: Target) -> Vec<UnitRef> {
+        let friends = self.team(source.side);
+        let enemies = self.team(source.side.other());
+        match target {
+            Target::FrontEnemy => enemies.first().map(|x| x.unit_ref).into_iter().collect(),
+            Target::AllEnemies => enemies.iter().map(|x| x.unit_ref).collect(),
+            Target::RandomEnemy => {
+                let candidates: Vec<_> = enemies.iter().map(|x| x.unit_ref).collect();
+                self.pick(candidates)
+            }
+            Target::RandomFriend => {
+                let candidates: Vec<_> = friends.iter().map(|x| x.unit_ref).filter(|x| *x != source).collect();
+                self.pick(candidates)
+            }
+            Target::FriendBehind => {
+                // a fainted unit is no longer in the list, so whoever took its position was behind it
+                let behind = match friends.get(position) {
+                    Some(x) if x.unit_ref == source => position + 1,
+                    _ => position,
+                };
+                friends.get(behind).map(|x| x.unit_ref).into_iter().collect()
+            }
+        }
+    }
+
+    fn pick(&mut self, candidates: Vec<UnitRef>) -> Vec<UnitRef> {
+        if candidates.is_empty() {
+            return vec![];
+        }
+        vec![candidates[self.rng.below(candidates.len())]]
+    }
+
+    fn apply(&mut self, source: UnitRef, position: usize, effect: Effect) {
+        match effect {
+            Effect::Damage { target, amount } => {
+                for unit in self.resolve(source, position, target) {
+                    self.damage(unit, amount);
+                }
+            }
+            Effect::Buff { target, attack, health } => {
+                for unit in self.resolve(source, position, target) {
+                    if let Some(x) = self.find_mut(unit) {
+                        x.attack += attack;
+                        x.health += health;
+                        self.events.push(BattleEvent::Buff { unit, attack, health });
+                    }
+                }
+            }
+        }
+    }
+
+    fn damage(&mut self, unit: UnitRef, amount: i32) {
+        if let Some(x) = self.find_mut(unit) {
+            x.health -= amount;
+            let health_left = x.health;
+            self.events.push(BattleEvent::Damage { unit, amount, health_left });
+        }
+    }
+
+    /// removes fainted units front to back, player1 first, and fires their faint abilities.
+    /// faint abilities can cause more faints, so this repeats until nobody is left at 0 health.
+    fn resolve_faints(&mut self) {
+        loop {
+            let mut fainted = None;
+            'search: for side in [Side::Player1, Side::Player2] {
+                for (position, x) in self.team(side).iter().enumerate() {
+                    if x.health <= 0 {
+                        fainted = Some((side, position));
+                        break 'search;
+                    }
+                }
+            }
+            let Some((side, position)) = fainted else { return };
+            let fighter = self.sides[side_index(side)].remove(position);
+            self.events.push(BattleEvent::Faint { unit: fighter.unit_ref });
+            if let Some(Ability { trigger: Trigger::Faint, effect }) = fighter.ability {
+                self.events.push(BattleEvent::AbilityTriggered { unit: fighter.unit_ref, trigger: Trigger::Faint });
+                self.apply(fighter.unit_ref, position, effect);
+            }
+        }
+    }
+
+    fn start_of_battle(&mut self) {
+        // highest attack goes first. ties go to player1, then to the unit further in front
+        let mut order: Vec<(i32, UnitRef)> = self.sides.iter().flatten()
+            .filter(|x| matches!(x.ability, Some(Ability { trigger: Trigger::StartOfBattle, .. })))
+            .map(|x| (x.attack, x.unit_ref))
+            .collect();
+        order.sort_by_key(|(attack, unit)| (-attack, side_index(unit.side), unit.slot));
+        for (_, unit) in order {
+            // a unit may have fainted to an earlier start of battle ability, but it still gets to act
+            let Some(position) = self.team(unit.side).iter().position(|x| x.unit_ref == unit) else {
+                continue;
+            };
+            if let Some(Ability { effect, .. }) = self.team(unit.side)[position].ability {
+                self.events.push(BattleEvent::AbilityTriggered { unit, trigger: Trigger::StartOfBattle });
+                self.apply(unit, position, effect);
+            }
+        }
+        self.resolve_faints();
+    }
+
+    fn attack_round(&mut self) {
+        let (Some(front1), Some(front2)) = (self.sides[0].first().cloned(), self.sides[1].first().cloned()) else {
+            return;
+        };
+        self.events.push(BattleEvent::Attack { attacker: front1.unit_ref, defender: front2.unit_ref });
+        self.damage(front2.unit_ref, front1.attack);
+        self.events.push(BattleEvent::Attack { attacker: front2.unit_ref, defender: front1.unit_ref });
+        self.damage(front1.unit_ref, front2.attack);
+        self.resolve_faints();
+    }
+
+    fn outcome(&self) -> Outcome {
+        match (self.sides[0].is_empty(), self.sides[1].is_empty()) {
+            (false, true) => Outcome::Winner(Side::Player1),
+            (true, false) => Outcome::Winner(Side::Player2),
+            _ => Outcome::Draw,
+        }
+    }
+}
+
+/// simulate a battle between two teams. the result only depends on the arguments.
+pub fn simulate(team1: &Team, team2: &Team, seed: u64) -> BattleResult {
+    let mut battle = Battle::new(team1, team2, seed);
+    battle.resolve_faints();
+    battle.start_of_battle();
+    let mut rounds = 0;
+    while !battle.sides[0].is_empty() && !battle.sides[1].is_empty() && rounds < MAX_ROUNDS {
+        battle.attack_round();
+        rounds += 1;
+    }
+    BattleResult { outcome: battle.outcome(), events: battle.events }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unit(attack: i32, health: i32, ability: Option<Ability>) -> Unit {
+        Unit { kind: "test".to_string(), attack, health, ability }
+    }
+
+    fn team(units: Vec<Unit>) -> Team {
+        Team { units }
+    }
+
+    #[test]
+    fn stronger_team_wins() {
+        let t1 = team(vec![unit(3, 3, None)]);
+        let t2 = team(vec![unit(1, 1, None)]);
+        assert_eq!(simulate(&t1, &t2, 0).outcome, Outcome::Winner(Side::Player1));
+        assert_eq!(simulate(&t2, &t1, 0).outcome, Outcome::Winner(Side::Player2));
+    }
+
+    #[test]
+    fn mirror_match_is_a_draw() {
+        let t1 = team(vec![unit(2, 2, None), unit(1, 1, None)]);
+        assert_eq!(simulate(&t1, &t1, 0).outcome, Outcome::Draw);
+        assert_eq!(simulate(&Team::default(), &Team::default(), 0).outcome, Outcome::Draw);
+    }
+
+    #[test]
+    fn units_fight_front_to_back() {
+        let t1 = team(vec![unit(1, 1, None), unit(5, 5, None)]);
+        let t2 = team(vec![unit(2, 3, None)]);
+        let result = simulate(&t1, &t2, 0);
+        let p1_front = UnitRef { side: Side::Player1, slot: 0 };
+        let p1_back = UnitRef { side: Side::Player1, slot: 1 };
+        let p2_front = UnitRef { side: Side::Player2, slot: 0 };
+        assert_eq!(result.events[0], BattleEvent::Attack { attacker: p1_front, defender: p2_front });
+        assert!(result.events.contains(&BattleEvent::Faint { unit: p1_front }));
+        assert!(result.events.contains(&BattleEvent::Attack { attacker: p1_back, defender: p2_front }));
+        assert_eq!(result.outcome, Outcome::Winner(Side::Player1));
+    }
+
+    #[test]
+    fn start_of_battle_and_faint_abilities_trigger() {
+        let snipe = Ability { trigger: Trigger::StartOfBattle, effect: Effect::Damage { target: Target::FrontEnemy, amount: 2 } };
+        let boost = Ability { trigger: Trigger::Faint, effect: Effect::Buff { target: Target::FriendBehind, attack: 2, health: 2 } };
+        // the sniper kills the enemy front unit before any attack happens.
+        // that unit's faint ability buffs the unit behind it
+        let t1 = team(vec![unit(1, 1, Some(snipe))]);
+        let t2 = team(vec![unit(1, 2, Some(boost)), unit(1, 1, None)]);
+        let result = simulate(&t1, &t2, 0);
+        let p2_back = UnitRef { side: Side::Player2, slot: 1 };
+        assert!(result.events.contains(&BattleEvent::Buff { unit: p2_back, attack: 2, health: 2 }));
+        assert_eq!(result.outcome, Outcome::Winner(Side::Player2));
+    }
+
+    #[test]
+    fn same_seed_same_log() {
+        let random = Ability { trigger: Trigger::StartOfBattle, effect: Effect::Damage { target: Target::RandomEnemy, amount: 1 } };
+        let t1 = team(vec![unit(2, 3, Some(random)), unit(1, 4, Some(random)), unit(3, 1, Some(random))]);
+        let t2 = team(vec![unit(1, 2, None), unit(2, 2, None), unit(1, 5, None), unit(2, 1, None)]);
+        let a = simulate(&t1, &t2, 42);
+        let b = simulate(&t1, &t2, 42);
+        assert_eq!(a, b);
+        // different seeds should eventually pick different targets
+        let differs = (0..32).any(|seed| simulate(&t1, &t2, seed).events != a.events);
+        assert!(differs);
+    }
+}
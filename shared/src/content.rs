@@ -0,0 +1,220 @@
+//! the game content: units, their abilities, shop tiers and the gold a turn starts with. it is
+//! read from `content.json`, which is embedded at build time, so balance can be tuned without
+//! touching the shop or the battle engine. abilities are built from the engine's trigger and
+//! effect vocabulary, and every unit the shop offers fights with the ability its content gives it.
+
+use std::{fmt, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::battle::{Ability, Effect, Trigger};
+
+const EMBEDDED: &str = include_str!("../content.json");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Content {
+    /// the `CONTENT_VERSION` this content is played as
+    pub version: u32,
+    /// gold does not carry over, every turn starts with this much
+    pub gold_per_turn: u32,
+    pub unit_cost: u32,
+    pub roll_cost: u32,
+    /// gold gained for selling a unit
+    pub sell_value: u32,
+    pub max_team_size: usize,
+    /// numbered from 1, in the order they unlock
+    pub tiers: Vec<TierDef>,
+    pub abilities: Vec<AbilityDef>,
+    /// in the order the shop draws from
+    pub units: Vec<UnitDef>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TierDef {
+    pub tier: u32,
+    /// the first turn units of this tier are offered on
+    pub turn: u32,
+    /// how many offers the shop shows once this tier is unlocked
+    pub shop_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbilityDef {
+    pub id: String,
+    pub trigger: Trigger,
+    pub effect: Effect,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnitDef {
+    pub kind: String,
+    pub tier: u32,
+    pub attack: i32,
+    pub health: i32,
+    /// id of one of the `abilities`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ability: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentError {
+    /// the json does not fit the schema
+    Parse(String),
+    NoTiers,
+    /// tiers must be numbered 1, 2, 3.. with tier 1 unlocked on turn 1 and each later tier on a later turn
+    MisorderedTier { tier: u32 },
+    /// the shop could not offer anything once the tier is unlocked
+    EmptyTier { tier: u32 },
+    DuplicateAbility(String),
+    DuplicateUnit(String),
+    UnknownAbility { kind: String, ability: String },
+    TierOutOfRange { kind: String, tier: u32, max_tier: u32 },
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentError::Parse(e) => write!(f, "invalid content json: {}", e),
+            ContentError::NoTiers => write!(f, "no tiers are defined"),
+            ContentError::MisorderedTier { tier } => write!(f, "tier {} is out of order", tier),
+            ContentError::EmptyTier { tier } => write!(f, "tier {} has no units", tier),
+            ContentError::DuplicateAbility(id) => write!(f, "ability '{}' is defined more than once", id),
+            ContentError::DuplicateUnit(kind) => write!(f, "unit '{}' is defined more than once", kind),
+            ContentError::UnknownAbility { kind, ability } => write!(f, "unit '{}' has unknown ability '{}'", kind, ability),
+            ContentError::TierOutOfRange { kind, tier, max_tier } => {
+                write!(f, "unit '{}' has tier {}, tiers go from 1 to {}", kind, tier, max_tier)
+            }
+        }
+    }
+}
+
+impl Content {
+    /// parses and validates content. reports every problem found, not just the first
+    pub fn from_json(json: &str) -> Result<Self, Vec<ContentError>> {
+        let content: Self = serde_json::from_str(json).map_err(|e| vec![ContentError::Parse(e.to_string())])?;
+        let errors = content.validate();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(content)
+    }
+
+    pub fn validate(&self) -> Vec<ContentError> {
+        let mut errors = Vec::new();
+        if self.tiers.is_empty() {
+            errors.push(ContentError::NoTiers);
+        }
+        let mut previous_turn = 0;
+        for (i, tier) in self.tiers.iter().enumerate() {
+            let in_order = if i == 0 { tier.turn == 1 } else { tier.turn > previous_turn };
+            if tier.tier != i as u32 + 1 || !in_order {
+                errors.push(ContentError::MisorderedTier { tier: tier.tier });
+            }
+            if !self.units.iter().any(|x| x.tier == tier.tier) {
+                errors.push(ContentError::EmptyTier { tier: tier.tier });
+            }
+            previous_turn = tier.turn;
+        }
+        for (i, ability) in self.abilities.iter().enumerate() {
+            if self.abilities[..i].iter().any(|x| x.id == ability.id) {
+                errors.push(ContentError::DuplicateAbility(ability.id.clone()));
+            }
+        }
+        let max_tier = self.tiers.len() as u32;
+        for (i, unit) in self.units.iter().enumerate() {
+            if self.units[..i].iter().any(|x| x.kind == unit.kind) {
+                errors.push(ContentError::DuplicateUnit(unit.kind.clone()));
+            }
+            if let Some(ability) = unit.ability.as_ref().filter(|&x| !self.abilities.iter().any(|y| &y.id == x)) {
+                errors.push(ContentError::UnknownAbility { kind: unit.kind.clone(), ability: ability.clone() });
+            }
+            if unit.tier < 1 || unit.tier > max_tier {
+                errors.push(ContentError::TierOutOfRange { kind: unit.kind.clone(), tier: unit.tier, max_tier });
+            }
+        }
+        errors
+    }
+
+    pub fn unit(&self, kind: &str) -> Option<&UnitDef> {
+        self.units.iter().find(|x| x.kind == kind)
+    }
+
+    pub fn ability(&self, id: &str) -> Option<Ability> {
+        self.abilities.iter().find(|x| x.id == id).map(|x| Ability { trigger: x.trigger, effect: x.effect })
+    }
+
+    /// the ability `unit` fights with
+    pub fn unit_ability(&self, unit: &UnitDef) -> Option<Ability> {
+        unit.ability.as_deref().and_then(|x| self.ability(x))
+    }
+
+    /// the highest tier unlocked on `turn_number`
+    fn unlocked(&self, turn_number: u32) -> Option<&TierDef> {
+        self.tiers.iter().take_while(|x| x.turn <= turn_number).last().or(self.tiers.first())
+    }
+
+    pub fn max_tier(&self, turn_number: u32) -> u32 {
+        self.unlocked(turn_number).map(|x| x.tier).unwrap_or(1)
+    }
+
+    /// how many offers the shop shows
+    pub fn shop_size(&self, turn_number: u32) -> usize {
+        self.unlocked(turn_number).map(|x| x.shop_size).unwrap_or_default()
+    }
+}
+
+/// the content runs of `version` are played with. only the embedded version is built in, runs of
+/// other versions cannot be played by this build
+pub fn content_for(version: u32) -> Result<Option<&'static Content>, &'static [ContentError]> {
+    Ok(Some(content()?).filter(|x| x.version == version))
+}
+
+/// the content embedded in this build, for `CONTENT_VERSION`. `embedded_content_is_valid` keeps a
+/// bad `content.json` from being shipped, the errors are still returned rather than panicking on
+pub fn content() -> Result<&'static Content, &'static [ContentError]> {
+    static CONTENT: OnceLock<Result<Content, Vec<ContentError>>> = OnceLock::new();
+    CONTENT.get_or_init(|| Content::from_json(EMBEDDED)).as_ref().map_err(|x| x.as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn embedded_content_is_valid() {
+        // fails the build in ci if `content.json` does not parse or breaks a rule
+        if let Err(errors) = Content::from_json(EMBEDDED) {
+            let errors: Vec<_> = errors.iter().map(|x| x.to_string()).collect();
+            panic!("content.json is invalid: {}", errors.join(", "));
+        }
+        let content = content().expect("embedded content is invalid");
+        assert_eq!(content.version, crate::CONTENT_VERSION);
+        assert_eq!(content_for(crate::CONTENT_VERSION), Ok(Some(content)));
+        assert_eq!(content_for(crate::CONTENT_VERSION + 1), Ok(None));
+        assert_eq!((content.max_tier(0), content.max_tier(2), content.max_tier(3), content.max_tier(40)), (1, 1, 2, 6));
+        assert_eq!((content.shop_size(1), content.shop_size(5), content.shop_size(40)), (3, 4, 5));
+        let slinger = content.unit("slinger").expect("slinger missing");
+        assert!(matches!(content.unit_ability(slinger), Some(Ability { trigger: Trigger::StartOfBattle, .. })));
+        assert_eq!(content.unit_ability(content.unit("squire").expect("squire missing")), None);
+    }
+
+    #[test]
+    fn invalid_content_reports_every_problem() {
+        let mut content = content().expect("embedded content is invalid").clone();
+        content.units.push(UnitDef { ability: Some("fireball".to_string()), ..content.units[0].clone() });
+        content.units.push(UnitDef { kind: "dragon".to_string(), tier: 7, attack: 9, health: 9, ability: None });
+        content.abilities.push(content.abilities[0].clone());
+        content.tiers.swap(1, 2);
+        let json = serde_json::to_string(&content).expect("failed to serialize");
+        let errors = Content::from_json(&json).expect_err("content should be invalid");
+        assert_eq!(errors, vec![
+            ContentError::MisorderedTier { tier: 3 },
+            ContentError::MisorderedTier { tier: 2 },
+            ContentError::DuplicateAbility("opening_shot".to_string()),
+            ContentError::DuplicateUnit("squire".to_string()),
+            ContentError::UnknownAbility { kind: "squire".to_string(), ability: "fireball".to_string() },
+            ContentError::TierOutOfRange { kind: "dragon".to_string(), tier: 7, max_tier: 6 },
+        ]);
+        assert!(matches!(Content::from_json("{}").err().as_deref(), Some([ContentError::Parse(_)])));
+    }
+}
@@ -1,6 +1,120 @@
-pub const PKEY: &'static str = "PKEY";
-pub const SKEY: &'static str = "SKEY";
+pub mod api;
+pub mod battle;
+pub mod content;
+pub mod replay;
 
-pub fn matchmaking_pkey(turn_number: u32) -> String {
-    format!("matchmaking_turn_{}", turn_number)
+pub const PKEY: &str = "PKEY";
+pub const SKEY: &str = "SKEY";
+
+/// environment variable the server reads its table name from
+pub const TABLE_NAME_ENV: &str = "TABLE_NAME";
+pub const DEFAULT_TABLE_NAME: &str = "mygametable2025";
+
+/// environment variable holding how many shards each turn's matchmaking pool is spread over.
+/// can be changed at any time: entries remember their shard, only new entries use the new count.
+pub const MATCHMAKING_SHARDS_ENV: &str = "MATCHMAKING_SHARDS";
+pub const DEFAULT_MATCHMAKING_SHARDS: u32 = 4;
+
+/// environment variable holding how long (seconds) a new lobby can be joined for
+pub const LOBBY_TTL_SECS_ENV: &str = "LOBBY_TTL_SECS";
+pub const DEFAULT_LOBBY_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// environment variable holding the secret session tokens are signed with
+pub const SESSION_SECRET_ENV: &str = "SESSION_SECRET";
+
+/// events carrying this key run the reaper rather than being handled as http requests.
+/// deploy schedules one every minute
+pub const REAP_EVENT_KEY: &str = "reap";
+
+/// attribute holding the unix time (seconds) after which dynamodb ttl may delete the item
+pub const EXPIRES_AT_ATTR: &str = "expires_at";
+
+/// the game content (unit stats, abilities) new runs are played with. bump it with every balance
+/// patch, along with the version in `content.json`: runs keep the version they started on and only
+/// meet runs and ghosts of the same version.
+pub const CONTENT_VERSION: u32 = 1;
+
+/// runs only meet runs of the same content version and lobby. partitions of content version 1
+/// outside of lobbies keep the names they had before either existed
+fn pool_suffix(content_version: u32, lobby: Option<&str>) -> String {
+    let mut out = match content_version {
+        0 | 1 => String::new(),
+        x => format!("#content_{}", x),
+    };
+    if let Some(code) = lobby {
+        out.push_str(&format!("#lobby_{}", code));
+    }
+    out
+}
+
+/// the matchmaking pool of a turn is split over several partitions so a busy turn
+/// does not exceed the throughput of a single partition
+pub fn matchmaking_pkey(content_version: u32, lobby: Option<&str>, turn_number: u32, shard: u32) -> String {
+    format!("matchmaking_turn_{}#shard_{}{}", turn_number, shard, pool_suffix(content_version, lobby))
+}
+
+/// battles that were paired when entries of a matchmaking partition were claimed, but not fought yet.
+/// one partition for each matchmaking partition
+pub fn pending_battles_pkey(content_version: u32, lobby: Option<&str>, turn_number: u32, shard: u32) -> String {
+    format!("pending_turn_{}#shard_{}{}", turn_number, shard, pool_suffix(content_version, lobby))
+}
+
+pub fn ghost_pkey(content_version: u32, lobby: Option<&str>, turn_number: u32) -> String {
+    format!("ghost_turn_{}{}", turn_number, pool_suffix(content_version, lobby))
+}
+
+pub fn run_pkey(run_id: &str) -> String {
+    format!("run_{}", run_id)
+}
+
+/// per player records, eg. rejected team submissions
+pub fn player_pkey(player_id: &str) -> String {
+    format!("player_{}", player_id)
+}
+
+/// `board` is the period and its start, eg. `daily_2025-01-31` or `all_time`
+pub fn leaderboard_pkey(board: &str) -> String {
+    format!("leaderboard_{}", board)
+}
+
+/// a lobby and its members live in the partition of its join code
+pub fn lobby_pkey(code: &str) -> String {
+    format!("lobby_{}", code)
+}
+
+pub const LOBBY_SKEY: &str = "lobby";
+
+/// sort key of a player's membership in a lobby. lives in the lobby's partition
+pub fn lobby_member_skey(player_id: &str) -> String {
+    format!("member_{}", player_id)
+}
+
+/// every matchmaking partition an entry was written to recently has an item in this partition, keyed by
+/// its pkey, so the reaper only scans those
+pub const MATCHMAKING_POOLS_PKEY: &str = "matchmaking_pools";
+
+/// sort key of the item in a finished run's partition that records where it is on the leaderboards
+pub const LEADERBOARD_SKEY: &str = "leaderboard";
+
+/// runs are the only item in their partition, so they share a fixed sort key
+pub const RUN_SKEY: &str = "run";
+
+/// sort key of the team a run submitted at the end of `turn_number`. lives in the run's partition
+pub fn snapshot_skey(turn_number: u32) -> String {
+    format!("snapshot_{:05}", turn_number)
+}
+
+/// sort key of the shop action log of `turn_number`. lives in the run's partition
+pub fn shop_skey(turn_number: u32) -> String {
+    format!("shop_{:05}", turn_number)
+}
+
+/// sort key of the marker written when a run ends `turn_number`. lives in the run's partition
+pub fn end_turn_skey(turn_number: u32) -> String {
+    format!("end_turn_{:05}", turn_number)
+}
+
+/// sort key of the battle a run fought at the end of `turn_number`. lives in the run's partition
+pub fn battle_skey(turn_number: u32) -> String {
+    format!("battle_{:05}", turn_number)
 }
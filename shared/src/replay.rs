@@ -0,0 +1,74 @@
+//! the replay stored with every battle: both teams, the seed and what the engine made of them,
+//! so the battle can be fought again later and checked against what was recorded.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::TeamSnapshot,
+    battle::{self, BattleEvent, BattleResult, Outcome},
+};
+
+/// the replay format produced by this version of the crate
+pub const REPLAY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    /// the `battle::ENGINE_VERSION` that fought the battle
+    pub engine_version: u32,
+    pub seed: u64,
+    pub player1: TeamSnapshot,
+    pub player2: TeamSnapshot,
+    pub outcome: Outcome,
+    /// `events_hash` of the event log the battle produced
+    pub events_hash: String,
+}
+
+impl Replay {
+    /// fights a battle and records it. fails with the snapshot version of a team that cannot be read
+    pub fn fight(player1: TeamSnapshot, player2: TeamSnapshot, seed: u64) -> Result<(Self, BattleResult), u32> {
+        let result = battle::simulate(&player1.clone().into_team()?, &player2.clone().into_team()?, seed);
+        let replay = Self {
+            version: REPLAY_VERSION,
+            engine_version: battle::ENGINE_VERSION,
+            seed,
+            player1,
+            player2,
+            outcome: result.outcome,
+            events_hash: events_hash(&result.events),
+        };
+        Ok((replay, result))
+    }
+
+    /// fights the battle again with this version of the engine
+    pub fn simulate(&self) -> Result<BattleResult, u32> {
+        Ok(battle::simulate(&self.player1.clone().into_team()?, &self.player2.clone().into_team()?, self.seed))
+    }
+}
+
+/// hex encoded sha256 of the json of `events`
+pub fn events_hash(events: &[BattleEvent]) -> String {
+    let json = serde_json::to_vec(events).expect("battle events always serialize");
+    Sha256::digest(json).iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::UnitSnapshot;
+
+    #[test]
+    fn replays_fight_the_same_battle_again() {
+        let unit = |attack, health| UnitSnapshot { id: None, kind: "test".to_string(), attack, health, ability: None };
+        let player1 = TeamSnapshot { units: vec![unit(2, 3), unit(1, 1)], ..TeamSnapshot::default() };
+        let player2 = TeamSnapshot { units: vec![unit(3, 2)], ..TeamSnapshot::default() };
+        let (replay, result) = Replay::fight(player1, player2, 7).expect("teams are readable");
+        let again = replay.simulate().expect("teams are readable");
+        assert_eq!(again, result);
+        assert_eq!(events_hash(&again.events), replay.events_hash);
+        assert_ne!(events_hash(&again.events[1..]), replay.events_hash);
+        let json = serde_json::to_string(&replay).expect("failed to serialize");
+        assert_eq!(serde_json::from_str::<Replay>(&json).expect("failed to parse"), replay);
+    }
+}